@@ -1,4 +1,5 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
 use std::{collections::HashMap, env, fs, path::Path};
 
 #[derive(Deserialize)]
@@ -16,6 +17,26 @@ struct ProviderConfig {
     name: String,
     style: StyleConfig,
     url: UrlConfig,
+    claims: Option<ClaimsConfig>,
+}
+
+/// JSON pointers into the userinfo responses, for OAuth2 providers without a module.
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+struct ClaimsConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    email: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    email_verified: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    picture: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -32,16 +53,44 @@ struct StyleConfig {
 #[serde(rename_all(deserialize = "kebab-case"))]
 #[serde(tag = "type")]
 enum UrlConfig {
-    #[serde(rename = "oauth2")]
+    #[serde(rename = "oauth2", rename_all(deserialize = "kebab-case"))]
     OAuth2 {
         auth: String,
         token: String,
+        #[serde(default)]
+        userinfo: Vec<String>,
+        #[serde(default)]
+        auth_header: AuthHeaderConfig,
     },
+    #[serde(rename_all(deserialize = "kebab-case"))]
     Oidc {
         issuer: String,
+        response_mode: Option<String>,
+        #[serde(default)]
+        client_auth: ClientAuthConfig,
+        #[serde(default)]
+        allowed_tenants: Vec<String>,
     },
 }
 
+#[derive(Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum AuthHeaderConfig {
+    #[default]
+    Bearer,
+    Token,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum ClientAuthConfig {
+    #[default]
+    #[serde(rename(serialize = "secret"))]
+    Secret,
+    #[serde(rename(serialize = "apple_jwt"))]
+    AppleJwt,
+}
+
 fn out_dir() -> String {
     env::var_os("OUT_DIR")
         .expect("failed to read OUT_DIR env")
@@ -64,144 +113,135 @@ fn providers() -> Vec<ProviderConfig> {
         .collect::<Vec<_>>()
 }
 
-fn gen_provider_def(config: &Config, provider: &ProviderConfig) -> String {
-    let ProviderConfig { name, url, .. } = provider;
+fn has_module(provider: &ProviderConfig) -> bool {
+    Path::new("src/providers")
+        .join(&provider.name)
+        .join("mod.rs")
+        .exists()
+}
+
+/// Serialize a provider to the definition format that is also used by the providers in D1.
+fn gen_provider_def(config: &Config, provider: &ProviderConfig) -> serde_json::Value {
+    let ProviderConfig {
+        name,
+        style,
+        url,
+        claims,
+    } = provider;
 
-    let name_upper = name.to_uppercase();
+    let scopes = config.providers.get(name).expect("provider not found");
 
-    let scopes = config
-        .providers
-        .get(name)
-        .expect("provider not found")
-        .iter()
-        .map(|s| format!(r#""{s}""#))
-        .collect::<Vec<String>>()
-        .join(",");
-
-    match url {
-        UrlConfig::OAuth2 { auth, token } => format!(
-            r#"
-const {name_upper}: Provider = Provider::OAuth2(OAuth2Provider {{
-    auth_url: "{auth}",
-    token_url: "{token}",
-    scopes: &[{scopes}],
-}});
-            "#
-        ),
-        UrlConfig::Oidc { issuer } => format!(
-            r#"
-const {name_upper}: Provider = Provider::Oidc(OidcProvider {{
-    issuer_url: "{issuer}",
-    scopes: &[{scopes}],
-}});
-            "#
-        ),
+    let icon = fs::read_to_string(Path::new("src/providers").join(name).join("provider.svg"))
+        .expect("provider icon not found");
+
+    let mut def = match url {
+        UrlConfig::OAuth2 {
+            auth,
+            token,
+            userinfo,
+            auth_header,
+        } => json!({
+            "type": "oauth2",
+            "auth_url": auth,
+            "token_url": token,
+            "userinfo_urls": userinfo,
+            "auth_header": auth_header,
+        }),
+        UrlConfig::Oidc {
+            issuer,
+            response_mode,
+            client_auth,
+            allowed_tenants,
+        } => json!({
+            "type": "oidc",
+            "issuer_url": issuer,
+            "response_mode": response_mode,
+            "client_auth": client_auth,
+            "allowed_tenants": allowed_tenants,
+        }),
+    };
+
+    def["name"] = json!(name);
+    def["scopes"] = json!(scopes);
+    def["style"] = json!({
+        "display_name": style.display_name,
+        "background_color": style.background_color,
+        "background_color_hover": style.background_color_hover,
+        "icon": icon,
+    });
+
+    if let Some(claims) = claims {
+        def["claims"] = json!(claims);
     }
+
+    def
 }
 
-fn gen_provider_fns(providers: &[ProviderConfig]) -> String {
-    let get_match_arms = providers
+fn check_provider(provider: &ProviderConfig) {
+    if let UrlConfig::OAuth2 { userinfo, .. } = &provider.url {
+        let has_id = provider
+            .claims
+            .as_ref()
+            .map_or(false, |claims| claims.id.is_some());
+
+        if !has_module(provider) && (userinfo.is_empty() || !has_id) {
+            panic!(
+                "provider {} requires a module or userinfo urls and an id claim",
+                provider.name
+            );
+        }
+    }
+}
+
+fn gen_provider_defs(config: &Config, providers: &[ProviderConfig]) {
+    let defs = providers
         .iter()
-        .map(|provider| {
-            let ProviderConfig { name, .. } = provider;
-            let name_upper = provider.name.to_uppercase();
+        .filter(|provider| config.providers.contains_key(&provider.name))
+        .map(|provider| gen_provider_def(config, provider))
+        .collect::<Vec<_>>();
 
-            format!(r#""{name}" => Ok({name_upper}),"#)
-        })
+    let dest_path = Path::new(&out_dir()).join("providers.json");
+    fs::write(&dest_path, serde_json::to_string(&defs).unwrap()).expect("failed to write output");
+}
+
+fn gen_provider_fns(providers: &[ProviderConfig]) {
+    let modules = providers
+        .iter()
+        .filter(|provider| has_module(provider))
+        .map(|provider| format!(r#""{}","#, provider.name))
         .collect::<String>();
 
     let fetch_match_arms = providers
         .iter()
+        .filter(|provider| has_module(provider))
         .map(|provider| {
             let ProviderConfig { name, .. } = provider;
 
-            match provider.url {
-                UrlConfig::OAuth2 { .. } => {
-                    format!(r#""{name}" => {name}::fetch_user(client, access_token).await,"#)
-                }
-                UrlConfig::Oidc { .. } => format!(r#""{name}" => unreachable!(),"#),
-            }
+            format!(r#""{name}" => Some({name}::fetch_user(client, access_token).await),"#)
         })
         .collect::<String>();
 
-    format!(
+    let provider_fns = format!(
         r#"
-pub fn get_provider(provider: &str) -> Result<Provider, Error> {{
-    match provider {{
-        {get_match_arms}
-        _ => Err(Error::InvalidConnection),
-    }}
-}}
+/// Providers with a compiled in module that implements `fetch_user`.
+const BUILTIN_MODULES: &[&str] = &[{modules}];
 
-pub async fn fetch_user(provider: &str, client: Client, access_token: &AccessToken) -> Result<User, Error> {{
-    let mut user = match provider {{
+/// Fetch the user through a provider that has a compiled in module, if there is one.
+async fn fetch_builtin_user(provider: &str, client: Client, access_token: &AccessToken) -> Option<Result<User, Error>> {{
+    match provider {{
         {fetch_match_arms}
-        _ => Err(Error::InvalidConnection),
-    }}?;
-
-    user.id = format!("{{provider}}|{{}}", user.id);
-
-    Ok(user)
+        _ => None,
+    }}
 }}
         "#
-    )
-}
-
-fn gen_providers(config: &Config, providers: &[ProviderConfig]) {
-    let provider_defs = providers
-        .iter()
-        .map(|provider| gen_provider_def(config, provider))
-        .collect::<String>();
-
-    let provider_fns = gen_provider_fns(providers);
+    );
 
     let dest_path = Path::new(&out_dir()).join("providers.rs");
-    fs::write(&dest_path, provider_defs + &provider_fns).expect("failed to write output");
-}
-
-fn gen_provider_button(provider: &ProviderConfig) -> String {
-    let ProviderConfig { name, style, .. } = provider;
-    let StyleConfig {
-        display_name,
-        background_color,
-        background_color_hover,
-        ..
-    } = style;
-
-    let svg = fs::read_to_string(Path::new("src/providers").join(name).join("provider.svg"))
-        .expect("provider icon not found");
-
-    format!(
-        r#"
-<button
-    class="oauth-provider"
-    onclick="oauth('{name}')"
-    style="--provider-bg-color: {background_color}; --provider-bg-color-hover: {background_color_hover}"
->
-    <div class="oauth-icon">
-        {svg}
-    </div>
-    <div class="oauth-name">{display_name}</div>
-</button>
-        "#
-    )
-}
-
-fn gen_providers_html(providers: &[ProviderConfig]) {
-    let html = providers
-        .iter()
-        .map(gen_provider_button)
-        .collect::<String>();
-
-    let login = include_str!("public/login.html").replace("<!-- OAUTH_PROVIDERS -->", &html);
-
-    let dest_path = Path::new(&out_dir()).join("login.html");
-    fs::write(&dest_path, login).expect("failed to write output");
+    fs::write(&dest_path, provider_fns).expect("failed to write output");
 }
 
 fn main() {
     println!("cargo:rerun-if-changed=config.toml");
-    println!("cargo:rerun-if-changed=public");
     println!("cargo:rerun-if-changed=src/providers/*/*");
 
     let config =
@@ -209,12 +249,10 @@ fn main() {
 
     println!("cargo:rustc-env=DOMAIN={}", config.domain);
 
-    let mut providers = providers()
-        .into_iter()
-        .filter(|provider| config.providers.contains_key(&provider.name))
-        .collect::<Vec<_>>();
+    let mut providers = providers();
     providers.sort_by_key(|provider| provider.name.clone());
+    providers.iter().for_each(check_provider);
 
-    gen_providers(&config, &providers);
-    gen_providers_html(&providers);
+    gen_provider_defs(&config, &providers);
+    gen_provider_fns(&providers);
 }
@@ -0,0 +1,52 @@
+const IV_LENGTH = 12;
+
+function b64encode(buf) {
+  return btoa(String.fromCharCode.apply(null, new Uint8Array(buf)));
+}
+
+function b64decode(str) {
+  return Uint8Array.from(atob(str), (c) => c.charCodeAt(0));
+}
+
+function importKey(key) {
+  return crypto.subtle.importKey("raw", b64decode(key), "AES-GCM", false, [
+    "encrypt",
+    "decrypt",
+  ]);
+}
+
+export async function encrypt(key, associatedData, plaintext) {
+  const iv = crypto.getRandomValues(new Uint8Array(IV_LENGTH));
+
+  const ciphertext = await crypto.subtle.encrypt(
+    {
+      name: "AES-GCM",
+      iv,
+      additionalData: new TextEncoder().encode(associatedData),
+    },
+    await importKey(key),
+    new TextEncoder().encode(plaintext)
+  );
+
+  const sealed = new Uint8Array(IV_LENGTH + ciphertext.byteLength);
+  sealed.set(iv);
+  sealed.set(new Uint8Array(ciphertext), IV_LENGTH);
+
+  return b64encode(sealed);
+}
+
+export async function decrypt(key, associatedData, sealed) {
+  const bytes = b64decode(sealed);
+
+  const plaintext = await crypto.subtle.decrypt(
+    {
+      name: "AES-GCM",
+      iv: bytes.slice(0, IV_LENGTH),
+      additionalData: new TextEncoder().encode(associatedData),
+    },
+    await importKey(key),
+    bytes.slice(IV_LENGTH)
+  );
+
+  return new TextDecoder().decode(plaintext);
+}
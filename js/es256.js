@@ -0,0 +1,43 @@
+const ALGORITHM = {
+  name: "ECDSA",
+  namedCurve: "P-256",
+  hash: "SHA-256",
+};
+
+const encoder = new TextEncoder();
+
+function base64url(bytes) {
+  const str = String.fromCharCode.apply(null, bytes);
+  return btoa(str).replace(/\+/g, "-").replace(/\//g, "_").replace(/=+$/, "");
+}
+
+function pem2der(pem) {
+  const base64 = pem
+    .replace(/-----(BEGIN|END) PRIVATE KEY-----/g, "")
+    .replace(/\s/g, "");
+
+  return Uint8Array.from(atob(base64), (c) => c.charCodeAt(0));
+}
+
+export async function sign_jwt(pem, header, claims) {
+  const key = await crypto.subtle.importKey(
+    "pkcs8",
+    pem2der(pem),
+    ALGORITHM,
+    false,
+    ["sign"]
+  );
+
+  const input = `${base64url(encoder.encode(header))}.${base64url(
+    encoder.encode(claims)
+  )}`;
+
+  // WebCrypto produces the raw r || s signature that JWS expects
+  const signature = await crypto.subtle.sign(
+    ALGORITHM,
+    key,
+    encoder.encode(input)
+  );
+
+  return `${input}.${base64url(new Uint8Array(signature))}`;
+}
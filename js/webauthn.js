@@ -0,0 +1,56 @@
+const ECDSA = {
+  name: "ECDSA",
+  namedCurve: "P-256",
+  hash: "SHA-256",
+};
+
+const RSA = {
+  name: "RSASSA-PKCS1-v1_5",
+  hash: "SHA-256",
+};
+
+// Authenticators produce DER encoded ECDSA signatures, WebCrypto expects the raw r || s
+function der2raw(der) {
+  const raw = new Uint8Array(64);
+
+  let offset = 2;
+  for (let i = 0; i < 2; i++) {
+    const len = der[offset + 1];
+    let int = der.subarray(offset + 2, offset + 2 + len);
+    while (int.length > 32 && int[0] === 0) {
+      int = int.subarray(1);
+    }
+
+    raw.set(int, 32 * (i + 1) - int.length);
+    offset += 2 + len;
+  }
+
+  return raw;
+}
+
+export async function verify(jwk, data, signature) {
+  const key = JSON.parse(jwk);
+
+  try {
+    if (key.kty === "EC") {
+      const publicKey = await crypto.subtle.importKey("jwk", key, ECDSA, false, [
+        "verify",
+      ]);
+
+      return await crypto.subtle.verify(
+        ECDSA,
+        publicKey,
+        der2raw(signature),
+        data
+      );
+    }
+
+    const publicKey = await crypto.subtle.importKey("jwk", key, RSA, false, [
+      "verify",
+    ]);
+
+    return await crypto.subtle.verify(RSA, publicKey, signature, data);
+  } catch {
+    return false;
+  }
+}
@@ -0,0 +1,14 @@
+-- Migration number: 0001 	 2026-10-18T09:12:41.318Z
+
+CREATE TABLE IF NOT EXISTS identities (
+    provider TEXT NOT NULL,
+    provider_user_id TEXT NOT NULL,
+    user_id TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+
+    created_at TEXT NOT NULL,
+    updated_at TEXT NOT NULL,
+
+    PRIMARY KEY (provider, provider_user_id)
+);
+
+CREATE INDEX IF NOT EXISTS identities_user_id ON identities(user_id);
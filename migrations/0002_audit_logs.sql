@@ -0,0 +1,16 @@
+-- Migration number: 0002 	 2026-10-18T10:03:17.904Z
+
+CREATE TABLE IF NOT EXISTS audit_logs (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+
+    action TEXT NOT NULL,
+    actor_id TEXT,
+    client_id TEXT,
+    target_id TEXT,
+    details TEXT,
+
+    created_at TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS audit_logs_target_id ON audit_logs(target_id);
+CREATE INDEX IF NOT EXISTS audit_logs_created_at ON audit_logs(created_at);
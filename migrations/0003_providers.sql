@@ -0,0 +1,10 @@
+-- Migration number: 0003 	 2026-10-18T11:26:52.147Z
+
+CREATE TABLE IF NOT EXISTS providers (
+    name TEXT PRIMARY KEY,
+    definition TEXT NOT NULL,
+    client_secret TEXT,
+
+    created_at TEXT NOT NULL,
+    updated_at TEXT NOT NULL
+);
@@ -0,0 +1,3 @@
+-- Migration number: 0004 	 2026-10-18T13:02:17.584Z
+
+ALTER TABLE identities ADD COLUMN tenant_id TEXT;
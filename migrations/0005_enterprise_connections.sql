@@ -0,0 +1,20 @@
+-- Migration number: 0005 	 2026-10-18T14:21:05.932Z
+
+CREATE TABLE IF NOT EXISTS enterprise_connections (
+    name TEXT PRIMARY KEY,
+    organization TEXT NOT NULL,
+    definition TEXT NOT NULL,
+    client_secret TEXT,
+
+    created_at TEXT NOT NULL,
+    updated_at TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS enterprise_connections_organization ON enterprise_connections(organization);
+
+CREATE TABLE IF NOT EXISTS enterprise_connection_domains (
+    domain TEXT PRIMARY KEY,
+    connection TEXT NOT NULL REFERENCES enterprise_connections(name) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS enterprise_connection_domains_connection ON enterprise_connection_domains(connection);
@@ -0,0 +1,15 @@
+-- Migration number: 0006 	 2026-10-18T15:08:44.127Z
+
+CREATE TABLE IF NOT EXISTS password_credentials (
+    user_id TEXT PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+    email TEXT NOT NULL,
+    password_hash TEXT NOT NULL,
+
+    failed_attempts INTEGER NOT NULL DEFAULT 0,
+    locked_until TEXT,
+
+    created_at TEXT NOT NULL,
+    updated_at TEXT NOT NULL
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS password_credentials_email ON password_credentials(email);
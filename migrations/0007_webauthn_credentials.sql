@@ -0,0 +1,19 @@
+-- Migration number: 0007 	 2026-10-18T16:02:31.518Z
+
+CREATE TABLE IF NOT EXISTS webauthn_credentials (
+    -- base64url encoded credential ID
+    id TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    name TEXT,
+
+    -- JWK of the public key
+    public_key TEXT NOT NULL,
+    sign_count INTEGER NOT NULL DEFAULT 0,
+    -- JSON array of transports, such as "internal" or "usb"
+    transports TEXT NOT NULL,
+
+    created_at TEXT NOT NULL,
+    last_used_at TEXT
+);
+
+CREATE INDEX IF NOT EXISTS webauthn_credentials_user_id ON webauthn_credentials(user_id);
@@ -0,0 +1,22 @@
+-- Migration number: 0008 	 2026-10-18T16:47:09.302Z
+
+CREATE TABLE IF NOT EXISTS totp_credentials (
+    user_id TEXT PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+    -- base32 secret, encrypted with ENCRYPTION_KEYS
+    secret TEXT NOT NULL,
+    -- time step of the last accepted code, which can't be used again
+    last_used_step INTEGER,
+    created_at TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS recovery_codes (
+    user_id TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    -- hex encoded SHA-256 of the code
+    code_hash TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    used_at TEXT,
+
+    PRIMARY KEY (user_id, code_hash)
+);
+
+ALTER TABLE applications ADD COLUMN require_mfa INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,18 @@
+-- Migration number: 0009 	 2026-10-18T21:12:44.518Z
+
+CREATE TABLE IF NOT EXISTS sessions (
+    id TEXT PRIMARY KEY,
+    -- hex encoded SHA-256 of the cookie value, which is only known to the browser
+    token_hash TEXT NOT NULL,
+    user_id TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    -- connection the user signed in with
+    connection TEXT NOT NULL,
+    -- JSON array of the authentication methods used besides the first factor
+    amr TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    expires_at TEXT NOT NULL,
+    last_used_at TEXT
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS sessions_token_hash ON sessions(token_hash);
+CREATE INDEX IF NOT EXISTS sessions_user_id ON sessions(user_id);
@@ -0,0 +1,15 @@
+-- Migration number: 0010 	 2026-10-18T22:03:17.904Z
+
+CREATE TABLE IF NOT EXISTS user_grants (
+    user_id TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    client_id TEXT NOT NULL REFERENCES applications(client_id) ON DELETE CASCADE,
+    -- space separated scopes the user approved
+    scopes TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    updated_at TEXT NOT NULL,
+
+    PRIMARY KEY (user_id, client_id)
+);
+
+-- First-party applications don't ask users for consent
+ALTER TABLE applications ADD COLUMN first_party INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,4 @@
+-- Migration number: 0011 	 2026-10-18T23:12:41.318Z
+
+-- Space separated URIs applications may redirect to after signing users out
+ALTER TABLE applications ADD COLUMN post_logout_redirect_uris TEXT NOT NULL DEFAULT '';
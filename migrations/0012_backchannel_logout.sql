@@ -0,0 +1,27 @@
+-- Migration number: 0012 	 2026-10-18T23:48:05.127Z
+
+-- Applications are notified at this URI when a session they signed in through ends
+ALTER TABLE applications ADD COLUMN backchannel_logout_uri TEXT;
+
+-- The applications each session signed in to
+CREATE TABLE IF NOT EXISTS session_applications (
+    session_id TEXT NOT NULL REFERENCES sessions(id) ON DELETE CASCADE,
+    client_id TEXT NOT NULL REFERENCES applications(client_id) ON DELETE CASCADE,
+    created_at TEXT NOT NULL,
+
+    PRIMARY KEY (session_id, client_id)
+);
+
+-- Back-channel logout notifications that failed and are retried by the scheduled handler
+CREATE TABLE IF NOT EXISTS logout_notifications (
+    id TEXT PRIMARY KEY,
+    client_id TEXT NOT NULL REFERENCES applications(client_id) ON DELETE CASCADE,
+    user_id TEXT NOT NULL,
+    -- the session already ended, so this doesn't reference sessions
+    session_id TEXT NOT NULL,
+    attempts INTEGER NOT NULL,
+    next_attempt_at TEXT NOT NULL,
+    created_at TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS logout_notifications_next_attempt_at ON logout_notifications(next_attempt_at);
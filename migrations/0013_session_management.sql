@@ -0,0 +1,22 @@
+-- Migration number: 0013 	 2026-10-18T23:58:12.604Z
+
+-- The browser the session was started in
+ALTER TABLE sessions ADD COLUMN user_agent TEXT;
+ALTER TABLE sessions ADD COLUMN ip TEXT;
+
+-- Access and refresh tokens that were issued together, since the tokens in KV can't be listed per user
+CREATE TABLE IF NOT EXISTS tokens (
+    id TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    client_id TEXT NOT NULL REFERENCES applications(client_id) ON DELETE CASCADE,
+    session_id TEXT NOT NULL REFERENCES sessions(id) ON DELETE CASCADE,
+    -- space separated scopes
+    scopes TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    -- when the refresh token expires
+    expires_at TEXT NOT NULL,
+    last_used_at TEXT
+);
+
+CREATE INDEX IF NOT EXISTS tokens_user_id ON tokens(user_id);
+CREATE INDEX IF NOT EXISTS tokens_session_id ON tokens(session_id);
@@ -0,0 +1,5 @@
+-- Migration number: 0014 	 2026-10-19T09:12:41.518Z
+
+-- Wrong TOTP and recovery codes, counted per user so that starting over doesn't allow more guesses
+ALTER TABLE totp_credentials ADD COLUMN failed_attempts INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE totp_credentials ADD COLUMN locked_until TEXT;
@@ -0,0 +1,6 @@
+-- Migration number: 0015 	 2026-10-19T10:03:27.846Z
+
+-- KV keys of the access and refresh token, to revoke them without listing KV. Tokens issued before
+-- don't have them and expire on their own.
+ALTER TABLE tokens ADD COLUMN access_key TEXT;
+ALTER TABLE tokens ADD COLUMN refresh_key TEXT;
@@ -0,0 +1,73 @@
+use axum::{
+    headers::{authorization::Bearer, Authorization},
+    routing::{delete, get, post},
+    Router,
+};
+use worker::body::Body;
+
+use crate::{auth::states::TokenMetadata, error::Error, tokens::get_access_token, AppState};
+
+mod grants;
+mod mfa;
+mod passkeys;
+mod phone;
+mod sessions;
+mod tokens;
+
+/// Resolve the access token of a request, which must have the scope.
+async fn require_token(
+    state: &AppState,
+    authorization: &Authorization<Bearer>,
+    scope: &str,
+) -> Result<TokenMetadata, Error> {
+    let token_meta = get_access_token(state, authorization.token()).await?;
+
+    if !token_meta.has_scope(scope) {
+        return Err(Error::MissingPermission);
+    }
+
+    Ok(token_meta)
+}
+
+/// Resolve the user an access token was issued to, for endpoints where users manage their own
+/// account.
+async fn require_user(
+    state: &AppState,
+    authorization: &Authorization<Bearer>,
+    scope: &str,
+) -> Result<String, Error> {
+    Ok(require_token(state, authorization, scope).await?.user_id)
+}
+
+pub fn router() -> Router<AppState, Body> {
+    Router::new()
+        .route("/account/grants", get(grants::list_grants))
+        .route("/account/grants/:client_id", delete(grants::delete_grant))
+        .route(
+            "/account/mfa/totp",
+            post(mfa::start_totp).delete(mfa::delete_totp),
+        )
+        .route("/account/mfa/totp/confirm", post(mfa::confirm_totp))
+        .route(
+            "/account/mfa/recovery-codes",
+            post(mfa::regenerate_recovery_codes),
+        )
+        .route(
+            "/account/passkeys",
+            get(passkeys::list_passkeys).post(passkeys::register_passkey),
+        )
+        .route("/account/passkeys/options", post(passkeys::passkey_options))
+        .route("/account/passkeys/:id", delete(passkeys::delete_passkey))
+        .route("/account/phone", post(phone::start_verification))
+        .route("/account/phone/verify", post(phone::verify_phone))
+        .route(
+            "/account/sessions",
+            get(sessions::list_sessions).delete(sessions::delete_sessions),
+        )
+        .route("/account/sessions/:id", delete(sessions::delete_session))
+        .route(
+            "/account/tokens",
+            get(tokens::list_tokens).delete(tokens::delete_tokens),
+        )
+        .route("/account/tokens/:id", delete(tokens::delete_token))
+}
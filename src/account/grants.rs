@@ -0,0 +1,77 @@
+use axum::{
+    extract::{Path, State},
+    headers::{authorization::Bearer, Authorization},
+    http::StatusCode,
+    response::IntoResponse,
+    Json, TypedHeader,
+};
+use futures::channel::oneshot;
+use oauth2::ClientId;
+
+use crate::{
+    auth::revocation::revoke_client_tokens,
+    error::Error,
+    grants::{self, GrantInfo},
+    AppState,
+};
+
+use super::require_user;
+
+async fn list_grants_impl(
+    state: AppState,
+    authorization: Authorization<Bearer>,
+) -> Result<impl IntoResponse, Error> {
+    let user_id = require_user(&state, &authorization, "read:account").await?;
+
+    let grants = grants::list_grants(&state.db, &user_id).await?;
+
+    Ok(Json(
+        grants.into_iter().map(GrantInfo::from).collect::<Vec<_>>(),
+    ))
+}
+
+pub async fn list_grants(
+    State(state): State<AppState>,
+    TypedHeader(authorization): TypedHeader<Authorization<Bearer>>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let res = list_grants_impl(state, authorization).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
+
+async fn delete_grant_impl(
+    state: AppState,
+    authorization: Authorization<Bearer>,
+    client_id: ClientId,
+) -> Result<impl IntoResponse, Error> {
+    let user_id = require_user(&state, &authorization, "write:account").await?;
+
+    if !grants::delete_grant(&state.db, &user_id, &client_id).await? {
+        return Err(Error::GrantNotFound);
+    }
+
+    // The application has to ask for consent again to get new tokens
+    revoke_client_tokens(&state, &user_id, &client_id).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn delete_grant(
+    State(state): State<AppState>,
+    TypedHeader(authorization): TypedHeader<Authorization<Bearer>>,
+    Path(client_id): Path<ClientId>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let res = delete_grant_impl(state, authorization, client_id).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
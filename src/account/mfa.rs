@@ -0,0 +1,242 @@
+use axum::{
+    extract::State,
+    headers::{authorization::Bearer, Authorization},
+    http::StatusCode,
+    response::IntoResponse,
+    Json, TypedHeader,
+};
+use chrono::Duration;
+use futures::channel::oneshot;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{
+    auth::states::{TokenMetadata, TotpEnrollmentState},
+    error::Error,
+    gen_string, mfa,
+    sessions::{self, ACR_MFA},
+    users, AppState,
+};
+
+use super::{require_token, require_user};
+
+/// How long an enrollment can be confirmed.
+const ENROLLMENT_TTL_MINUTES: i64 = 10;
+
+#[derive(Deserialize)]
+pub struct ConfirmTotp {
+    /// The ID returned when the enrollment was started.
+    enrollment_id: String,
+    code: String,
+    /// A code of the TOTP that is replaced, see `require_second_factor`.
+    current_code: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct CurrentCode {
+    /// A TOTP or recovery code, see `require_second_factor`.
+    code: String,
+}
+
+fn enrollment_key(enrollment_id: &str) -> String {
+    format!("totp:enrollment:{enrollment_id}")
+}
+
+/// Changes to the second factor of a user require that the session of the access token used it,
+/// or otherwise a current TOTP or recovery code. This keeps a token of a sign in with only a
+/// password from removing or replacing the second factor.
+async fn require_second_factor(
+    state: &AppState,
+    token_meta: &TokenMetadata,
+    code: Option<&str>,
+) -> Result<(), Error> {
+    if let Some(session_id) = &token_meta.session_id {
+        let session = sessions::get_session(&state.db, session_id).await?;
+        if session.map_or(false, |session| session.acr() == ACR_MFA) {
+            return Ok(());
+        }
+    }
+
+    let code = code.ok_or(Error::MfaRequired)?;
+
+    if !mfa::count_attempt(&state.db, &token_meta.user_id).await? {
+        return Err(Error::MfaLocked);
+    }
+
+    if mfa::verify_totp(state, &token_meta.user_id, code).await?
+        || mfa::use_recovery_code(&state.db, &token_meta.user_id, code).await?
+    {
+        return mfa::reset_failed_attempts(&state.db, &token_meta.user_id).await;
+    }
+
+    Err(Error::InvalidMfaCode)
+}
+
+async fn start_totp_impl(
+    state: AppState,
+    authorization: Authorization<Bearer>,
+) -> Result<impl IntoResponse, Error> {
+    let user_id = require_user(&state, &authorization, "write:account").await?;
+
+    let user = users::get_user(&state.db, &user_id)
+        .await
+        .map_err(Error::D1)?
+        .ok_or(Error::UserNotFound)?;
+
+    let secret = mfa::generate_secret();
+    let enrollment_id = gen_string(32);
+    let uri = mfa::otpauth_uri(&secret, user.email.as_deref().unwrap_or(&user.id));
+
+    state
+        .kv
+        .put(
+            &enrollment_key(&enrollment_id),
+            TotpEnrollmentState {
+                user_id,
+                secret: secret.clone(),
+            },
+        )
+        .unwrap()
+        .expiration_ttl(Duration::minutes(ENROLLMENT_TTL_MINUTES).num_seconds() as u64)
+        .execute()
+        .await
+        .map_err(Error::Kv)?;
+
+    Ok(Json(json!({
+        "enrollment_id": enrollment_id,
+        "secret": secret,
+        "uri": uri,
+    })))
+}
+
+pub async fn start_totp(
+    State(state): State<AppState>,
+    TypedHeader(authorization): TypedHeader<Authorization<Bearer>>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let res = start_totp_impl(state, authorization).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
+
+async fn confirm_totp_impl(
+    state: AppState,
+    authorization: Authorization<Bearer>,
+    req: ConfirmTotp,
+) -> Result<impl IntoResponse, Error> {
+    let token_meta = require_token(&state, &authorization, "write:account").await?;
+    let user_id = token_meta.user_id.clone();
+
+    let key = enrollment_key(&req.enrollment_id);
+    let enrollment = state
+        .kv
+        .get(&key)
+        .json::<TotpEnrollmentState>()
+        .await
+        .map_err(Error::Kv)?
+        .filter(|enrollment| enrollment.user_id == user_id)
+        .ok_or(Error::InvalidLink)?;
+
+    let step =
+        mfa::verify_enrollment(&enrollment.secret, &req.code).ok_or(Error::InvalidMfaCode)?;
+
+    if mfa::has_totp(&state.db, &user_id).await? {
+        require_second_factor(&state, &token_meta, req.current_code.as_deref()).await?;
+    }
+
+    state.kv.delete(&key).await.map_err(Error::Kv)?;
+
+    let recovery_codes = mfa::enroll_totp(&state, &user_id, &enrollment.secret, step).await?;
+
+    Ok(Json(json!({ "recovery_codes": recovery_codes })))
+}
+
+pub async fn confirm_totp(
+    State(state): State<AppState>,
+    TypedHeader(authorization): TypedHeader<Authorization<Bearer>>,
+    Json(req): Json<ConfirmTotp>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let res = confirm_totp_impl(state, authorization, req).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
+
+async fn delete_totp_impl(
+    state: AppState,
+    authorization: Authorization<Bearer>,
+    req: Option<CurrentCode>,
+) -> Result<impl IntoResponse, Error> {
+    let token_meta = require_token(&state, &authorization, "write:account").await?;
+
+    if !mfa::has_totp(&state.db, &token_meta.user_id).await? {
+        return Err(Error::MfaNotEnrolled);
+    }
+
+    let code = req.as_ref().map(|req| req.code.as_str());
+    require_second_factor(&state, &token_meta, code).await?;
+
+    if !mfa::disable_totp(&state.db, &token_meta.user_id).await? {
+        return Err(Error::MfaNotEnrolled);
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn delete_totp(
+    State(state): State<AppState>,
+    TypedHeader(authorization): TypedHeader<Authorization<Bearer>>,
+    req: Option<Json<CurrentCode>>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let res = delete_totp_impl(state, authorization, req.map(|Json(req)| req)).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
+
+async fn regenerate_recovery_codes_impl(
+    state: AppState,
+    authorization: Authorization<Bearer>,
+    req: Option<CurrentCode>,
+) -> Result<impl IntoResponse, Error> {
+    let token_meta = require_token(&state, &authorization, "write:account").await?;
+
+    if !mfa::has_totp(&state.db, &token_meta.user_id).await? {
+        return Err(Error::MfaNotEnrolled);
+    }
+
+    let code = req.as_ref().map(|req| req.code.as_str());
+    require_second_factor(&state, &token_meta, code).await?;
+
+    let recovery_codes = mfa::regenerate_recovery_codes(&state.db, &token_meta.user_id).await?;
+
+    Ok(Json(json!({ "recovery_codes": recovery_codes })))
+}
+
+pub async fn regenerate_recovery_codes(
+    State(state): State<AppState>,
+    TypedHeader(authorization): TypedHeader<Authorization<Bearer>>,
+    req: Option<Json<CurrentCode>>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let res =
+            regenerate_recovery_codes_impl(state, authorization, req.map(|Json(req)| req)).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
@@ -0,0 +1,190 @@
+use axum::{
+    extract::{Path, State},
+    headers::{authorization::Bearer, Authorization},
+    http::StatusCode,
+    response::IntoResponse,
+    Json, TypedHeader,
+};
+use futures::channel::oneshot;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{
+    auth::states::PasskeyRegistrationState,
+    error::Error,
+    gen_string, users,
+    webauthn::{self, PasskeyInfo, RegistrationCredential},
+    AppState,
+};
+
+use super::require_user;
+
+#[derive(Deserialize)]
+pub struct RegisterPasskey {
+    /// The ID returned with the creation options.
+    challenge_id: String,
+    name: Option<String>,
+    credential: RegistrationCredential,
+}
+
+fn registration_key(challenge_id: &str) -> String {
+    format!("webauthn:registration:{challenge_id}")
+}
+
+async fn list_passkeys_impl(
+    state: AppState,
+    authorization: Authorization<Bearer>,
+) -> Result<impl IntoResponse, Error> {
+    let user_id = require_user(&state, &authorization, "read:account").await?;
+
+    let passkeys = webauthn::list_passkeys(&state.db, &user_id).await?;
+
+    Ok(Json(
+        passkeys
+            .into_iter()
+            .map(PasskeyInfo::from)
+            .collect::<Vec<_>>(),
+    ))
+}
+
+pub async fn list_passkeys(
+    State(state): State<AppState>,
+    TypedHeader(authorization): TypedHeader<Authorization<Bearer>>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let res = list_passkeys_impl(state, authorization).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
+
+async fn passkey_options_impl(
+    state: AppState,
+    authorization: Authorization<Bearer>,
+) -> Result<impl IntoResponse, Error> {
+    let user_id = require_user(&state, &authorization, "write:account").await?;
+
+    let user = users::get_user(&state.db, &user_id)
+        .await
+        .map_err(Error::D1)?
+        .ok_or(Error::UserNotFound)?;
+
+    // Registering a passkey the user already has would replace it on the authenticator
+    let existing = webauthn::list_passkeys(&state.db, &user_id).await?;
+
+    let challenge = webauthn::challenge();
+    let challenge_id = gen_string(32);
+
+    let account_name = user.email.clone().unwrap_or_else(|| user_id.clone());
+    let options = webauthn::creation_options(
+        &challenge,
+        &user_id,
+        &account_name,
+        user.name.as_deref().unwrap_or(&account_name),
+        &existing,
+    );
+
+    state
+        .kv
+        .put(
+            &registration_key(&challenge_id),
+            PasskeyRegistrationState { user_id, challenge },
+        )
+        .unwrap()
+        .expiration_ttl(webauthn::TIMEOUT_SECONDS)
+        .execute()
+        .await
+        .map_err(Error::Kv)?;
+
+    Ok(Json(
+        json!({ "challenge_id": challenge_id, "options": options }),
+    ))
+}
+
+pub async fn passkey_options(
+    State(state): State<AppState>,
+    TypedHeader(authorization): TypedHeader<Authorization<Bearer>>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let res = passkey_options_impl(state, authorization).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
+
+async fn register_passkey_impl(
+    state: AppState,
+    authorization: Authorization<Bearer>,
+    req: RegisterPasskey,
+) -> Result<impl IntoResponse, Error> {
+    let user_id = require_user(&state, &authorization, "write:account").await?;
+
+    let key = registration_key(&req.challenge_id);
+    let registration = state
+        .kv
+        .get(&key)
+        .json::<PasskeyRegistrationState>()
+        .await
+        .map_err(Error::Kv)?
+        .filter(|registration| registration.user_id == user_id)
+        .ok_or_else(|| Error::InvalidPasskey("unknown or expired challenge".into()))?;
+
+    state.kv.delete(&key).await.map_err(Error::Kv)?;
+
+    let passkey = webauthn::verify_registration(req.credential, &registration.challenge)?;
+    let name = req.name.filter(|name| !name.trim().is_empty());
+
+    webauthn::insert_passkey(&state.db, &user_id, name.as_deref(), &passkey).await?;
+
+    Ok(StatusCode::CREATED)
+}
+
+pub async fn register_passkey(
+    State(state): State<AppState>,
+    TypedHeader(authorization): TypedHeader<Authorization<Bearer>>,
+    Json(req): Json<RegisterPasskey>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let res = register_passkey_impl(state, authorization, req).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
+
+async fn delete_passkey_impl(
+    state: AppState,
+    authorization: Authorization<Bearer>,
+    id: String,
+) -> Result<impl IntoResponse, Error> {
+    let user_id = require_user(&state, &authorization, "write:account").await?;
+
+    if !webauthn::delete_passkey(&state.db, &user_id, &id).await? {
+        return Err(Error::PasskeyNotFound);
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn delete_passkey(
+    State(state): State<AppState>,
+    TypedHeader(authorization): TypedHeader<Authorization<Bearer>>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let res = delete_passkey_impl(state, authorization, id).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
@@ -0,0 +1,184 @@
+use axum::{
+    extract::State,
+    headers::{authorization::Bearer, Authorization},
+    http::StatusCode,
+    response::IntoResponse,
+    Json, TypedHeader,
+};
+use chrono::Duration;
+use futures::channel::oneshot;
+use serde::Deserialize;
+
+use crate::{
+    auth::states::PhoneVerificationState,
+    error::Error,
+    sms::{self, normalize_phone_number, Sms, SmsSender},
+    AppState,
+};
+
+use super::require_user;
+
+/// How long the code can be used.
+const CODE_TTL_MINUTES: i64 = 10;
+
+/// Wrong codes after which the code is invalidated.
+const MAX_ATTEMPTS: u32 = 5;
+
+#[derive(Deserialize)]
+pub struct StartVerification {
+    phone_number: String,
+}
+
+#[derive(Deserialize)]
+pub struct VerifyPhone {
+    code: String,
+}
+
+/// A user verifies one number at a time, so starting again replaces the previous code.
+fn verification_key(user_id: &str) -> String {
+    format!("phone-verification:{user_id}")
+}
+
+async fn put_verification(
+    state: &AppState,
+    user_id: &str,
+    verification: &PhoneVerificationState,
+) -> Result<(), Error> {
+    state
+        .kv
+        .put(&verification_key(user_id), verification)
+        .unwrap()
+        .expiration_ttl(Duration::minutes(CODE_TTL_MINUTES).num_seconds() as u64)
+        .execute()
+        .await
+        .map_err(Error::Kv)
+}
+
+async fn send_code(sender: &SmsSender, to: String, code: &str) -> Result<(), Error> {
+    sender
+        .send(Sms {
+            to,
+            body: format!(
+                "{code} is your verification code. It expires in {CODE_TTL_MINUTES} minutes."
+            ),
+        })
+        .await
+}
+
+async fn start_verification_impl(
+    state: AppState,
+    authorization: Authorization<Bearer>,
+    req: StartVerification,
+) -> Result<impl IntoResponse, Error> {
+    let user_id = require_user(&state, &authorization, "write:account").await?;
+    let sender = SmsSender::new(&state.env)?;
+
+    let phone_number = normalize_phone_number(&req.phone_number)?;
+    sms::check_rate_limit(&state, &phone_number).await?;
+
+    let code = sms::gen_code();
+
+    put_verification(
+        &state,
+        &user_id,
+        &PhoneVerificationState {
+            phone_number: phone_number.clone(),
+            code: code.clone(),
+            attempts: 0,
+        },
+    )
+    .await?;
+
+    send_code(&sender, phone_number, &code).await?;
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+pub async fn start_verification(
+    State(state): State<AppState>,
+    TypedHeader(authorization): TypedHeader<Authorization<Bearer>>,
+    Json(req): Json<StartVerification>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let res = start_verification_impl(state, authorization, req).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
+
+async fn verify_phone_impl(
+    state: AppState,
+    authorization: Authorization<Bearer>,
+    req: VerifyPhone,
+) -> Result<impl IntoResponse, Error> {
+    let user_id = require_user(&state, &authorization, "write:account").await?;
+
+    let key = verification_key(&user_id);
+    let mut verification = state
+        .kv
+        .get(&key)
+        .json::<PhoneVerificationState>()
+        .await
+        .map_err(Error::Kv)?
+        .ok_or(Error::InvalidLink)?;
+
+    if req.code.trim() == verification.code {
+        state.kv.delete(&key).await.map_err(Error::Kv)?;
+
+        sms::set_phone_verified(&state.db, &user_id, &verification.phone_number).await?;
+
+        return Ok(StatusCode::NO_CONTENT);
+    }
+
+    verification.attempts += 1;
+    if verification.attempts >= MAX_ATTEMPTS {
+        state.kv.delete(&key).await.map_err(Error::Kv)?;
+    } else {
+        put_verification(&state, &user_id, &verification).await?;
+    }
+
+    Err(Error::InvalidMfaCode)
+}
+
+pub async fn verify_phone(
+    State(state): State<AppState>,
+    TypedHeader(authorization): TypedHeader<Authorization<Bearer>>,
+    Json(req): Json<VerifyPhone>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let res = verify_phone_impl(state, authorization, req).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use futures::executor::block_on;
+
+    use super::*;
+
+    #[test]
+    fn sends_verification_code() {
+        let outbox = Rc::default();
+        let sender = SmsSender::Memory(Rc::clone(&outbox));
+        let code = sms::gen_code();
+
+        block_on(send_code(&sender, "+14155552671".into(), &code)).unwrap();
+
+        let outbox = outbox.borrow();
+        assert_eq!(outbox.len(), 1);
+        assert_eq!(outbox[0].to, "+14155552671");
+        assert!(outbox[0]
+            .body
+            .starts_with(&format!("{code} is your verification code.")));
+    }
+}
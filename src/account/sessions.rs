@@ -0,0 +1,104 @@
+use axum::{
+    extract::{Path, State},
+    headers::{authorization::Bearer, Authorization},
+    http::StatusCode,
+    response::IntoResponse,
+    Json, TypedHeader,
+};
+use futures::channel::oneshot;
+
+use crate::{
+    auth::logout::{end_session, end_user_sessions},
+    error::Error,
+    sessions::{self, SessionInfo},
+    AppState,
+};
+
+use super::require_user;
+
+async fn list_sessions_impl(
+    state: AppState,
+    authorization: Authorization<Bearer>,
+) -> Result<impl IntoResponse, Error> {
+    let user_id = require_user(&state, &authorization, "read:account").await?;
+
+    let sessions = sessions::list_sessions(&state.db, &user_id).await?;
+
+    Ok(Json(
+        sessions
+            .into_iter()
+            .map(SessionInfo::from)
+            .collect::<Vec<_>>(),
+    ))
+}
+
+pub async fn list_sessions(
+    State(state): State<AppState>,
+    TypedHeader(authorization): TypedHeader<Authorization<Bearer>>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let res = list_sessions_impl(state, authorization).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
+
+async fn delete_sessions_impl(
+    state: AppState,
+    authorization: Authorization<Bearer>,
+) -> Result<impl IntoResponse, Error> {
+    let user_id = require_user(&state, &authorization, "write:account").await?;
+
+    end_user_sessions(&state, &user_id).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn delete_sessions(
+    State(state): State<AppState>,
+    TypedHeader(authorization): TypedHeader<Authorization<Bearer>>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let res = delete_sessions_impl(state, authorization).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
+
+async fn delete_session_impl(
+    state: AppState,
+    authorization: Authorization<Bearer>,
+    id: String,
+) -> Result<impl IntoResponse, Error> {
+    let user_id = require_user(&state, &authorization, "write:account").await?;
+
+    let session = sessions::get_session(&state.db, &id)
+        .await?
+        .filter(|session| session.user_id == user_id)
+        .ok_or(Error::SessionNotFound)?;
+
+    end_session(&state, &session).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn delete_session(
+    State(state): State<AppState>,
+    TypedHeader(authorization): TypedHeader<Authorization<Bearer>>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let res = delete_session_impl(state, authorization, id).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
@@ -0,0 +1,96 @@
+use axum::{
+    extract::{Path, State},
+    headers::{authorization::Bearer, Authorization},
+    http::StatusCode,
+    response::IntoResponse,
+    Json, TypedHeader,
+};
+use futures::channel::oneshot;
+
+use crate::{
+    auth::revocation::{self, revoke_token_set, revoke_user_tokens, TokenInfo},
+    error::Error,
+    AppState,
+};
+
+use super::require_user;
+
+async fn list_tokens_impl(
+    state: AppState,
+    authorization: Authorization<Bearer>,
+) -> Result<impl IntoResponse, Error> {
+    let user_id = require_user(&state, &authorization, "read:account").await?;
+
+    let tokens = revocation::list_tokens(&state.db, &user_id).await?;
+
+    Ok(Json(
+        tokens.into_iter().map(TokenInfo::from).collect::<Vec<_>>(),
+    ))
+}
+
+pub async fn list_tokens(
+    State(state): State<AppState>,
+    TypedHeader(authorization): TypedHeader<Authorization<Bearer>>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let res = list_tokens_impl(state, authorization).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
+
+async fn delete_tokens_impl(
+    state: AppState,
+    authorization: Authorization<Bearer>,
+) -> Result<impl IntoResponse, Error> {
+    let user_id = require_user(&state, &authorization, "write:account").await?;
+
+    // This includes the token of the request itself
+    revoke_user_tokens(&state, &user_id).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn delete_tokens(
+    State(state): State<AppState>,
+    TypedHeader(authorization): TypedHeader<Authorization<Bearer>>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let res = delete_tokens_impl(state, authorization).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
+
+async fn delete_token_impl(
+    state: AppState,
+    authorization: Authorization<Bearer>,
+    id: String,
+) -> Result<impl IntoResponse, Error> {
+    let user_id = require_user(&state, &authorization, "write:account").await?;
+
+    revoke_token_set(&state, &user_id, &id).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn delete_token(
+    State(state): State<AppState>,
+    TypedHeader(authorization): TypedHeader<Authorization<Bearer>>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let res = delete_token_impl(state, authorization, id).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
@@ -0,0 +1,63 @@
+use axum::{
+    headers::{authorization::Bearer, Authorization},
+    routing::{delete, get, put},
+    Router,
+};
+use worker::body::Body;
+
+use crate::{error::Error, tokens, AppState};
+
+mod enterprise_connections;
+mod providers;
+mod sessions;
+mod users;
+
+async fn require_scope(
+    state: &AppState,
+    authorization: &Authorization<Bearer>,
+    scope: &str,
+) -> Result<(), Error> {
+    let token_meta = tokens::get_access_token(state, authorization.token()).await?;
+
+    if !token_meta.has_scope(scope) {
+        return Err(Error::MissingPermission);
+    }
+
+    Ok(())
+}
+
+pub fn router() -> Router<AppState, Body> {
+    Router::new()
+        .route("/users/:user_id", get(users::get_user))
+        .route(
+            "/users/:user_id/connections/:provider/token",
+            get(users::get_connection_token),
+        )
+        .route(
+            "/users/:user_id/sessions",
+            get(sessions::list_sessions).delete(sessions::delete_sessions),
+        )
+        .route(
+            "/users/:user_id/sessions/:id",
+            delete(sessions::delete_session),
+        )
+        .route(
+            "/users/:user_id/tokens",
+            get(sessions::list_tokens).delete(sessions::delete_tokens),
+        )
+        .route("/users/:user_id/tokens/:id", delete(sessions::delete_token))
+        .route("/providers", get(providers::list_providers))
+        .route(
+            "/providers/:name",
+            put(providers::put_provider).delete(providers::delete_provider),
+        )
+        .route(
+            "/enterprise-connections",
+            get(enterprise_connections::list_connections),
+        )
+        .route(
+            "/enterprise-connections/:name",
+            put(enterprise_connections::put_connection)
+                .delete(enterprise_connections::delete_connection),
+        )
+}
@@ -0,0 +1,147 @@
+use axum::{
+    extract::{Path, State},
+    headers::{authorization::Bearer, Authorization},
+    http::StatusCode,
+    response::IntoResponse,
+    Json, TypedHeader,
+};
+use futures::channel::oneshot;
+use serde::Deserialize;
+
+use crate::{
+    crypto,
+    enterprise::{self, EnterpriseConnection},
+    error::Error,
+    providers::{self, client_secret_key},
+    AppState,
+};
+
+use super::require_scope;
+
+#[derive(Deserialize)]
+pub struct PutConnection {
+    #[serde(flatten)]
+    connection: EnterpriseConnection,
+    client_secret: Option<String>,
+}
+
+async fn list_connections_impl(
+    state: AppState,
+    authorization: Authorization<Bearer>,
+) -> Result<impl IntoResponse, Error> {
+    require_scope(&state, &authorization, "read:connections").await?;
+
+    Ok(Json(enterprise::list_connections(&state.db).await?))
+}
+
+pub async fn list_connections(
+    State(state): State<AppState>,
+    TypedHeader(authorization): TypedHeader<Authorization<Bearer>>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let res = list_connections_impl(state, authorization).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
+
+async fn put_connection_impl(
+    state: AppState,
+    authorization: Authorization<Bearer>,
+    name: String,
+    req: PutConnection,
+) -> Result<impl IntoResponse, Error> {
+    require_scope(&state, &authorization, "write:connections").await?;
+
+    let mut connection = req.connection;
+
+    if connection.name != name {
+        return Err(Error::InvalidProvider(
+            "name does not match the path".into(),
+        ));
+    }
+
+    connection.validate()?;
+
+    if providers::list_providers(&state.db)
+        .await?
+        .iter()
+        .any(|provider| provider.name == connection.name)
+    {
+        return Err(Error::InvalidProvider("name is used by a provider".into()));
+    }
+
+    if let Some(domain) = enterprise::find_taken_domain(&state.db, &connection).await? {
+        return Err(Error::InvalidProvider(format!(
+            "domain {domain} is used by another connection"
+        )));
+    }
+
+    if let Some(client_secret) = req.client_secret {
+        connection.client_secret = Some(
+            crypto::encrypt(
+                &state.env,
+                &client_secret_key(&connection.name),
+                &client_secret,
+            )
+            .await?,
+        );
+    } else if enterprise::get_connection(&state.db, &connection.name)
+        .await?
+        .is_none()
+    {
+        return Err(Error::InvalidProvider("client_secret is required".into()));
+    }
+
+    enterprise::upsert_connection(&state.db, &connection).await?;
+
+    Ok(Json(connection))
+}
+
+pub async fn put_connection(
+    State(state): State<AppState>,
+    TypedHeader(authorization): TypedHeader<Authorization<Bearer>>,
+    Path(name): Path<String>,
+    Json(req): Json<PutConnection>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let res = put_connection_impl(state, authorization, name, req).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
+
+async fn delete_connection_impl(
+    state: AppState,
+    authorization: Authorization<Bearer>,
+    name: String,
+) -> Result<impl IntoResponse, Error> {
+    require_scope(&state, &authorization, "write:connections").await?;
+
+    if !enterprise::delete_connection(&state.db, &name).await? {
+        return Err(Error::ConnectionNotFound);
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn delete_connection(
+    State(state): State<AppState>,
+    TypedHeader(authorization): TypedHeader<Authorization<Bearer>>,
+    Path(name): Path<String>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let res = delete_connection_impl(state, authorization, name).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
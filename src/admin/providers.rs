@@ -0,0 +1,142 @@
+use axum::{
+    extract::{Path, State},
+    headers::{authorization::Bearer, Authorization},
+    http::StatusCode,
+    response::IntoResponse,
+    Json, TypedHeader,
+};
+use futures::channel::oneshot;
+use serde::Deserialize;
+
+use crate::{
+    crypto, enterprise,
+    error::Error,
+    providers::{self, client_secret_key, ProviderConfig},
+    AppState,
+};
+
+use super::require_scope;
+
+#[derive(Deserialize)]
+pub struct PutProvider {
+    #[serde(flatten)]
+    provider: ProviderConfig,
+    client_secret: Option<String>,
+}
+
+async fn list_providers_impl(
+    state: AppState,
+    authorization: Authorization<Bearer>,
+) -> Result<impl IntoResponse, Error> {
+    require_scope(&state, &authorization, "read:providers").await?;
+
+    Ok(Json(providers::list_providers(&state.db).await?))
+}
+
+pub async fn list_providers(
+    State(state): State<AppState>,
+    TypedHeader(authorization): TypedHeader<Authorization<Bearer>>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let res = list_providers_impl(state, authorization).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
+
+async fn put_provider_impl(
+    state: AppState,
+    authorization: Authorization<Bearer>,
+    name: String,
+    req: PutProvider,
+) -> Result<impl IntoResponse, Error> {
+    require_scope(&state, &authorization, "write:providers").await?;
+
+    let mut provider = req.provider;
+
+    if provider.name != name {
+        return Err(Error::InvalidProvider(
+            "name does not match the path".into(),
+        ));
+    }
+
+    if enterprise::get_connection(&state.db, &provider.name)
+        .await?
+        .is_some()
+    {
+        return Err(Error::InvalidProvider(
+            "name is used by an enterprise connection".into(),
+        ));
+    }
+
+    provider.client_secret = match req.client_secret {
+        Some(client_secret) => Some(
+            crypto::encrypt(
+                &state.env,
+                &client_secret_key(&provider.name),
+                &client_secret,
+            )
+            .await?,
+        ),
+        // Updates without a secret keep the stored one
+        None => providers::list_providers(&state.db)
+            .await?
+            .into_iter()
+            .find(|existing| existing.name == provider.name)
+            .and_then(|existing| existing.client_secret),
+    };
+
+    provider.validate(&state.env)?;
+
+    providers::upsert_provider(&state.db, &provider).await?;
+
+    Ok(Json(provider))
+}
+
+pub async fn put_provider(
+    State(state): State<AppState>,
+    TypedHeader(authorization): TypedHeader<Authorization<Bearer>>,
+    Path(name): Path<String>,
+    Json(req): Json<PutProvider>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let res = put_provider_impl(state, authorization, name, req).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
+
+async fn delete_provider_impl(
+    state: AppState,
+    authorization: Authorization<Bearer>,
+    name: String,
+) -> Result<impl IntoResponse, Error> {
+    require_scope(&state, &authorization, "write:providers").await?;
+
+    if !providers::delete_provider(&state.db, &name).await? {
+        return Err(Error::ProviderNotFound);
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn delete_provider(
+    State(state): State<AppState>,
+    TypedHeader(authorization): TypedHeader<Authorization<Bearer>>,
+    Path(name): Path<String>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let res = delete_provider_impl(state, authorization, name).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
@@ -0,0 +1,196 @@
+use axum::{
+    extract::{Path, State},
+    headers::{authorization::Bearer, Authorization},
+    http::StatusCode,
+    response::IntoResponse,
+    Json, TypedHeader,
+};
+use futures::channel::oneshot;
+
+use crate::{
+    auth::{
+        logout::{end_session, end_user_sessions},
+        revocation::{self, revoke_token_set, revoke_user_tokens, TokenInfo},
+    },
+    error::Error,
+    sessions::{self, SessionInfo},
+    AppState,
+};
+
+use super::require_scope;
+
+async fn list_sessions_impl(
+    state: AppState,
+    authorization: Authorization<Bearer>,
+    user_id: String,
+) -> Result<impl IntoResponse, Error> {
+    require_scope(&state, &authorization, "read:users").await?;
+
+    let sessions = sessions::list_sessions(&state.db, &user_id).await?;
+
+    Ok(Json(
+        sessions
+            .into_iter()
+            .map(SessionInfo::from)
+            .collect::<Vec<_>>(),
+    ))
+}
+
+pub async fn list_sessions(
+    State(state): State<AppState>,
+    TypedHeader(authorization): TypedHeader<Authorization<Bearer>>,
+    Path(user_id): Path<String>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let res = list_sessions_impl(state, authorization, user_id).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
+
+async fn delete_sessions_impl(
+    state: AppState,
+    authorization: Authorization<Bearer>,
+    user_id: String,
+) -> Result<impl IntoResponse, Error> {
+    require_scope(&state, &authorization, "write:users").await?;
+
+    end_user_sessions(&state, &user_id).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn delete_sessions(
+    State(state): State<AppState>,
+    TypedHeader(authorization): TypedHeader<Authorization<Bearer>>,
+    Path(user_id): Path<String>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let res = delete_sessions_impl(state, authorization, user_id).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
+
+async fn delete_session_impl(
+    state: AppState,
+    authorization: Authorization<Bearer>,
+    user_id: String,
+    id: String,
+) -> Result<impl IntoResponse, Error> {
+    require_scope(&state, &authorization, "write:users").await?;
+
+    let session = sessions::get_session(&state.db, &id)
+        .await?
+        .filter(|session| session.user_id == user_id)
+        .ok_or(Error::SessionNotFound)?;
+
+    end_session(&state, &session).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn delete_session(
+    State(state): State<AppState>,
+    TypedHeader(authorization): TypedHeader<Authorization<Bearer>>,
+    Path((user_id, id)): Path<(String, String)>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let res = delete_session_impl(state, authorization, user_id, id).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
+
+async fn list_tokens_impl(
+    state: AppState,
+    authorization: Authorization<Bearer>,
+    user_id: String,
+) -> Result<impl IntoResponse, Error> {
+    require_scope(&state, &authorization, "read:users").await?;
+
+    let tokens = revocation::list_tokens(&state.db, &user_id).await?;
+
+    Ok(Json(
+        tokens.into_iter().map(TokenInfo::from).collect::<Vec<_>>(),
+    ))
+}
+
+pub async fn list_tokens(
+    State(state): State<AppState>,
+    TypedHeader(authorization): TypedHeader<Authorization<Bearer>>,
+    Path(user_id): Path<String>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let res = list_tokens_impl(state, authorization, user_id).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
+
+async fn delete_tokens_impl(
+    state: AppState,
+    authorization: Authorization<Bearer>,
+    user_id: String,
+) -> Result<impl IntoResponse, Error> {
+    require_scope(&state, &authorization, "write:users").await?;
+
+    revoke_user_tokens(&state, &user_id).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn delete_tokens(
+    State(state): State<AppState>,
+    TypedHeader(authorization): TypedHeader<Authorization<Bearer>>,
+    Path(user_id): Path<String>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let res = delete_tokens_impl(state, authorization, user_id).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
+
+async fn delete_token_impl(
+    state: AppState,
+    authorization: Authorization<Bearer>,
+    user_id: String,
+    id: String,
+) -> Result<impl IntoResponse, Error> {
+    require_scope(&state, &authorization, "write:users").await?;
+
+    revoke_token_set(&state, &user_id, &id).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn delete_token(
+    State(state): State<AppState>,
+    TypedHeader(authorization): TypedHeader<Authorization<Bearer>>,
+    Path((user_id, id)): Path<(String, String)>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let res = delete_token_impl(state, authorization, user_id, id).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
@@ -0,0 +1,109 @@
+use axum::{
+    extract::{Path, State},
+    headers::{authorization::Bearer, Authorization},
+    response::IntoResponse,
+    Json, TypedHeader,
+};
+use chrono::{DateTime, Utc};
+use futures::channel::oneshot;
+use oauth2::AccessToken;
+use serde::Serialize;
+use serde_json::json;
+
+use crate::{
+    audit::{self, AuditEvent},
+    auth::connections::get_connection_tokens,
+    error::Error,
+    tokens, users, AppState,
+};
+
+#[derive(Serialize)]
+struct ConnectionTokenResponse {
+    access_token: AccessToken,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+async fn get_user_impl(
+    state: AppState,
+    authorization: Authorization<Bearer>,
+    user_id: String,
+) -> Result<impl IntoResponse, Error> {
+    let token_meta = tokens::get_access_token(&state, authorization.token()).await?;
+
+    if !token_meta.has_scope("read:users") {
+        return Err(Error::MissingPermission);
+    }
+
+    let user = users::get_user(&state.db, &user_id)
+        .await
+        .map_err(Error::D1)?
+        .ok_or(Error::UserNotFound)?;
+
+    Ok(Json(user))
+}
+
+pub async fn get_user(
+    State(state): State<AppState>,
+    TypedHeader(authorization): TypedHeader<Authorization<Bearer>>,
+    Path(user_id): Path<String>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let res = get_user_impl(state, authorization, user_id).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
+
+async fn get_connection_token_impl(
+    state: AppState,
+    authorization: Authorization<Bearer>,
+    user_id: String,
+    provider: String,
+) -> Result<impl IntoResponse, Error> {
+    let token_meta = tokens::get_access_token(&state, authorization.token()).await?;
+
+    // Reading the tokens of other users requires access to all users
+    if !token_meta.has_scope("read:user_idp_tokens")
+        || (token_meta.user_id != user_id && !token_meta.has_scope("read:users"))
+    {
+        return Err(Error::MissingPermission);
+    }
+
+    let tokens = get_connection_tokens(&state, &user_id, &provider).await?;
+
+    audit::record(
+        &state.db,
+        AuditEvent {
+            action: audit::CONNECTION_TOKEN_READ,
+            actor_id: Some(&token_meta.user_id),
+            client_id: Some(&token_meta.client_id),
+            target_id: Some(&user_id),
+            details: Some(json!({ "provider": provider })),
+        },
+    )
+    .await
+    .map_err(Error::D1)?;
+
+    Ok(Json(ConnectionTokenResponse {
+        access_token: tokens.access_token,
+        expires_at: tokens.expires_at,
+    }))
+}
+
+pub async fn get_connection_token(
+    State(state): State<AppState>,
+    TypedHeader(authorization): TypedHeader<Authorization<Bearer>>,
+    Path((user_id, provider)): Path<(String, String)>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let res = get_connection_token_impl(state, authorization, user_id, provider).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
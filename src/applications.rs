@@ -56,12 +56,112 @@ WHERE client_id = ?
     })
 }
 
+/// Whether users of the application must use a second factor.
+pub async fn requires_mfa(db: &d1::Database, client_id: &ClientId) -> bool {
+    d1::query!(
+        db,
+        r#"
+SELECT require_mfa
+FROM applications
+WHERE client_id = ?
+        "#,
+        client_id,
+    )
+    .unwrap()
+    .first::<u8>(Some("require_mfa"))
+    .await
+    .unwrap()
+    .map_or(false, |require_mfa| require_mfa == 1)
+}
+
+/// Whether the application belongs to the same party as the worker, so users don't have to
+/// approve the scopes it requests.
+pub async fn is_first_party(db: &d1::Database, client_id: &ClientId) -> bool {
+    d1::query!(
+        db,
+        r#"
+SELECT first_party
+FROM applications
+WHERE client_id = ?
+        "#,
+        client_id,
+    )
+    .unwrap()
+    .first::<u8>(Some("first_party"))
+    .await
+    .unwrap()
+    .map_or(false, |first_party| first_party == 1)
+}
+
+pub async fn get_name(db: &d1::Database, client_id: &ClientId) -> Option<String> {
+    d1::query!(
+        db,
+        r#"
+SELECT name
+FROM applications
+WHERE client_id = ?
+        "#,
+        client_id,
+    )
+    .unwrap()
+    .first::<String>(Some("name"))
+    .await
+    .unwrap()
+}
+
+/// Whether the URI is the one the application registered to receive authorization responses.
+pub async fn is_redirect_uri(db: &d1::Database, client_id: &ClientId, uri: &str) -> bool {
+    d1::query!(
+        db,
+        r#"
+SELECT redirect_uri
+FROM applications
+WHERE client_id = ?
+        "#,
+        client_id,
+    )
+    .unwrap()
+    .first::<String>(Some("redirect_uri"))
+    .await
+    .unwrap()
+    .map_or(false, |redirect_uri| redirect_uri == uri)
+}
+
+/// Whether the application registered the URI to redirect to after signing users out.
+pub async fn is_post_logout_redirect_uri(
+    db: &d1::Database,
+    client_id: &ClientId,
+    uri: &str,
+) -> bool {
+    d1::query!(
+        db,
+        r#"
+SELECT post_logout_redirect_uris
+FROM applications
+WHERE client_id = ?
+        "#,
+        client_id,
+    )
+    .unwrap()
+    .first::<String>(Some("post_logout_redirect_uris"))
+    .await
+    .unwrap()
+    .map_or(false, |uris| uris.split_whitespace().any(|u| u == uri))
+}
+
 #[derive(Deserialize)]
 pub struct CreateApplication {
     name: String,
     description: Option<String>,
     redirect_uri: String,
     scopes: Vec<String>,
+    #[serde(default)]
+    require_mfa: bool,
+    #[serde(default)]
+    first_party: bool,
+    #[serde(default)]
+    post_logout_redirect_uris: Vec<String>,
+    backchannel_logout_uri: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -80,8 +180,8 @@ pub async fn create_application(
     d1::query!(
         db,
         r#"
-INSERT INTO applications (client_id, client_secret, redirect_uri, name, description, scopes)
-VALUES (?, ?, ?, ?, ?, ?)
+INSERT INTO applications (client_id, client_secret, redirect_uri, name, description, scopes, require_mfa, first_party, post_logout_redirect_uris, backchannel_logout_uri)
+VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
 RETURNING client_id, client_secret
         "#,
         Alphanumeric.sample_string(&mut rng, CLIENT_ID_LEN),
@@ -90,6 +190,10 @@ RETURNING client_id, client_secret
         data.name,
         data.description,
         data.scopes.join(" "),
+        u8::from(data.require_mfa),
+        u8::from(data.first_party),
+        data.post_logout_redirect_uris.join(" "),
+        data.backchannel_logout_uri,
     )
     .unwrap()
     .first::<CreateApplicationResponse>(None)
@@ -0,0 +1,36 @@
+use chrono::Utc;
+use oauth2::ClientId;
+use serde_json::Value;
+
+use crate::d1;
+
+pub const CONNECTION_TOKEN_READ: &str = "connection_token.read";
+
+pub struct AuditEvent<'a> {
+    pub action: &'a str,
+    /// The user on whose behalf the action was performed.
+    pub actor_id: Option<&'a str>,
+    /// The application that performed the action.
+    pub client_id: Option<&'a ClientId>,
+    /// The user the action was performed on.
+    pub target_id: Option<&'a str>,
+    pub details: Option<Value>,
+}
+
+pub async fn record(db: &d1::Database, event: AuditEvent<'_>) -> worker::Result<d1::QueryResult> {
+    d1::query!(
+        db,
+        r#"
+INSERT INTO audit_logs (action, actor_id, client_id, target_id, details, created_at)
+VALUES (?, ?, ?, ?, ?, ?)
+        "#,
+        event.action,
+        event.actor_id,
+        event.client_id,
+        event.target_id,
+        event.details.map(|details| details.to_string()),
+        Utc::now(),
+    )?
+    .run()
+    .await
+}
@@ -3,19 +3,31 @@ use axum::{
     Router,
 };
 use oauth2::{ClientId, ClientSecret};
-use worker::{body::Body, Env};
+use worker::body::Body;
 
 use crate::{
+    crypto,
     error::Error,
     oauth::OAuthClient,
-    oidc::OidcClient,
-    providers::{get_provider, Provider},
+    oidc::{apple, OidcClient},
+    providers::{client_secret_key, ClientAuth, OidcProvider, Provider, ProviderConfig},
     AppState,
 };
 
 pub mod authorize;
 pub mod callback;
+pub mod connections;
+pub mod consent;
+pub mod discover;
+pub mod logout;
+pub mod mfa;
+pub mod passkey;
+pub mod password;
+pub mod passwordless;
 pub mod refresh;
+pub mod revocation;
+pub mod saml;
+pub mod sms;
 pub mod states;
 pub mod token;
 
@@ -24,37 +36,113 @@ pub enum AuthClient {
     Oidc(OidcClient),
 }
 
-async fn get_auth_client(name: &str, env: &Env) -> Result<AuthClient, Error> {
-    let provider = get_provider(name)?;
+async fn get_auth_client(state: &AppState, provider: &ProviderConfig) -> Result<AuthClient, Error> {
+    // SAML connections sign in through the assertion consumer service instead
+    if let Provider::Saml(_) = provider.provider {
+        return Err(Error::InvalidConnection);
+    }
 
-    let client_id = env
-        .var(&format!("{}_CLIENT_ID", name.to_uppercase()))
-        .expect("provider client ID not set")
-        .to_string();
+    let client_id = provider
+        .client_id
+        .clone()
+        .or_else(|| provider.env_var(&state.env, "CLIENT_ID"))
+        .ok_or(Error::MissingClientCredentials)?;
     let client_id = ClientId::new(client_id);
 
-    let client_secret = env
-        .var(&format!("{}_CLIENT_SECRET", name.to_uppercase()))
-        .expect("provider client secret not set")
-        .to_string();
-    let client_secret = ClientSecret::new(client_secret);
+    let client_secret = match &provider.client_secret {
+        Some(client_secret) => {
+            crypto::decrypt::<String>(
+                &state.env,
+                &client_secret_key(&provider.name),
+                client_secret,
+            )
+            .await?
+        }
+        None => provider
+            .env_var(&state.env, "CLIENT_SECRET")
+            .ok_or(Error::MissingClientCredentials)?,
+    };
+    let client_secret = match &provider.provider {
+        Provider::Oidc(OidcProvider {
+            client_auth: ClientAuth::AppleJwt,
+            ..
+        }) => apple::client_secret(state, provider, &client_id, &client_secret).await?,
+        _ => ClientSecret::new(client_secret),
+    };
 
-    match provider {
+    match &provider.provider {
         Provider::OAuth2(p) => Ok(AuthClient::OAuth2(OAuthClient::new(
             client_id,
             client_secret,
             p,
+            &provider.scopes,
         ))),
         Provider::Oidc(p) => Ok(AuthClient::Oidc(
-            OidcClient::new(client_id, client_secret, p).await?,
+            OidcClient::new(
+                state.kv.clone(),
+                client_id,
+                client_secret,
+                p,
+                &provider.scopes,
+            )
+            .await?,
         )),
+        Provider::Saml(_) => unreachable!(),
     }
 }
 
 pub fn router() -> Router<AppState, Body> {
     Router::new()
         .route("/authorize", get(authorize::oauth_authorize))
-        .route("/callback", get(callback::oauth_callback))
+        .route(
+            "/callback",
+            get(callback::oauth_callback).post(callback::oauth_callback_form_post),
+        )
+        .route(
+            "/consent",
+            get(consent::consent_page).post(consent::consent),
+        )
+        .route("/discover", get(discover::oauth_discover))
+        .route(
+            "/logout",
+            get(logout::logout).post(logout::logout_form_post),
+        )
+        .route("/mfa", get(mfa::mfa_page))
+        .route("/mfa/options", post(mfa::mfa_options))
+        .route("/mfa/verify", post(mfa::mfa_verify))
+        .route("/mfa/passkey", post(mfa::mfa_passkey))
+        .route("/mfa/continue", post(mfa::mfa_continue))
+        .route("/passkey/options", post(passkey::passkey_options))
+        .route("/passkey/login", post(passkey::passkey_login))
+        .route(
+            "/passkey/register/options",
+            post(passkey::passkey_register_options),
+        )
+        .route("/passkey/register", post(passkey::passkey_register))
+        .route("/password", post(password::password_login))
+        .route("/password/signup", post(password::password_signup))
+        .route("/password/verify", get(password::password_verify))
+        .route("/password/forgot", post(password::password_forgot))
+        .route(
+            "/password/reset",
+            get(password::password_reset_page).post(password::password_reset),
+        )
+        .route(
+            "/passwordless/start",
+            post(passwordless::passwordless_start),
+        )
+        .route(
+            "/passwordless/link",
+            get(passwordless::passwordless_link_page).post(passwordless::passwordless_link),
+        )
+        .route(
+            "/passwordless/code",
+            get(passwordless::passwordless_code_page).post(passwordless::passwordless_code),
+        )
+        .route("/saml/metadata", get(saml::saml_metadata))
+        .route("/saml/acs", post(saml::saml_acs))
+        .route("/sms/start", post(sms::sms_start))
+        .route("/sms/code", get(sms::sms_code_page).post(sms::sms_code))
         .route("/token", post(token::oauth_token))
         .route("/refresh", post(refresh::oauth_refresh))
 }
@@ -2,17 +2,31 @@ use std::{collections::HashSet, fmt::Debug};
 
 use axum::{
     extract::{Query, State},
+    http::HeaderMap,
     response::{Html, IntoResponse, Redirect, Response},
 };
-use chrono::Duration;
+use axum_extra::extract::cookie::CookieJar;
+use chrono::{Duration, Utc};
 use futures::channel::oneshot;
 use oauth2::{basic::BasicErrorResponseType, ClientId, CsrfToken, ResponseType, Scope};
+use openidconnect::core::CoreAuthErrorResponseType;
+use reqwest::Url;
 use serde::{Deserialize, Serialize};
 
-use crate::{applications::get_scopes, error::Error, AppState};
+use crate::{
+    applications::{get_scopes, is_redirect_uri},
+    error::Error,
+    login::login_page,
+    mfa,
+    providers::{get_provider, Provider},
+    saml,
+    sessions::{self, Device, Session, ACR_MFA, ACR_SINGLE_FACTOR},
+    users::{get_user, User},
+    AppState,
+};
 
 use super::{
-    get_auth_client,
+    consent, get_auth_client,
     states::{AuthorizeFlowState, AuthorizeFlowStateType},
     AuthClient,
 };
@@ -25,9 +39,86 @@ pub struct AuthorizeRequest {
     pub redirect_uri: String,
     pub scope: Option<String>,
     pub state: CsrfToken,
+    /// Space separated `none`, `login`, `consent` or `select_account`.
+    pub prompt: Option<String>,
+    /// Seconds since the user signed in after which they have to sign in again.
+    pub max_age: Option<i64>,
+    /// Email address or phone number of the user, which is filled in on the login page and passed
+    /// to providers.
+    pub login_hint: Option<String>,
+    /// Space separated preferred languages, which are passed to providers.
+    pub ui_locales: Option<String>,
+    /// Space separated authentication context classes, in order of preference.
+    pub acr_values: Option<String>,
+}
+
+impl AuthorizeRequest {
+    fn prompts(&self) -> impl Iterator<Item = &str> {
+        self.prompt
+            .as_deref()
+            .unwrap_or_default()
+            .split_whitespace()
+    }
+
+    pub fn has_prompt(&self, prompt: &str) -> bool {
+        self.prompts().any(|p| p == prompt)
+    }
+
+    fn acr_values(&self) -> impl Iterator<Item = &str> {
+        self.acr_values
+            .as_deref()
+            .unwrap_or_default()
+            .split_whitespace()
+    }
+
+    /// Whether the application only accepts a sign in with a second factor.
+    fn requires_mfa_acr(&self) -> bool {
+        self.acr_values().any(|acr| acr == ACR_MFA)
+            && !self.acr_values().any(|acr| acr == ACR_SINGLE_FACTOR)
+    }
+
+    /// Whether none of the requested authentication context classes are supported.
+    fn has_unmet_acr(&self) -> bool {
+        self.acr_values.is_some()
+            && !self
+                .acr_values()
+                .any(|acr| acr == ACR_MFA || acr == ACR_SINGLE_FACTOR)
+    }
 }
 
-async fn oauth_authorize_impl(state: AppState, req: AuthorizeRequest) -> impl IntoResponse {
+fn unmet_authentication_requirements() -> CoreAuthErrorResponseType {
+    CoreAuthErrorResponseType::Extension("unmet_authentication_requirements".into())
+}
+
+/// Parse an authorization request that is passed along in the query of another request, such as
+/// the sign in forms of the login page.
+pub fn parse_authorize_request(query: &str) -> Result<AuthorizeRequest, Error> {
+    serde_urlencoded::from_str(query).map_err(|_| {
+        Error::OAuth2(
+            BasicErrorResponseType::InvalidRequest,
+            "invalid authorization request".into(),
+        )
+    })
+}
+
+/// Return to the login page of an authorization request, showing a message to the user.
+pub fn back_to_login(authorize_query: &str, message: &str) -> Redirect {
+    let mut url = Url::parse(&format!("{}/oauth/authorize", env!("DOMAIN"))).unwrap();
+    let params =
+        serde_urlencoded::from_str::<Vec<(String, String)>>(authorize_query).unwrap_or_default();
+
+    url.query_pairs_mut()
+        .extend_pairs(params.iter().filter(|(key, _)| key != "message"))
+        .append_pair("message", message);
+
+    Redirect::to(url.as_str())
+}
+
+/// Check the parameters of an authorization request, returning the requested scopes.
+pub async fn validate_request(
+    state: &AppState,
+    req: &AuthorizeRequest,
+) -> Result<HashSet<Scope>, Error> {
     if req.response_type.as_str() != "code" {
         return Err(Error::OAuth2(
             BasicErrorResponseType::InvalidRequest,
@@ -35,13 +126,9 @@ async fn oauth_authorize_impl(state: AppState, req: AuthorizeRequest) -> impl In
         ));
     }
 
-    // There is an early return when connection is not set
-    let connection = req.connection.unwrap();
-
-    let auth_client = get_auth_client(&connection, &state.env).await?;
-
     let requested_scopes = req
         .scope
+        .as_ref()
         .map(|scope| {
             scope
                 .split(' ')
@@ -57,6 +144,14 @@ async fn oauth_authorize_impl(state: AppState, req: AuthorizeRequest) -> impl In
             "unable to find client".into(),
         ))?;
 
+    // Codes and errors are sent to the redirect URI, so it has to be checked before any redirect
+    if !is_redirect_uri(&state.db, &req.client_id, &req.redirect_uri).await {
+        return Err(Error::OAuth2(
+            BasicErrorResponseType::InvalidRequest,
+            "redirect_uri is not registered".into(),
+        ));
+    }
+
     if !requested_scopes.is_subset(&allowed_scopes) {
         return Err(Error::OAuth2(
             BasicErrorResponseType::InvalidScope,
@@ -64,16 +159,237 @@ async fn oauth_authorize_impl(state: AppState, req: AuthorizeRequest) -> impl In
         ));
     }
 
-    let ((auth_url, csrf_token, pkce_verifier), ty) = match auth_client {
-        AuthClient::OAuth2(client) => (client.authorize_url(), AuthorizeFlowStateType::OAuth2),
-        AuthClient::Oidc(client) => {
-            let (auth_url, csrf_token, nonce, pkce_verifier) = client.authorize_url();
+    Ok(requested_scopes)
+}
+
+/// Return an error to the application, for valid requests that can't be completed.
+pub fn error_redirect(
+    redirect_uri: &str,
+    state: &CsrfToken,
+    error: CoreAuthErrorResponseType,
+) -> Result<Redirect, Error> {
+    let mut url = Url::parse(redirect_uri).map_err(|_| {
+        Error::OAuth2(
+            BasicErrorResponseType::InvalidRequest,
+            "redirect_uri is malformed".into(),
+        )
+    })?;
+
+    url.query_pairs_mut()
+        .append_pair("error", error.as_ref())
+        .append_pair("state", state.secret());
+
+    Ok(Redirect::to(url.as_str()))
+}
+
+/// Whether the session of the browser can sign the user in without asking them.
+enum SessionCheck {
+    Reusable(Session, User),
+    /// The user has to add a second factor to the session, with the error that is returned for
+    /// `prompt=none`.
+    StepUp(Session, User, CoreAuthErrorResponseType),
+    /// The user has to use the login page, which is returned as error for `prompt=none`.
+    Interaction(CoreAuthErrorResponseType),
+}
+
+/// Check the session of the browser. A new sign in is needed when the application asks for it, or
+/// the user signed in too long ago, with another connection or as another user than hinted. The
+/// user has to step up the session when the application requires a second factor they didn't use.
+async fn check_session(
+    state: &AppState,
+    req: &AuthorizeRequest,
+    jar: &CookieJar,
+) -> Result<SessionCheck, Error> {
+    let login_required = SessionCheck::Interaction(CoreAuthErrorResponseType::LoginRequired);
+
+    if req.has_prompt("login") {
+        return Ok(login_required);
+    }
+    // The login page is where users choose the account
+    if req.has_prompt("select_account") {
+        return Ok(SessionCheck::Interaction(
+            CoreAuthErrorResponseType::AccountSelectionRequired,
+        ));
+    }
+
+    let Some(session) = sessions::find_session(&state.db, jar).await? else {
+        return Ok(login_required);
+    };
+
+    if let Some(max_age) = req.max_age {
+        if Utc::now() - session.auth_time() > Duration::seconds(max_age) {
+            return Ok(login_required);
+        }
+    }
+
+    if req
+        .connection
+        .as_ref()
+        .map_or(false, |connection| *connection != session.connection)
+    {
+        return Ok(login_required);
+    }
+
+    let Some(user) = get_user(&state.db, &session.user_id)
+        .await
+        .map_err(Error::D1)?
+        .filter(|user| user.blocked != Some(true))
+    else {
+        return Ok(login_required);
+    };
+
+    if let Some(login_hint) = &req.login_hint {
+        let matches = [&user.email, &user.phone_number]
+            .into_iter()
+            .flatten()
+            .any(|hint| hint.eq_ignore_ascii_case(login_hint.trim()));
+        if !matches {
+            return Ok(login_required);
+        }
+    }
+
+    if !session.amr().iter().any(|method| method == "mfa") {
+        if req.requires_mfa_acr() {
+            return Ok(SessionCheck::StepUp(
+                session,
+                user,
+                unmet_authentication_requirements(),
+            ));
+        }
+
+        if mfa::is_required(state, &req.client_id).await {
+            return Ok(SessionCheck::StepUp(
+                session,
+                user,
+                CoreAuthErrorResponseType::InteractionRequired,
+            ));
+        }
+    }
+
+    Ok(SessionCheck::Reusable(session, user))
+}
+
+async fn oauth_authorize_impl(
+    state: AppState,
+    req: AuthorizeRequest,
+    jar: CookieJar,
+    device: Device,
+) -> Result<Response, Error> {
+    if req.has_prompt("none") && req.prompts().count() > 1 {
+        return Err(Error::OAuth2(
+            BasicErrorResponseType::InvalidRequest,
+            "prompt none can't be combined with other values".into(),
+        ));
+    }
+
+    // Errors are sent to the redirect URI, so the request is validated before anything else
+    let scopes = validate_request(&state, &req).await?;
+
+    if req.has_unmet_acr() {
+        return Ok(error_redirect(
+            &req.redirect_uri,
+            &req.state,
+            unmet_authentication_requirements(),
+        )?
+        .into_response());
+    }
+
+    let (session, user, step_up) = match check_session(&state, &req, &jar).await? {
+        SessionCheck::Reusable(session, user) => (session, user, false),
+        SessionCheck::StepUp(_, _, error) | SessionCheck::Interaction(error)
+            if req.has_prompt("none") =>
+        {
+            return Ok(error_redirect(&req.redirect_uri, &req.state, error)?.into_response());
+        }
+        SessionCheck::StepUp(session, user, _) => (session, user, true),
+        SessionCheck::Interaction(_) => return sign_in_page(&state, req, scopes, device).await,
+    };
+
+    sessions::touch_session(&state.db, &session.id).await?;
+
+    let flow = AuthorizeFlowState {
+        ty: AuthorizeFlowStateType::Session {
+            session_id: session.id.clone(),
+        },
+        connection: session.connection.clone(),
+        state: req.state,
+        scopes,
+        client_id: req.client_id,
+        redirect_uri: req.redirect_uri,
+        prompt: req.prompt,
+        acr_values: req.acr_values,
+        device,
+    };
+
+    if step_up {
+        return Ok(super::mfa::challenge(&state, flow, &user)
+            .await?
+            .into_response());
+    }
+
+    if flow.has_prompt("none") && consent::is_needed(&state, &flow, &user.id).await? {
+        return Ok(error_redirect(
+            &flow.redirect_uri,
+            &flow.state,
+            CoreAuthErrorResponseType::ConsentRequired,
+        )?
+        .into_response());
+    }
+
+    Ok(consent::continue_flow(&state, flow, user, &session)
+        .await?
+        .into_response())
+}
+
+/// Show the login page, or redirect to the provider of the connection of the request.
+async fn sign_in_page(
+    state: &AppState,
+    req: AuthorizeRequest,
+    requested_scopes: HashSet<Scope>,
+    device: Device,
+) -> Result<Response, Error> {
+    let Some(connection) = req.connection.clone() else {
+        return Ok(login_page(state).await?.into_response());
+    };
+
+    let provider = get_provider(&state.db, &connection).await?;
+
+    let (response, csrf_token, ty) = match &provider.provider {
+        Provider::Saml(saml) => {
+            let csrf_token = CsrfToken::new_random();
+            let request_id = saml::request_id();
+            let form = saml::authn_request_form(saml, &request_id, csrf_token.secret());
 
             (
-                (auth_url, csrf_token, pkce_verifier),
-                AuthorizeFlowStateType::Oidc { nonce },
+                Html(form).into_response(),
+                csrf_token,
+                AuthorizeFlowStateType::Saml { request_id },
             )
         }
+        _ => match get_auth_client(state, &provider).await? {
+            AuthClient::OAuth2(client) => {
+                let (auth_url, csrf_token, pkce_verifier) = client.authorize_url();
+
+                (
+                    Redirect::temporary(auth_url.as_str()).into_response(),
+                    csrf_token,
+                    AuthorizeFlowStateType::OAuth2 { pkce_verifier },
+                )
+            }
+            AuthClient::Oidc(client) => {
+                let (auth_url, csrf_token, nonce, pkce_verifier) =
+                    client.authorize_url(req.login_hint.as_deref(), req.ui_locales.as_deref());
+
+                (
+                    Redirect::temporary(auth_url.as_str()).into_response(),
+                    csrf_token,
+                    AuthorizeFlowStateType::Oidc {
+                        nonce,
+                        pkce_verifier,
+                    },
+                )
+            }
+        },
     };
 
     state
@@ -84,10 +400,12 @@ async fn oauth_authorize_impl(state: AppState, req: AuthorizeRequest) -> impl In
                 ty,
                 connection,
                 state: req.state,
-                pkce_verifier,
                 scopes: requested_scopes,
                 client_id: req.client_id,
                 redirect_uri: req.redirect_uri,
+                prompt: req.prompt,
+                acr_values: req.acr_values,
+                device,
             },
         )
         .unwrap()
@@ -96,21 +414,20 @@ async fn oauth_authorize_impl(state: AppState, req: AuthorizeRequest) -> impl In
         .await
         .map_err(Error::Kv)?;
 
-    Ok(Redirect::temporary(auth_url.as_str()))
+    Ok(response)
 }
 
 pub async fn oauth_authorize(
     State(state): State<AppState>,
     Query(req): Query<AuthorizeRequest>,
+    jar: CookieJar,
+    headers: HeaderMap,
 ) -> Response {
-    if req.connection.is_none() {
-        return Html(include_str!(concat!(env!("OUT_DIR"), "/login.html"))).into_response();
-    }
-
     let (tx, rx) = oneshot::channel();
 
     wasm_bindgen_futures::spawn_local(async move {
-        let res = oauth_authorize_impl(state, req).await;
+        let device = Device::from_headers(&headers);
+        let res = oauth_authorize_impl(state, req, jar, device).await;
         tx.send(res).map_err(|_| ()).unwrap();
     });
 
@@ -1,32 +1,35 @@
 use axum::{
-    extract::{Query, State},
+    extract::{Form, Query, State},
     http::Uri,
-    response::{IntoResponse, Redirect},
+    response::{IntoResponse, Redirect, Response},
 };
-use chrono::Duration;
+use axum_extra::extract::cookie::CookieJar;
 use futures::channel::oneshot;
 use oauth2::{
     basic::{BasicErrorResponseType, BasicTokenType},
-    AuthorizationCode, CsrfToken, ExtraTokenFields, PkceCodeVerifier, StandardTokenResponse,
-    TokenResponse,
+    AuthorizationCode, CsrfToken, PkceCodeVerifier, TokenResponse,
 };
-use openidconnect::core::{CoreIdTokenFields, CoreTokenResponse};
 use serde::Deserialize;
 
 use crate::{
     error::Error,
     gen_string, http_client,
-    providers::fetch_user,
-    tokens::{self, generate_access_refresh_token_set, AccessRefreshTokenSet},
-    users::{upsert_user, User},
+    oidc::apple,
+    providers::{fetch_user, get_provider, ClientAuth, OidcProvider, Provider},
+    sessions::{self, Session},
+    tokens::{
+        self, access_token_key, generate_access_refresh_token_set, refresh_token_key,
+        AccessRefreshTokenSet,
+    },
+    users::{get_identities, get_user, upsert_identity, upsert_user, User},
     AppState,
 };
 
 use super::{
-    get_auth_client,
-    states::{
-        AuthorizeFlowState, AuthorizeFlowStateType, CodeFlowState, ConnectionTokens, TokenMetadata,
-    },
+    connections::{extract_connection_tokens, store_connection_tokens},
+    consent, get_auth_client, mfa,
+    revocation::index_tokens,
+    states::{AuthorizeFlowState, AuthorizeFlowStateType, CodeFlowState, TokenMetadata},
     AuthClient,
 };
 
@@ -36,43 +39,68 @@ pub struct CallbackRequest {
     state: CsrfToken,
 }
 
-fn extract_connection_tokens(
-    res: &StandardTokenResponse<impl ExtraTokenFields, BasicTokenType>,
-) -> ConnectionTokens {
-    ConnectionTokens {
-        access_token: res.access_token().clone(),
-        expires_in: res
-            .expires_in()
-            .unwrap_or_else(|| Duration::weeks(1).to_std().unwrap()), // TODO: properly handle the expires
-        refresh_token: res.refresh_token().cloned(),
-    }
+#[derive(Deserialize)]
+pub struct FormCallbackRequest {
+    code: AuthorizationCode,
+    state: CsrfToken,
+    /// Sent by Apple in the form body of the first sign in.
+    user: Option<String>,
 }
 
 async fn exchange_user(
     state: &AppState,
     req: CallbackRequest,
+    form_user: Option<String>,
     flow: &AuthorizeFlowState,
 ) -> Result<User, Error> {
-    let oauth = get_auth_client(&flow.connection, &state.env).await?;
-
-    let pkce_verifier = PkceCodeVerifier::new(flow.pkce_verifier.secret().clone());
+    let provider = get_provider(&state.db, &flow.connection).await?;
+    let oauth = get_auth_client(state, &provider).await?;
 
-    let (user, tokens) = match (oauth, &flow.ty) {
-        (AuthClient::OAuth2(client), AuthorizeFlowStateType::OAuth2) => {
+    let (user, tokens, tenant_id) = match (oauth, &flow.ty) {
+        (AuthClient::OAuth2(client), AuthorizeFlowStateType::OAuth2 { pkce_verifier }) => {
+            let pkce_verifier = PkceCodeVerifier::new(pkce_verifier.secret().clone());
             let res = client.exchange_code(req.code, pkce_verifier).await?;
 
-            let user = fetch_user(&flow.connection, http_client(), res.access_token()).await?;
+            let user = fetch_user(&provider, http_client(), res.access_token()).await?;
             let tokens = extract_connection_tokens(&res);
 
-            (user, tokens)
+            (user, tokens, None)
         }
-        (AuthClient::Oidc(client), AuthorizeFlowStateType::Oidc { nonce }) => {
-            let res = client.exchange_code(req.code, pkce_verifier, nonce).await?;
+        (
+            AuthClient::Oidc(client),
+            AuthorizeFlowStateType::Oidc {
+                nonce,
+                pkce_verifier,
+            },
+        ) => {
+            let pkce_verifier = PkceCodeVerifier::new(pkce_verifier.secret().clone());
+            let (res, id_token_claims) =
+                client.exchange_code(req.code, pkce_verifier, nonce).await?;
 
-            let user_info = client.user_info(res.access_token().clone()).await?;
+            let user_info = client
+                .user_info(res.access_token().clone(), &id_token_claims)
+                .await?;
             let tokens = extract_connection_tokens(&res);
 
-            (User::from_claims(&flow.connection, user_info), tokens)
+            let mut user = User::from_claims(&user_info);
+            if let Some(claims) = &provider.claims {
+                let profile = serde_json::to_value(&user_info).map_err(Error::SerdeJson)?;
+                claims.apply(&[profile], &mut user);
+            }
+            // Only Apple sends the user, and it can't be verified, so it is ignored for other
+            // providers
+            if let (
+                Some(form_user),
+                Provider::Oidc(OidcProvider {
+                    client_auth: ClientAuth::AppleJwt,
+                    ..
+                }),
+            ) = (&form_user, &provider.provider)
+            {
+                apple::apply_form_user(form_user, &mut user);
+            }
+
+            (user, tokens, client.tenant_id(&id_token_claims))
         }
         _ => {
             return Err(Error::OAuth2(
@@ -82,16 +110,51 @@ async fn exchange_user(
         }
     };
 
+    let user = sign_in(state, &provider.name, user, tenant_id.as_deref()).await?;
+
+    store_connection_tokens(state, &user.id, &provider.name, &tokens).await?;
+
+    Ok(user)
+}
+
+/// Store a user that signed in through a provider. The ID of the user is expected to be the ID at
+/// the provider, and is prefixed with the provider name.
+pub async fn sign_in(
+    state: &AppState,
+    provider: &str,
+    mut user: User,
+    tenant_id: Option<&str>,
+) -> Result<User, Error> {
+    let provider_user_id = user.id.clone();
+    user.id = format!("{provider}|{provider_user_id}");
+
+    if let Some(existing) = get_user(&state.db, &user.id).await.map_err(Error::D1)? {
+        // Apple only sends the name on the first sign in, so it is kept from the stored user
+        if user.name.is_none() && user.given_name.is_none() && user.family_name.is_none() {
+            user.name = existing.name;
+            user.given_name = existing.given_name;
+            user.family_name = existing.family_name;
+        }
+
+        // Second factors are enrolled here, not at the provider
+        user.multifactor = existing.multifactor;
+
+        // Phone numbers verified here are kept when the provider doesn't have one
+        if user.phone_number.is_none() {
+            user.phone_number = existing.phone_number;
+            user.phone_verified = existing.phone_verified;
+        }
+    }
+
     upsert_user(&state.db, &user).await.map_err(Error::D1)?;
 
-    state
-        .kv
-        .put(&format!("connection:{}:tokens", user.id), tokens)
-        .unwrap()
-        .expiration_ttl(Duration::weeks(4).num_seconds() as u64) // TODO: properly handle the expires
-        .execute()
+    upsert_identity(&state.db, &user, provider, &provider_user_id, tenant_id)
         .await
-        .map_err(Error::Kv)?;
+        .map_err(Error::D1)?;
+
+    user.identities = get_identities(&state.db, &user.id)
+        .await
+        .map_err(Error::D1)?;
 
     Ok(user)
 }
@@ -100,18 +163,20 @@ async fn gen_and_store_tokens(
     state: &AppState,
     flow: &AuthorizeFlowState,
     user: &User,
+    session: &Session,
 ) -> Result<AccessRefreshTokenSet, Error> {
     let tokens = generate_access_refresh_token_set();
+    let token_meta = TokenMetadata {
+        user_id: user.id.clone(),
+        client_id: flow.client_id.clone(),
+        scopes: flow.scopes.clone(),
+        session_id: Some(session.id.clone()),
+        token_id: Some(gen_string(32)),
+    };
 
     state
         .kv
-        .put(
-            &format!("token:access:{}", tokens.access_token.secret()),
-            TokenMetadata {
-                client_id: user.id.clone(),
-                scopes: flow.scopes.clone(),
-            },
-        )
+        .put(&access_token_key(tokens.access_token.secret()), &token_meta)
         .unwrap()
         .expiration_ttl(tokens.expires_in.num_seconds() as u64)
         .execute()
@@ -121,11 +186,8 @@ async fn gen_and_store_tokens(
     state
         .kv
         .put(
-            &format!("token:refresh:{}", tokens.refresh_token.secret()),
-            TokenMetadata {
-                client_id: user.id.clone(),
-                scopes: flow.scopes.clone(),
-            },
+            &refresh_token_key(tokens.refresh_token.secret()),
+            &token_meta,
         )
         .unwrap()
         .expiration_ttl(tokens.refresh_expires_in.num_seconds() as u64)
@@ -133,12 +195,15 @@ async fn gen_and_store_tokens(
         .await
         .map_err(Error::Kv)?;
 
+    index_tokens(state, &token_meta, &tokens).await?;
+
     Ok(tokens)
 }
 
 async fn oauth_callback_impl(
     state: AppState,
     req: CallbackRequest,
+    form_user: Option<String>,
 ) -> Result<impl IntoResponse, Error> {
     let flow = state
         .kv
@@ -151,24 +216,89 @@ async fn oauth_callback_impl(
             "could not find flow for the given state".into(),
         ))?;
 
-    let user = exchange_user(&state, req, &flow).await?;
-    let access_refresh_tokens = gen_and_store_tokens(&state, &flow, &user).await?;
+    let user = exchange_user(&state, req, form_user, &flow).await?;
+
+    complete_flow(&state, flow, user).await
+}
+
+/// Finish an authorization flow for a signed in user, by redirecting back to the application with
+/// an authorization code, or to the second factor challenge first when it is required.
+pub async fn complete_flow(
+    state: &AppState,
+    flow: AuthorizeFlowState,
+    user: User,
+) -> Result<Response, Error> {
+    if mfa::is_needed(state, &flow, &user).await {
+        return Ok(mfa::challenge(state, flow, &user).await?.into_response());
+    }
+
+    issue_code(state, flow, user, &[]).await
+}
+
+/// Issue the authorization code of a flow, with the authentication methods that were used besides
+/// the first factor. This starts a session, so that the user can sign in to other applications
+/// without signing in again, or steps up the session the flow was started with.
+pub async fn issue_code(
+    state: &AppState,
+    mut flow: AuthorizeFlowState,
+    user: User,
+    second_factor_amr: &[&str],
+) -> Result<Response, Error> {
+    // The user already has a session, which was stepped up with a second factor
+    if let AuthorizeFlowStateType::Session { session_id } = &flow.ty {
+        let session = sessions::get_session(&state.db, session_id)
+            .await?
+            .ok_or(Error::InvalidLink)?;
+        let session = sessions::step_up_session(&state.db, session, second_factor_amr).await?;
+
+        return Ok(consent::continue_flow(state, flow, user, &session)
+            .await?
+            .into_response());
+    }
+
+    let mut amr: Vec<&str> = flow.ty.amr().to_vec();
+    for method in second_factor_amr {
+        if !amr.contains(method) {
+            amr.push(*method);
+        }
+    }
+
+    let device = std::mem::take(&mut flow.device);
+    let (session, cookie) =
+        sessions::create_session(&state.db, &user.id, &flow.connection, &amr, device).await?;
+
+    let redirect = consent::continue_flow(state, flow, user, &session).await?;
+
+    Ok((CookieJar::new().add(cookie), redirect).into_response())
+}
+
+/// Redirect back to the application with an authorization code for a user with a session.
+pub async fn redirect_with_code(
+    state: &AppState,
+    flow: AuthorizeFlowState,
+    user: User,
+    session: &Session,
+) -> Result<Redirect, Error> {
+    let access_refresh_tokens = gen_and_store_tokens(state, &flow, &user, session).await?;
+    sessions::add_session_application(&state.db, &session.id, &flow.client_id).await?;
 
     let code = AuthorizationCode::new(gen_string(16));
 
     let id_token = tokens::id_token(
-        &state,
+        state,
         &flow.client_id,
         &code,
+        &flow.scopes,
         user,
+        session,
         &access_refresh_tokens.access_token,
     )
     .await?;
 
-    let mut reply = CoreTokenResponse::new(
+    let mut reply = tokens::TokenResponse::new(
         access_refresh_tokens.access_token,
         BasicTokenType::Bearer,
-        CoreIdTokenFields::new(Some(id_token), oauth2::EmptyExtraTokenFields {}),
+        tokens::IdTokenFields::new(Some(id_token), oauth2::EmptyExtraTokenFields {}),
     );
     reply.set_refresh_token(Some(access_refresh_tokens.refresh_token));
     reply.set_expires_in(Some(&access_refresh_tokens.expires_in.to_std().unwrap()));
@@ -204,7 +334,8 @@ async fn oauth_callback_impl(
         .await
         .map_err(Error::Kv)?;
 
-    Ok(Redirect::temporary(&uri.to_string()))
+    // See Other, so that callbacks posted by the provider are not posted to the application
+    Ok(Redirect::to(&uri.to_string()))
 }
 
 pub async fn oauth_callback(
@@ -214,7 +345,26 @@ pub async fn oauth_callback(
     let (tx, rx) = oneshot::channel();
 
     wasm_bindgen_futures::spawn_local(async move {
-        let res = oauth_callback_impl(state, req).await;
+        let res = oauth_callback_impl(state, req, None).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
+
+/// Callback for providers that use `response_mode=form_post`, such as Apple.
+pub async fn oauth_callback_form_post(
+    State(state): State<AppState>,
+    Form(req): Form<FormCallbackRequest>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let callback = CallbackRequest {
+            code: req.code,
+            state: req.state,
+        };
+        let res = oauth_callback_impl(state, callback, req.user).await;
         tx.send(res).map_err(|_| ()).unwrap();
     });
 
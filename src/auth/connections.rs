@@ -0,0 +1,290 @@
+use chrono::{Duration, Utc};
+use oauth2::{basic::BasicTokenType, ExtraTokenFields, StandardTokenResponse, TokenResponse};
+use serde::{Deserialize, Serialize};
+use worker::console_error;
+
+use crate::{
+    crypto::{self, Encrypted},
+    error::Error,
+    providers::get_provider,
+    AppState,
+};
+
+use super::{get_auth_client, states::ConnectionTokens, AuthClient};
+
+/// Tokens are kept this long after they were last stored, so that those of abandoned accounts
+/// don't stay forever.
+const CONNECTION_TOKENS_TTL_WEEKS: i64 = 4;
+
+/// Maximum number of entries re-encrypted per scheduled run, to stay within subrequest limits.
+const REENCRYPT_BATCH_SIZE: usize = 50;
+
+/// Stored as KV metadata so that entries encrypted with an old key can be found without reading
+/// them.
+#[derive(Serialize, Deserialize)]
+struct ConnectionTokensMetadata {
+    version: u32,
+    /// Set on entries that can't be decrypted, so that they are skipped instead of being retried on
+    /// every re-encryption run.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    undecryptable: bool,
+}
+
+pub fn extract_connection_tokens(
+    res: &StandardTokenResponse<impl ExtraTokenFields, BasicTokenType>,
+) -> ConnectionTokens {
+    ConnectionTokens {
+        access_token: res.access_token().clone(),
+        expires_at: res
+            .expires_in()
+            .and_then(|expires_in| Duration::from_std(expires_in).ok())
+            .map(|expires_in| Utc::now() + expires_in),
+        refresh_token: res.refresh_token().cloned(),
+    }
+}
+
+fn connection_tokens_key(user_id: &str, provider: &str) -> String {
+    format!("connection:{user_id}:{provider}:tokens")
+}
+
+/// Tokens used to be stored per user, only for the provider the user signed up with.
+fn legacy_connection_tokens_key(user_id: &str) -> String {
+    format!("connection:{user_id}:tokens")
+}
+
+/// Decode stored tokens, which may have been stored before tokens were encrypted.
+async fn decode_connection_tokens(
+    state: &AppState,
+    key: &str,
+    value: &str,
+) -> Result<ConnectionTokens, Error> {
+    match serde_json::from_str::<Encrypted>(value) {
+        Ok(encrypted) => crypto::decrypt(&state.env, key, &encrypted).await,
+        Err(_) => serde_json::from_str(value).map_err(Error::SerdeJson),
+    }
+}
+
+async fn read_connection_tokens(
+    state: &AppState,
+    key: &str,
+) -> Result<Option<ConnectionTokens>, Error> {
+    let Some(value) = state.kv.get(key).text().await.map_err(Error::Kv)? else {
+        return Ok(None);
+    };
+
+    decode_connection_tokens(state, key, &value).await.map(Some)
+}
+
+/// KV requires expirations to be at least 60 seconds in the future.
+fn min_expiration() -> u64 {
+    (Utc::now() + Duration::seconds(60)).timestamp() as u64
+}
+
+async fn put_encrypted(
+    state: &AppState,
+    key: &str,
+    encrypted: &Encrypted,
+    expiration: Option<u64>,
+) -> Result<(), Error> {
+    let mut put = state
+        .kv
+        .put(key, encrypted)
+        .unwrap()
+        .metadata(ConnectionTokensMetadata {
+            version: encrypted.version,
+            undecryptable: false,
+        })
+        .map_err(Error::Kv)?;
+
+    if let Some(expiration) = expiration {
+        put = put.expiration(expiration);
+    }
+
+    put.execute().await.map_err(Error::Kv)
+}
+
+pub async fn store_connection_tokens(
+    state: &AppState,
+    user_id: &str,
+    provider: &str,
+    tokens: &ConnectionTokens,
+) -> Result<(), Error> {
+    let key = connection_tokens_key(user_id, provider);
+    let encrypted = crypto::encrypt(&state.env, &key, tokens).await?;
+
+    // Without a refresh token the tokens are useless once the access token expires
+    let expiration = match (&tokens.refresh_token, tokens.expires_at) {
+        (None, Some(expires_at)) => (expires_at.timestamp() as u64).max(min_expiration()),
+        _ => (Utc::now() + Duration::weeks(CONNECTION_TOKENS_TTL_WEEKS)).timestamp() as u64,
+    };
+
+    put_encrypted(state, &key, &encrypted, Some(expiration)).await
+}
+
+/// Get the tokens of a connection, refreshing the access token through the provider when it has
+/// expired.
+pub async fn get_connection_tokens(
+    state: &AppState,
+    user_id: &str,
+    provider: &str,
+) -> Result<ConnectionTokens, Error> {
+    let key = connection_tokens_key(user_id, provider);
+
+    let tokens = match read_connection_tokens(state, &key).await? {
+        Some(tokens) => tokens,
+        None => migrate_legacy_tokens(state, user_id, provider)
+            .await?
+            .ok_or(Error::TokensNotFound)?,
+    };
+
+    if !tokens.is_expired() {
+        return Ok(tokens);
+    }
+
+    let Some(refresh_token) = &tokens.refresh_token else {
+        return Err(Error::TokensNotFound);
+    };
+
+    let config = get_provider(&state.db, provider).await?;
+
+    let refreshed = match get_auth_client(state, &config).await? {
+        AuthClient::OAuth2(client) => {
+            extract_connection_tokens(&client.exchange_refresh_token(refresh_token).await?)
+        }
+        AuthClient::Oidc(client) => {
+            extract_connection_tokens(&client.exchange_refresh_token(refresh_token).await?)
+        }
+    };
+
+    // Providers are not required to rotate the refresh token
+    let refreshed = ConnectionTokens {
+        refresh_token: refreshed.refresh_token.or(tokens.refresh_token),
+        ..refreshed
+    };
+
+    store_connection_tokens(state, user_id, provider, &refreshed).await?;
+
+    Ok(refreshed)
+}
+
+/// Move tokens stored under the legacy key of a user to the key of the connection.
+async fn migrate_legacy_tokens(
+    state: &AppState,
+    user_id: &str,
+    provider: &str,
+) -> Result<Option<ConnectionTokens>, Error> {
+    if user_id.split_once('|').map(|(prefix, _)| prefix) != Some(provider) {
+        return Ok(None);
+    }
+
+    let legacy_key = legacy_connection_tokens_key(user_id);
+
+    let Some(tokens) = read_connection_tokens(state, &legacy_key).await? else {
+        return Ok(None);
+    };
+
+    store_connection_tokens(state, user_id, provider, &tokens).await?;
+    state.kv.delete(&legacy_key).await.map_err(Error::Kv)?;
+
+    Ok(Some(tokens))
+}
+
+async fn reencrypt_entry(
+    state: &AppState,
+    key: &str,
+    expiration: Option<u64>,
+) -> Result<(), Error> {
+    let Some(value) = state.kv.get(key).text().await.map_err(Error::Kv)? else {
+        return Ok(());
+    };
+
+    // The entry may expire soon after it was listed
+    let expiration = expiration.map(|expiration| expiration.max(min_expiration()));
+
+    // Entries written before tokens were encrypted are encrypted for the first time
+    let tokens = match decode_connection_tokens(state, key, &value).await {
+        Ok(tokens) => tokens,
+        Err(err) => {
+            mark_undecryptable(state, key, value, expiration).await?;
+            return Err(err);
+        }
+    };
+
+    let encrypted = crypto::encrypt(&state.env, key, &tokens).await?;
+
+    put_encrypted(state, key, &encrypted, expiration).await
+}
+
+/// Keep an entry that can't be decrypted as it is, but leave it out of later re-encryption runs.
+async fn mark_undecryptable(
+    state: &AppState,
+    key: &str,
+    value: String,
+    expiration: Option<u64>,
+) -> Result<(), Error> {
+    let mut put = state
+        .kv
+        .put(key, value)
+        .unwrap()
+        .metadata(ConnectionTokensMetadata {
+            version: 0,
+            undecryptable: true,
+        })
+        .map_err(Error::Kv)?;
+
+    if let Some(expiration) = expiration {
+        put = put.expiration(expiration);
+    }
+
+    put.execute().await.map_err(Error::Kv)
+}
+
+/// Re-encrypt connection tokens that were encrypted with an older key, so that the old key can be
+/// removed after a rotation.
+pub async fn reencrypt_connection_tokens(state: &AppState) -> Result<(), Error> {
+    let version = crypto::current_version(&state.env)?;
+
+    let mut reencrypted = 0;
+    let mut cursor = None;
+
+    loop {
+        let mut list = state.kv.list().prefix("connection:".into());
+        if let Some(cursor) = cursor {
+            list = list.cursor(cursor);
+        }
+
+        let res = list.execute().await.map_err(Error::Kv)?;
+
+        for key in res.keys {
+            let outdated = key
+                .metadata
+                .and_then(|metadata| {
+                    serde_json::from_value::<ConnectionTokensMetadata>(metadata).ok()
+                })
+                .map_or(true, |metadata| {
+                    metadata.version != version && !metadata.undecryptable
+                });
+
+            if !outdated {
+                continue;
+            }
+
+            if reencrypted == REENCRYPT_BATCH_SIZE {
+                return Ok(());
+            }
+
+            reencrypted += 1;
+
+            // A single entry that can't be re-encrypted shouldn't hold back the others
+            if let Err(err) = reencrypt_entry(state, &key.name, key.expiration).await {
+                console_error!("failed to re-encrypt {}: {err:?}", key.name);
+            }
+        }
+
+        if res.list_complete {
+            return Ok(());
+        }
+
+        cursor = res.cursor;
+    }
+}
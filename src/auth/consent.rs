@@ -0,0 +1,224 @@
+use axum::{
+    extract::{Form, Query, State},
+    response::{Html, IntoResponse, Redirect, Response},
+};
+use chrono::Duration;
+use futures::channel::oneshot;
+use openidconnect::core::CoreAuthErrorResponseType;
+use reqwest::Url;
+use serde::Deserialize;
+
+use crate::{
+    applications,
+    error::Error,
+    gen_string, grants,
+    login::escape_html,
+    sessions::{self, Session},
+    tokens::IDENTITIES_SCOPE,
+    users::{get_user, User},
+    AppState,
+};
+
+use super::{
+    authorize::error_redirect,
+    callback::redirect_with_code,
+    states::{AuthorizeFlowState, ConsentState},
+};
+
+/// How long the user can take to decide.
+const CONSENT_TTL_MINUTES: i64 = 10;
+
+#[derive(Deserialize)]
+pub struct ConsentRequest {
+    state: String,
+}
+
+#[derive(Deserialize)]
+pub struct ConsentForm {
+    /// `allow` or `deny`.
+    decision: String,
+}
+
+fn consent_key(id: &str) -> String {
+    format!("consent:{id}")
+}
+
+fn describe_scope(scope: &str) -> &str {
+    match scope {
+        "openid" => "Sign you in",
+        "profile" => "See your name, username and picture",
+        "email" => "See your email address",
+        IDENTITIES_SCOPE => "See the accounts you signed in with",
+        "read:users" => "See all users",
+        "write:users" => "Sign users out",
+        "read:user_idp_tokens" => "Use the accounts users signed in with",
+        "read:providers" => "See the sign in providers",
+        "write:providers" => "Manage the sign in providers",
+        "read:connections" => "See the enterprise connections",
+        "write:connections" => "Manage the enterprise connections",
+        "read:account" => "See your account settings, such as your passkeys",
+        "write:account" => "Manage your account settings, such as your passkeys",
+        _ => scope,
+    }
+}
+
+/// Whether the user has to approve the scopes of the flow before the application receives an
+/// authorization code. Users only approve the scopes of third-party applications once, unless the
+/// application asks again with `prompt=consent`.
+pub async fn is_needed(
+    state: &AppState,
+    flow: &AuthorizeFlowState,
+    user_id: &str,
+) -> Result<bool, Error> {
+    if flow.has_prompt("consent") {
+        return Ok(true);
+    }
+
+    if applications::is_first_party(&state.db, &flow.client_id).await {
+        return Ok(false);
+    }
+
+    Ok(!grants::is_granted(&state.db, user_id, &flow.client_id, &flow.scopes).await?)
+}
+
+/// Redirect back to the application with an authorization code, or to the consent page first
+/// when the user has to approve its scopes.
+pub async fn continue_flow(
+    state: &AppState,
+    flow: AuthorizeFlowState,
+    user: User,
+    session: &Session,
+) -> Result<Redirect, Error> {
+    if !is_needed(state, &flow, &user.id).await? {
+        return redirect_with_code(state, flow, user, session).await;
+    }
+
+    let id = gen_string(32);
+
+    state
+        .kv
+        .put(
+            &consent_key(&id),
+            ConsentState {
+                flow,
+                user_id: user.id,
+                session_id: session.id.clone(),
+            },
+        )
+        .unwrap()
+        .expiration_ttl(Duration::minutes(CONSENT_TTL_MINUTES).num_seconds() as u64)
+        .execute()
+        .await
+        .map_err(Error::Kv)?;
+
+    let mut url = Url::parse(&format!("{}/oauth/consent", env!("DOMAIN"))).unwrap();
+    url.query_pairs_mut().append_pair("state", &id);
+
+    Ok(Redirect::to(url.as_str()))
+}
+
+async fn get_state(state: &AppState, id: &str) -> Result<ConsentState, Error> {
+    state
+        .kv
+        .get(&consent_key(id))
+        .json::<ConsentState>()
+        .await
+        .map_err(Error::Kv)?
+        .ok_or(Error::InvalidLink)
+}
+
+async fn consent_page_impl(state: AppState, req: ConsentRequest) -> Result<Response, Error> {
+    let consent = get_state(&state, &req.state).await?;
+
+    let name = applications::get_name(&state.db, &consent.flow.client_id)
+        .await
+        .ok_or(Error::InvalidLink)?;
+
+    let mut scopes = consent
+        .flow
+        .scopes
+        .iter()
+        .map(|scope| scope.as_str())
+        .collect::<Vec<_>>();
+    scopes.sort_unstable();
+
+    let scopes = scopes
+        .into_iter()
+        .map(|scope| format!("<li>{}</li>", escape_html(describe_scope(scope))))
+        .collect::<String>();
+
+    let page = include_str!("../../public/consent.html")
+        .replace("<!-- APPLICATION -->", &escape_html(&name))
+        .replace("<!-- SCOPES -->", &scopes);
+
+    Ok(Html(page).into_response())
+}
+
+async fn consent_impl(
+    state: AppState,
+    req: ConsentRequest,
+    form: ConsentForm,
+) -> Result<Response, Error> {
+    let consent = get_state(&state, &req.state).await?;
+
+    // A decision can only be made once
+    state
+        .kv
+        .delete(&consent_key(&req.state))
+        .await
+        .map_err(Error::Kv)?;
+
+    let flow = consent.flow;
+
+    if form.decision != "allow" {
+        return Ok(error_redirect(
+            &flow.redirect_uri,
+            &flow.state,
+            CoreAuthErrorResponseType::AccessDenied,
+        )?
+        .into_response());
+    }
+
+    let session = sessions::get_session(&state.db, &consent.session_id)
+        .await?
+        .ok_or(Error::InvalidLink)?;
+    let user = get_user(&state.db, &consent.user_id)
+        .await
+        .map_err(Error::D1)?
+        .ok_or(Error::UserNotFound)?;
+
+    grants::save_grant(&state.db, &user.id, &flow.client_id, &flow.scopes).await?;
+
+    Ok(redirect_with_code(&state, flow, user, &session)
+        .await?
+        .into_response())
+}
+
+pub async fn consent_page(
+    State(state): State<AppState>,
+    Query(req): Query<ConsentRequest>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let res = consent_page_impl(state, req).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
+
+pub async fn consent(
+    State(state): State<AppState>,
+    Query(req): Query<ConsentRequest>,
+    Form(form): Form<ConsentForm>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let res = consent_impl(state, req, form).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
@@ -0,0 +1,77 @@
+use axum::{
+    extract::{Query, State},
+    response::{IntoResponse, Redirect},
+    Json,
+};
+use futures::channel::oneshot;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+
+use crate::{enterprise::find_connection_by_email, error::Error, AppState};
+
+#[derive(Deserialize)]
+pub struct DiscoverRequest {
+    email: String,
+}
+
+#[derive(Serialize)]
+struct DiscoverResponse {
+    connection: String,
+    organization: String,
+}
+
+/// Home realm discovery, which finds the enterprise connection for the domain of an email address.
+async fn oauth_discover_impl(
+    state: AppState,
+    req: DiscoverRequest,
+) -> Result<impl IntoResponse, Error> {
+    let connection = find_connection_by_email(&state.db, &req.email)
+        .await?
+        .ok_or(Error::ConnectionNotFound)?;
+
+    Ok(Json(DiscoverResponse {
+        connection: connection.name,
+        organization: connection.organization,
+    }))
+}
+
+/// Continue an authorization request with the enterprise connection that owns the domain of an
+/// email address, so that the identity provider of the organization can't be bypassed by signing in
+/// or up with a password or email code.
+pub async fn enterprise_redirect(
+    state: &AppState,
+    authorize_query: &str,
+    email: &str,
+) -> Result<Option<Redirect>, Error> {
+    let Some(connection) = find_connection_by_email(&state.db, email).await? else {
+        return Ok(None);
+    };
+
+    let mut url = Url::parse(&format!("{}/oauth/authorize", env!("DOMAIN"))).unwrap();
+    let params =
+        serde_urlencoded::from_str::<Vec<(String, String)>>(authorize_query).unwrap_or_default();
+
+    url.query_pairs_mut()
+        .extend_pairs(
+            params
+                .iter()
+                .filter(|(key, _)| key != "connection" && key != "message"),
+        )
+        .append_pair("connection", &connection.name);
+
+    Ok(Some(Redirect::to(url.as_str())))
+}
+
+pub async fn oauth_discover(
+    State(state): State<AppState>,
+    Query(req): Query<DiscoverRequest>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let res = oauth_discover_impl(state, req).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
@@ -0,0 +1,145 @@
+use axum::{
+    extract::{Form, Query, State},
+    response::{Html, IntoResponse, Redirect, Response},
+};
+use axum_extra::extract::cookie::CookieJar;
+use futures::channel::oneshot;
+use oauth2::{basic::BasicErrorResponseType, ClientId};
+use reqwest::Url;
+use serde::Deserialize;
+
+use crate::{
+    applications, backchannel,
+    error::Error,
+    sessions::{self, Session},
+    tokens::verify_id_token_hint,
+    AppState,
+};
+
+use super::revocation::{revoke_session_tokens, revoke_user_tokens};
+
+/// RP-initiated logout request, see
+/// <https://openid.net/specs/openid-connect-rpinitiated-1_0.html#RPLogout>.
+#[derive(Deserialize)]
+pub struct LogoutRequest {
+    id_token_hint: Option<String>,
+    client_id: Option<ClientId>,
+    post_logout_redirect_uri: Option<String>,
+    state: Option<String>,
+}
+
+/// Sign out of a session, revoking the tokens issued through it and notifying the applications it
+/// signed in to.
+pub async fn end_session(state: &AppState, session: &Session) -> Result<(), Error> {
+    revoke_session_tokens(state, &session.user_id, &session.id).await?;
+    backchannel::notify_session_end(state, &session.user_id, &session.id).await?;
+    sessions::delete_session(&state.db, &session.id).await
+}
+
+/// Sign a user out everywhere.
+pub async fn end_user_sessions(state: &AppState, user_id: &str) -> Result<(), Error> {
+    revoke_user_tokens(state, user_id).await?;
+    backchannel::notify_user_sessions_end(state, user_id).await?;
+    sessions::delete_user_sessions(&state.db, user_id).await
+}
+
+fn invalid_request(description: &str) -> Error {
+    Error::OAuth2(BasicErrorResponseType::InvalidRequest, description.into())
+}
+
+/// The application that signs the user out, from the audience of the ID token it sends as hint or
+/// the client ID it passes along.
+async fn get_client_id(state: &AppState, req: &LogoutRequest) -> Result<Option<ClientId>, Error> {
+    let Some(hint) = &req.id_token_hint else {
+        return Ok(req.client_id.clone());
+    };
+
+    let claims = verify_id_token_hint(state, hint).await?;
+    let aud = claims
+        .audiences()
+        .first()
+        .map(|aud| ClientId::new(aud.to_string()))
+        .ok_or_else(|| invalid_request("invalid id_token_hint"))?;
+
+    match &req.client_id {
+        Some(client_id) if *client_id != aud => {
+            Err(invalid_request("client_id does not match id_token_hint"))
+        }
+        _ => Ok(Some(aud)),
+    }
+}
+
+async fn logout_impl(
+    state: AppState,
+    req: LogoutRequest,
+    jar: CookieJar,
+) -> Result<Response, Error> {
+    let client_id = get_client_id(&state, &req).await?;
+
+    // Check where to redirect to before signing out, so that a bad request doesn't leave the user
+    // signed out on an error page
+    let redirect = match &req.post_logout_redirect_uri {
+        Some(uri) => {
+            let client_id = client_id.as_ref().ok_or_else(|| {
+                invalid_request("post_logout_redirect_uri requires id_token_hint or client_id")
+            })?;
+
+            if !applications::is_post_logout_redirect_uri(&state.db, client_id, uri).await {
+                return Err(invalid_request(
+                    "post_logout_redirect_uri is not registered",
+                ));
+            }
+
+            let mut url = Url::parse(uri)
+                .map_err(|_| invalid_request("post_logout_redirect_uri is malformed"))?;
+            if let Some(logout_state) = &req.state {
+                url.query_pairs_mut().append_pair("state", logout_state);
+            }
+
+            Some(Redirect::to(url.as_str()))
+        }
+        None => None,
+    };
+
+    if let Some(session) = sessions::find_session(&state.db, &jar).await? {
+        end_session(&state, &session).await?;
+    }
+
+    let jar = jar.remove(sessions::removal_cookie());
+
+    Ok(match redirect {
+        Some(redirect) => (jar, redirect).into_response(),
+        None => (jar, Html(include_str!("../../public/logout.html"))).into_response(),
+    })
+}
+
+pub async fn logout(
+    State(state): State<AppState>,
+    Query(req): Query<LogoutRequest>,
+    jar: CookieJar,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let res = logout_impl(state, req, jar).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
+
+/// Applications may also send the logout request as form.
+pub async fn logout_form_post(
+    State(state): State<AppState>,
+    jar: CookieJar,
+    Form(req): Form<LogoutRequest>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let res = logout_impl(state, req, jar).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
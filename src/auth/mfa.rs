@@ -0,0 +1,336 @@
+use axum::{
+    extract::{Form, Query, State},
+    response::{Html, IntoResponse, Redirect, Response},
+    Json,
+};
+use chrono::Duration;
+use futures::channel::oneshot;
+use reqwest::Url;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{
+    error::Error,
+    gen_string, mfa,
+    users::{get_user, User},
+    webauthn::{self, AuthenticationCredential},
+    AppState,
+};
+
+use super::{
+    callback::issue_code,
+    states::{AuthorizeFlowState, AuthorizeFlowStateType, MfaState},
+};
+
+/// How long the second factor can be entered after the first factor.
+const CHALLENGE_TTL_MINUTES: i64 = 10;
+
+#[derive(Deserialize)]
+pub struct MfaRequest {
+    state: String,
+}
+
+#[derive(Deserialize)]
+pub struct CodeForm {
+    code: String,
+}
+
+/// The credential as JSON, encoded like `PublicKeyCredential.toJSON()`.
+#[derive(Deserialize)]
+pub struct CredentialForm {
+    credential: String,
+}
+
+fn challenge_page(id: &str, message: Option<&str>) -> Redirect {
+    let mut url = Url::parse(&format!("{}/oauth/mfa", env!("DOMAIN"))).unwrap();
+
+    url.query_pairs_mut().append_pair("state", id);
+    if let Some(message) = message {
+        url.query_pairs_mut().append_pair("message", message);
+    }
+
+    Redirect::to(url.as_str())
+}
+
+async fn put_state(state: &AppState, id: &str, pending: &MfaState) -> Result<(), Error> {
+    state
+        .kv
+        .put(&format!("mfa:{id}"), pending)
+        .unwrap()
+        .expiration_ttl(Duration::minutes(CHALLENGE_TTL_MINUTES).num_seconds() as u64)
+        .execute()
+        .await
+        .map_err(Error::Kv)
+}
+
+async fn get_state(state: &AppState, id: &str) -> Result<MfaState, Error> {
+    state
+        .kv
+        .get(&format!("mfa:{id}"))
+        .json::<MfaState>()
+        .await
+        .map_err(Error::Kv)?
+        .ok_or(Error::InvalidLink)
+}
+
+/// Whether a second factor is needed, which is the case for users that enrolled one, or for every
+/// user when the application requires it or only accepts the multi-factor `acr`.
+pub async fn is_needed(state: &AppState, flow: &AuthorizeFlowState, user: &User) -> bool {
+    // Passkeys verify the user on the device, so they are multi-factor by themselves
+    if let AuthorizeFlowStateType::Passkey { .. }
+    | AuthorizeFlowStateType::PasskeyRegistration { .. } = flow.ty
+    {
+        return false;
+    }
+
+    user.multifactor.is_some()
+        || flow.requires_mfa_acr()
+        || mfa::is_required(state, &flow.client_id).await
+}
+
+/// Hold on to the flow until the second factor has been verified.
+pub async fn challenge(
+    state: &AppState,
+    flow: AuthorizeFlowState,
+    user: &User,
+) -> Result<Redirect, Error> {
+    let id = gen_string(32);
+
+    put_state(
+        state,
+        &id,
+        &MfaState {
+            flow,
+            user_id: user.id.clone(),
+            enrollment: None,
+            passkey_challenge: None,
+            recovery_codes: None,
+        },
+    )
+    .await?;
+
+    Ok(challenge_page(&id, None))
+}
+
+/// Issue the authorization code once the second factor has been verified.
+async fn finish(
+    state: &AppState,
+    id: &str,
+    pending: MfaState,
+    amr: &[&str],
+) -> Result<Response, Error> {
+    state
+        .kv
+        .delete(&format!("mfa:{id}"))
+        .await
+        .map_err(Error::Kv)?;
+
+    let user = get_user(&state.db, &pending.user_id)
+        .await
+        .map_err(Error::D1)?
+        .ok_or(Error::UserNotFound)?;
+
+    issue_code(state, pending.flow, user, amr).await
+}
+
+async fn mfa_page_impl(state: AppState, req: MfaRequest) -> Result<Response, Error> {
+    get_state(&state, &req.state).await?;
+
+    Ok(Html(include_str!("../../public/mfa.html")).into_response())
+}
+
+/// The factors the user can use, or the secret to enroll TOTP when the user has none.
+async fn mfa_options_impl(state: AppState, req: MfaRequest) -> Result<Response, Error> {
+    let mut pending = get_state(&state, &req.state).await?;
+
+    if let Some(recovery_codes) = &pending.recovery_codes {
+        return Ok(Json(json!({ "recovery_codes": recovery_codes })).into_response());
+    }
+
+    let user = get_user(&state.db, &pending.user_id)
+        .await
+        .map_err(Error::D1)?
+        .ok_or(Error::UserNotFound)?;
+
+    let totp = mfa::has_totp(&state.db, &user.id).await?;
+
+    let passkeys = webauthn::list_passkeys(&state.db, &user.id).await?;
+    let passkey = (!passkeys.is_empty()).then(|| {
+        let challenge = webauthn::challenge();
+        let options = webauthn::request_options(&challenge, &passkeys, false);
+        pending.passkey_challenge = Some(challenge);
+        options
+    });
+
+    let enrollment = (!totp && passkeys.is_empty()).then(|| {
+        let secret = pending
+            .enrollment
+            .get_or_insert_with(mfa::generate_secret)
+            .clone();
+        let account = user.email.as_deref().unwrap_or(&user.id);
+
+        json!({ "secret": secret, "uri": mfa::otpauth_uri(&secret, account) })
+    });
+
+    put_state(&state, &req.state, &pending).await?;
+
+    Ok(Json(json!({
+        "totp": totp,
+        "passkey": passkey,
+        "enrollment": enrollment,
+    }))
+    .into_response())
+}
+
+async fn mfa_verify_impl(
+    state: AppState,
+    req: MfaRequest,
+    form: CodeForm,
+) -> Result<Response, Error> {
+    let mut pending = get_state(&state, &req.state).await?;
+
+    if let Some(secret) = &pending.enrollment {
+        if let Some(step) = mfa::verify_enrollment(secret, &form.code) {
+            let recovery_codes = mfa::enroll_totp(&state, &pending.user_id, secret, step).await?;
+
+            // The recovery codes are shown before the sign in continues
+            pending.enrollment = None;
+            pending.recovery_codes = Some(recovery_codes);
+            put_state(&state, &req.state, &pending).await?;
+
+            return Ok(challenge_page(&req.state, None).into_response());
+        }
+    } else {
+        if !mfa::count_attempt(&state.db, &pending.user_id).await? {
+            return Ok(
+                challenge_page(&req.state, Some(&Error::MfaLocked.to_string())).into_response(),
+            );
+        }
+
+        if mfa::verify_totp(&state, &pending.user_id, &form.code).await?
+            || mfa::use_recovery_code(&state.db, &pending.user_id, &form.code).await?
+        {
+            mfa::reset_failed_attempts(&state.db, &pending.user_id).await?;
+            return finish(&state, &req.state, pending, &["mfa", "otp"]).await;
+        }
+    }
+
+    Ok(challenge_page(&req.state, Some(&Error::InvalidMfaCode.to_string())).into_response())
+}
+
+async fn mfa_passkey_impl(
+    state: AppState,
+    req: MfaRequest,
+    form: CredentialForm,
+) -> Result<Response, Error> {
+    let pending = get_state(&state, &req.state).await?;
+
+    let challenge = pending
+        .passkey_challenge
+        .as_deref()
+        .ok_or_else(|| Error::InvalidPasskey("missing challenge".into()))?;
+
+    let credential = serde_json::from_str::<AuthenticationCredential>(&form.credential)
+        .map_err(|_| Error::InvalidPasskey("invalid credential".into()))?;
+
+    // Only the passkeys of the user that signed in can be used
+    let passkey = webauthn::get_passkey(&state.db, &credential.id)
+        .await?
+        .filter(|passkey| passkey.user_id == pending.user_id)
+        .ok_or_else(|| Error::InvalidPasskey("unknown passkey".into()))?;
+
+    let sign_count =
+        match webauthn::verify_authentication(&credential, challenge, &passkey, false).await {
+            Ok(sign_count) => sign_count,
+            Err(e @ Error::InvalidPasskey(_)) => {
+                return Ok(challenge_page(&req.state, Some(&e.to_string())).into_response())
+            }
+            Err(e) => return Err(e),
+        };
+    webauthn::update_sign_count(&state.db, &passkey.id, sign_count).await?;
+
+    finish(&state, &req.state, pending, &["mfa", "hwk"]).await
+}
+
+/// Continue after the recovery codes of a new enrollment have been shown.
+async fn mfa_continue_impl(state: AppState, req: MfaRequest) -> Result<Response, Error> {
+    let pending = get_state(&state, &req.state).await?;
+
+    if pending.recovery_codes.is_none() {
+        return Err(Error::InvalidMfaCode);
+    }
+
+    finish(&state, &req.state, pending, &["mfa", "otp"]).await
+}
+
+pub async fn mfa_page(
+    State(state): State<AppState>,
+    Query(req): Query<MfaRequest>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let res = mfa_page_impl(state, req).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
+
+pub async fn mfa_options(
+    State(state): State<AppState>,
+    Query(req): Query<MfaRequest>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let res = mfa_options_impl(state, req).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
+
+pub async fn mfa_verify(
+    State(state): State<AppState>,
+    Query(req): Query<MfaRequest>,
+    Form(form): Form<CodeForm>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let res = mfa_verify_impl(state, req, form).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
+
+pub async fn mfa_passkey(
+    State(state): State<AppState>,
+    Query(req): Query<MfaRequest>,
+    Form(form): Form<CredentialForm>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let res = mfa_passkey_impl(state, req, form).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
+
+pub async fn mfa_continue(
+    State(state): State<AppState>,
+    Query(req): Query<MfaRequest>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let res = mfa_continue_impl(state, req).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
@@ -0,0 +1,339 @@
+use axum::{
+    extract::{Form, Query, RawQuery, State},
+    http::HeaderMap,
+    response::{IntoResponse, Response},
+    Json,
+};
+use futures::channel::oneshot;
+use oauth2::{basic::BasicErrorResponseType, CsrfToken};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{
+    error::Error,
+    gen_string,
+    password::normalize_email,
+    sessions::Device,
+    users::{get_user, User},
+    webauthn::{self, AuthenticationCredential, RegistrationCredential},
+    AppState,
+};
+
+use super::{
+    authorize::{back_to_login, parse_authorize_request, validate_request},
+    callback::{complete_flow, sign_in},
+    states::{AuthorizeFlowState, AuthorizeFlowStateType},
+};
+
+/// Name of the connection, and the provider of the identities of users that signed up with a
+/// passkey.
+const CONNECTION: &str = "passkey";
+
+#[derive(Deserialize)]
+pub struct RegisterOptionsForm {
+    email: Option<String>,
+    name: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct CeremonyRequest {
+    state: String,
+}
+
+/// The credential as JSON, encoded like `PublicKeyCredential.toJSON()`.
+#[derive(Deserialize)]
+pub struct CredentialForm {
+    credential: String,
+}
+
+fn invalid_credential() -> Error {
+    Error::InvalidPasskey("invalid credential".into())
+}
+
+/// Store the flow of a ceremony, which can be completed until its challenge expires.
+async fn start_ceremony(
+    state: &AppState,
+    query: &str,
+    ty: AuthorizeFlowStateType,
+    device: Device,
+) -> Result<String, Error> {
+    let req = parse_authorize_request(query)?;
+    let scopes = validate_request(state, &req).await?;
+
+    let csrf_token = CsrfToken::new_random();
+
+    state
+        .kv
+        .put(
+            &format!("state:{}", csrf_token.secret()),
+            AuthorizeFlowState {
+                ty,
+                connection: CONNECTION.to_string(),
+                state: req.state,
+                scopes,
+                client_id: req.client_id,
+                redirect_uri: req.redirect_uri,
+                prompt: req.prompt,
+                acr_values: req.acr_values,
+                device,
+            },
+        )
+        .unwrap()
+        .expiration_ttl(webauthn::TIMEOUT_SECONDS)
+        .execute()
+        .await
+        .map_err(Error::Kv)?;
+
+    Ok(csrf_token.secret().clone())
+}
+
+/// Load the flow of a ceremony. The flow is deleted right away, since a challenge may only be used
+/// once.
+async fn take_flow(state: &AppState, id: &str) -> Result<AuthorizeFlowState, Error> {
+    let key = format!("state:{id}");
+
+    let flow = state
+        .kv
+        .get(&key)
+        .json::<AuthorizeFlowState>()
+        .await
+        .map_err(Error::Kv)?
+        .ok_or(Error::OAuth2(
+            BasicErrorResponseType::InvalidGrant,
+            "could not find flow for the given state".into(),
+        ))?;
+
+    state.kv.delete(&key).await.map_err(Error::Kv)?;
+
+    Ok(flow)
+}
+
+async fn passkey_options_impl(
+    state: AppState,
+    query: String,
+    device: Device,
+) -> Result<Response, Error> {
+    let challenge = webauthn::challenge();
+
+    // No credentials are allowed explicitly, so that the user can pick any discoverable credential
+    let options = webauthn::request_options(&challenge, &[], true);
+
+    let id = start_ceremony(
+        &state,
+        &query,
+        AuthorizeFlowStateType::Passkey {
+            challenge,
+            authorize_query: query.clone(),
+        },
+        device,
+    )
+    .await?;
+
+    Ok(Json(json!({ "state": id, "options": options })).into_response())
+}
+
+async fn check_assertion(
+    state: &AppState,
+    form: &CredentialForm,
+    challenge: &str,
+) -> Result<User, Error> {
+    let credential = serde_json::from_str::<AuthenticationCredential>(&form.credential)
+        .map_err(|_| invalid_credential())?;
+
+    let passkey = webauthn::get_passkey(&state.db, &credential.id)
+        .await?
+        .ok_or_else(|| Error::InvalidPasskey("unknown passkey".into()))?;
+
+    let sign_count =
+        webauthn::verify_authentication(&credential, challenge, &passkey, true).await?;
+    webauthn::update_sign_count(&state.db, &passkey.id, sign_count).await?;
+
+    let user = get_user(&state.db, &passkey.user_id)
+        .await
+        .map_err(Error::D1)?
+        .ok_or(Error::UserNotFound)?;
+
+    if user.blocked == Some(true) {
+        return Err(Error::UserBlocked);
+    }
+
+    Ok(user)
+}
+
+async fn passkey_login_impl(
+    state: AppState,
+    req: CeremonyRequest,
+    form: CredentialForm,
+) -> Result<Response, Error> {
+    let flow = take_flow(&state, &req.state).await?;
+
+    let AuthorizeFlowStateType::Passkey {
+        challenge,
+        authorize_query,
+    } = &flow.ty
+    else {
+        return Err(Error::InvalidConnection);
+    };
+
+    let user = match check_assertion(&state, &form, challenge).await {
+        Ok(user) => user,
+        Err(e @ (Error::InvalidPasskey(_) | Error::UserBlocked)) => {
+            return Ok(back_to_login(authorize_query, &e.to_string()).into_response())
+        }
+        Err(e) => return Err(e),
+    };
+
+    Ok(complete_flow(&state, flow, user).await?.into_response())
+}
+
+async fn passkey_register_options_impl(
+    state: AppState,
+    query: String,
+    form: RegisterOptionsForm,
+    device: Device,
+) -> Result<Response, Error> {
+    let email = form
+        .email
+        .map(|email| normalize_email(&email))
+        .filter(|email| !email.is_empty());
+    let name = form
+        .name
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty());
+
+    let challenge = webauthn::challenge();
+    let user_id = gen_string(32);
+
+    // Authenticators show the name to pick a credential, so it should identify the account
+    let account_name = email.clone().or_else(|| name.clone()).unwrap_or_default();
+    let options = webauthn::creation_options(
+        &challenge,
+        &format!("{CONNECTION}|{user_id}"),
+        &account_name,
+        name.as_deref().unwrap_or(&account_name),
+        &[],
+    );
+
+    let id = start_ceremony(
+        &state,
+        &query,
+        AuthorizeFlowStateType::PasskeyRegistration {
+            challenge,
+            authorize_query: query.clone(),
+            user_id,
+            email,
+            name,
+        },
+        device,
+    )
+    .await?;
+
+    Ok(Json(json!({ "state": id, "options": options })).into_response())
+}
+
+async fn passkey_register_impl(
+    state: AppState,
+    req: CeremonyRequest,
+    form: CredentialForm,
+) -> Result<Response, Error> {
+    let flow = take_flow(&state, &req.state).await?;
+
+    let AuthorizeFlowStateType::PasskeyRegistration {
+        challenge,
+        authorize_query,
+        user_id,
+        email,
+        name,
+    } = &flow.ty
+    else {
+        return Err(Error::InvalidConnection);
+    };
+
+    let passkey = match serde_json::from_str::<RegistrationCredential>(&form.credential)
+        .map_err(|_| invalid_credential())
+        .and_then(|credential| webauthn::verify_registration(credential, challenge))
+    {
+        Ok(passkey) => passkey,
+        Err(e @ Error::InvalidPasskey(_)) => {
+            return Ok(back_to_login(authorize_query, &e.to_string()).into_response())
+        }
+        Err(e) => return Err(e),
+    };
+
+    // The email address is not verified by the passkey
+    let user = User {
+        email: email.clone(),
+        email_verified: email.as_ref().map(|_| false),
+        name: name.clone(),
+        ..User::default_with_id(user_id.clone())
+    };
+    let user = sign_in(&state, CONNECTION, user, None).await?;
+
+    webauthn::insert_passkey(&state.db, &user.id, None, &passkey).await?;
+
+    Ok(complete_flow(&state, flow, user).await?.into_response())
+}
+
+pub async fn passkey_options(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    RawQuery(query): RawQuery,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let device = Device::from_headers(&headers);
+        let res = passkey_options_impl(state, query.unwrap_or_default(), device).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
+
+pub async fn passkey_login(
+    State(state): State<AppState>,
+    Query(req): Query<CeremonyRequest>,
+    Form(form): Form<CredentialForm>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let res = passkey_login_impl(state, req, form).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
+
+pub async fn passkey_register_options(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    RawQuery(query): RawQuery,
+    Form(form): Form<RegisterOptionsForm>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let device = Device::from_headers(&headers);
+        let res =
+            passkey_register_options_impl(state, query.unwrap_or_default(), form, device).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
+
+pub async fn passkey_register(
+    State(state): State<AppState>,
+    Query(req): Query<CeremonyRequest>,
+    Form(form): Form<CredentialForm>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let res = passkey_register_impl(state, req, form).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
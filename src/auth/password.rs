@@ -0,0 +1,526 @@
+use axum::{
+    extract::{Form, Query, RawQuery, State},
+    http::HeaderMap,
+    response::{Html, IntoResponse, Redirect, Response},
+};
+use chrono::Duration;
+use futures::channel::oneshot;
+use reqwest::Url;
+use serde::Deserialize;
+
+use crate::{
+    error::Error,
+    gen_string,
+    mailer::{Email, Mailer},
+    password::{self, get_credentials, hash_password, normalize_email, CONNECTION},
+    sessions::Device,
+    users::{get_user, User},
+    AppState,
+};
+
+use super::{
+    authorize::{back_to_login, parse_authorize_request, validate_request},
+    callback::{complete_flow, sign_in},
+    discover::enterprise_redirect,
+    logout::end_user_sessions,
+    states::{
+        AuthorizeFlowState, AuthorizeFlowStateType, EmailVerificationState, PasswordResetState,
+    },
+};
+
+#[derive(Deserialize)]
+pub struct PasswordForm {
+    email: String,
+    password: String,
+}
+
+#[derive(Deserialize)]
+pub struct SignUpForm {
+    email: String,
+    password: String,
+    name: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct VerifyRequest {
+    token: String,
+}
+
+#[derive(Deserialize)]
+pub struct ForgotPasswordForm {
+    email: String,
+}
+
+#[derive(Deserialize)]
+pub struct ResetRequest {
+    token: String,
+}
+
+#[derive(Deserialize)]
+pub struct ResetForm {
+    password: String,
+}
+
+async fn check_credentials(state: &AppState, form: &PasswordForm) -> Result<User, Error> {
+    // Unknown and locked accounts fail like a wrong password, after as long as verifying it takes,
+    // to not reveal which email addresses have an account
+    let Some(credentials) = get_credentials(&state.db, &form.email)
+        .await?
+        .filter(|credentials| !credentials.is_locked())
+    else {
+        password::verify_dummy(&form.password);
+        return Err(Error::InvalidCredentials);
+    };
+
+    if !credentials.verify(&form.password) {
+        password::record_failed_attempt(&state.db, &credentials.user_id).await?;
+        return Err(Error::InvalidCredentials);
+    }
+
+    password::reset_failed_attempts(&state.db, &credentials.user_id).await?;
+
+    let user = get_user(&state.db, &credentials.user_id)
+        .await
+        .map_err(Error::D1)?
+        .ok_or(Error::UserNotFound)?;
+
+    if user.blocked == Some(true) {
+        return Err(Error::UserBlocked);
+    }
+
+    if user.email_verified != Some(true) {
+        return Err(Error::EmailNotVerified);
+    }
+
+    Ok(user)
+}
+
+async fn password_login_impl(
+    state: AppState,
+    query: String,
+    form: PasswordForm,
+    device: Device,
+) -> Result<Response, Error> {
+    let req = parse_authorize_request(&query)?;
+    let scopes = validate_request(&state, &req).await?;
+
+    if let Some(redirect) = enterprise_redirect(&state, &query, &form.email).await? {
+        return Ok(redirect.into_response());
+    }
+
+    let user = match check_credentials(&state, &form).await {
+        Ok(user) => user,
+        Err(e @ (Error::InvalidCredentials | Error::UserBlocked | Error::EmailNotVerified)) => {
+            return Ok(back_to_login(&query, &e.to_string()).into_response())
+        }
+        Err(e) => return Err(e),
+    };
+
+    let flow = AuthorizeFlowState {
+        ty: AuthorizeFlowStateType::Password,
+        connection: CONNECTION.to_string(),
+        state: req.state,
+        scopes,
+        client_id: req.client_id,
+        redirect_uri: req.redirect_uri,
+        prompt: req.prompt,
+        acr_values: req.acr_values,
+        device,
+    };
+
+    Ok(complete_flow(&state, flow, user).await?.into_response())
+}
+
+async fn send_account_exists_email(mailer: &Mailer, to: String) -> Result<(), Error> {
+    mailer
+        .send(Email {
+            to,
+            subject: "Your account already exists".into(),
+            body: "Someone tried to sign up with your email address, which already has an account. If this was you, you can sign in or reset your password on the login page. If it wasn't, you can ignore this email.".into(),
+        })
+        .await
+}
+
+async fn send_verification_email(mailer: &Mailer, to: String, token: &str) -> Result<(), Error> {
+    mailer
+        .send(Email {
+            to,
+            subject: "Verify your email address".into(),
+            body: format!(
+                "Open the following link to verify your email address:\n\n{}/oauth/password/verify?token={token}\n\nThe link expires in 24 hours.",
+                env!("DOMAIN"),
+            ),
+        })
+        .await
+}
+
+async fn send_reset_email(mailer: &Mailer, to: String, token: &str) -> Result<(), Error> {
+    mailer
+        .send(Email {
+            to,
+            subject: "Reset your password".into(),
+            body: format!(
+                "Open the following link to choose a new password:\n\n{}/oauth/password/reset?token={token}\n\nThe link expires in 1 hour. If you didn't request a password reset, you can ignore this email.",
+                env!("DOMAIN"),
+            ),
+        })
+        .await
+}
+
+async fn sign_up(
+    state: &AppState,
+    mailer: &Mailer,
+    query: &str,
+    form: SignUpForm,
+) -> Result<(), Error> {
+    let email = normalize_email(&form.email);
+    let password_hash = hash_password(&form.password)?;
+
+    // The owner of the account is told instead of the one signing up, to not reveal which email
+    // addresses have one
+    if let Some(credentials) = get_credentials(&state.db, &email).await? {
+        return send_account_exists_email(mailer, credentials.email).await;
+    }
+
+    let user = User {
+        email: Some(email.clone()),
+        email_verified: Some(false),
+        name: form.name.filter(|name| !name.trim().is_empty()),
+        ..User::default_with_id(gen_string(32))
+    };
+    let user = sign_in(state, CONNECTION, user, None).await?;
+
+    password::insert_credentials(&state.db, &user.id, &email, &password_hash).await?;
+
+    let token = gen_string(32);
+
+    state
+        .kv
+        .put(
+            &format!("email-verification:{token}"),
+            EmailVerificationState {
+                user_id: user.id,
+                authorize_query: query.to_string(),
+            },
+        )
+        .unwrap()
+        .expiration_ttl(Duration::days(1).num_seconds() as u64)
+        .execute()
+        .await
+        .map_err(Error::Kv)?;
+
+    send_verification_email(mailer, email, &token).await
+}
+
+async fn password_signup_impl(
+    state: AppState,
+    query: String,
+    form: SignUpForm,
+) -> Result<Response, Error> {
+    let req = parse_authorize_request(&query)?;
+    validate_request(&state, &req).await?;
+
+    if let Some(redirect) = enterprise_redirect(&state, &query, &form.email).await? {
+        return Ok(redirect.into_response());
+    }
+
+    let mailer = Mailer::new(&state.env)?;
+
+    let message = match sign_up(&state, &mailer, &query, form).await {
+        Ok(()) => "Check your email to verify your email address".to_string(),
+        Err(e @ Error::WeakPassword) => e.to_string(),
+        Err(e) => return Err(e),
+    };
+
+    Ok(back_to_login(&query, &message).into_response())
+}
+
+async fn password_verify_impl(state: AppState, req: VerifyRequest) -> Result<Response, Error> {
+    let key = format!("email-verification:{}", req.token);
+
+    let verification = state
+        .kv
+        .get(&key)
+        .json::<EmailVerificationState>()
+        .await
+        .map_err(Error::Kv)?
+        .ok_or(Error::InvalidLink)?;
+
+    state.kv.delete(&key).await.map_err(Error::Kv)?;
+
+    password::set_email_verified(&state.db, &verification.user_id).await?;
+
+    Ok(back_to_login(
+        &verification.authorize_query,
+        "Your email address is verified, you can now sign in",
+    )
+    .into_response())
+}
+
+async fn send_reset_link(
+    state: &AppState,
+    mailer: &Mailer,
+    query: &str,
+    form: ForgotPasswordForm,
+) -> Result<(), Error> {
+    let Some(credentials) = get_credentials(&state.db, &form.email).await? else {
+        return Ok(());
+    };
+
+    let token = gen_string(32);
+
+    state
+        .kv
+        .put(
+            &format!("password-reset:{token}"),
+            PasswordResetState {
+                user_id: credentials.user_id,
+                authorize_query: query.to_string(),
+            },
+        )
+        .unwrap()
+        .expiration_ttl(Duration::hours(1).num_seconds() as u64)
+        .execute()
+        .await
+        .map_err(Error::Kv)?;
+
+    send_reset_email(mailer, credentials.email, &token).await
+}
+
+async fn password_forgot_impl(
+    state: AppState,
+    query: String,
+    form: ForgotPasswordForm,
+) -> Result<Response, Error> {
+    let req = parse_authorize_request(&query)?;
+    validate_request(&state, &req).await?;
+
+    if let Some(redirect) = enterprise_redirect(&state, &query, &form.email).await? {
+        return Ok(redirect.into_response());
+    }
+
+    // The response is the same whether or not there is an account, to not reveal which email
+    // addresses have one
+    let mailer = Mailer::new(&state.env)?;
+    send_reset_link(&state, &mailer, &query, form).await?;
+
+    Ok(back_to_login(
+        &query,
+        "If there is an account for this email address, you will receive a link to reset your password",
+    )
+    .into_response())
+}
+
+async fn get_password_reset(state: &AppState, token: &str) -> Result<PasswordResetState, Error> {
+    state
+        .kv
+        .get(&format!("password-reset:{token}"))
+        .json::<PasswordResetState>()
+        .await
+        .map_err(Error::Kv)?
+        .ok_or(Error::InvalidLink)
+}
+
+async fn password_reset_page_impl(state: AppState, req: ResetRequest) -> Result<Response, Error> {
+    get_password_reset(&state, &req.token).await?;
+
+    Ok(Html(include_str!("../../public/reset-password.html")).into_response())
+}
+
+async fn password_reset_impl(
+    state: AppState,
+    req: ResetRequest,
+    form: ResetForm,
+) -> Result<Response, Error> {
+    let reset = get_password_reset(&state, &req.token).await?;
+
+    let password_hash = match hash_password(&form.password) {
+        Ok(password_hash) => password_hash,
+        Err(e @ Error::WeakPassword) => {
+            let mut url = Url::parse(&format!("{}/oauth/password/reset", env!("DOMAIN"))).unwrap();
+            url.query_pairs_mut()
+                .append_pair("token", &req.token)
+                .append_pair("message", &e.to_string());
+
+            return Ok(Redirect::to(url.as_str()).into_response());
+        }
+        Err(e) => return Err(e),
+    };
+
+    // The link can only be used once
+    state
+        .kv
+        .delete(&format!("password-reset:{}", req.token))
+        .await
+        .map_err(Error::Kv)?;
+
+    password::update_password(&state.db, &reset.user_id, &password_hash).await?;
+
+    // Sign out everywhere, in case the password was reset because the account was compromised
+    end_user_sessions(&state, &reset.user_id).await?;
+
+    Ok(back_to_login(
+        &reset.authorize_query,
+        "Your password has been changed, you can now sign in",
+    )
+    .into_response())
+}
+
+pub async fn password_login(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    RawQuery(query): RawQuery,
+    Form(form): Form<PasswordForm>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let device = Device::from_headers(&headers);
+        let res = password_login_impl(state, query.unwrap_or_default(), form, device).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
+
+pub async fn password_signup(
+    State(state): State<AppState>,
+    RawQuery(query): RawQuery,
+    Form(form): Form<SignUpForm>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let res = password_signup_impl(state, query.unwrap_or_default(), form).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
+
+pub async fn password_verify(
+    State(state): State<AppState>,
+    Query(req): Query<VerifyRequest>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let res = password_verify_impl(state, req).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
+
+pub async fn password_forgot(
+    State(state): State<AppState>,
+    RawQuery(query): RawQuery,
+    Form(form): Form<ForgotPasswordForm>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let res = password_forgot_impl(state, query.unwrap_or_default(), form).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
+
+pub async fn password_reset_page(
+    State(state): State<AppState>,
+    Query(req): Query<ResetRequest>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let res = password_reset_page_impl(state, req).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
+
+pub async fn password_reset(
+    State(state): State<AppState>,
+    Query(req): Query<ResetRequest>,
+    Form(form): Form<ResetForm>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let res = password_reset_impl(state, req, form).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use futures::executor::block_on;
+
+    use super::*;
+
+    #[test]
+    fn sends_reset_link_to_the_account() {
+        let outbox = Rc::default();
+        let mailer = Mailer::Memory(Rc::clone(&outbox));
+
+        block_on(send_reset_email(
+            &mailer,
+            "user@example.com".into(),
+            "token",
+        ))
+        .unwrap();
+
+        let outbox = outbox.borrow();
+        assert_eq!(outbox.len(), 1);
+        assert_eq!(outbox[0].to, "user@example.com");
+        assert_eq!(outbox[0].subject, "Reset your password");
+        assert!(outbox[0].body.contains(&format!(
+            "{}/oauth/password/reset?token=token\n",
+            env!("DOMAIN")
+        )));
+    }
+
+    #[test]
+    fn sends_verification_link() {
+        let outbox = Rc::default();
+        let mailer = Mailer::Memory(Rc::clone(&outbox));
+
+        block_on(send_verification_email(
+            &mailer,
+            "user@example.com".into(),
+            "token",
+        ))
+        .unwrap();
+
+        let outbox = outbox.borrow();
+        assert_eq!(outbox.len(), 1);
+        assert_eq!(outbox[0].to, "user@example.com");
+        assert!(outbox[0].body.contains(&format!(
+            "{}/oauth/password/verify?token=token\n",
+            env!("DOMAIN")
+        )));
+    }
+
+    #[test]
+    fn tells_existing_account_without_a_link() {
+        let outbox = Rc::default();
+        let mailer = Mailer::Memory(Rc::clone(&outbox));
+
+        block_on(send_account_exists_email(
+            &mailer,
+            "user@example.com".into(),
+        ))
+        .unwrap();
+
+        let outbox = outbox.borrow();
+        assert_eq!(outbox.len(), 1);
+        assert_eq!(outbox[0].to, "user@example.com");
+        assert!(!outbox[0].body.contains("token="));
+    }
+}
@@ -0,0 +1,396 @@
+use axum::{
+    extract::{Form, Query, RawQuery, State},
+    http::HeaderMap,
+    response::{Html, IntoResponse, Redirect, Response},
+};
+use chrono::Duration;
+use futures::channel::oneshot;
+use oauth2::CsrfToken;
+use rand::{thread_rng, Rng};
+use reqwest::Url;
+use serde::Deserialize;
+
+use crate::{
+    error::Error,
+    gen_string,
+    mailer::{Email, Mailer},
+    password::normalize_email,
+    rate_limit::check_rate_limit,
+    sessions::Device,
+    users::User,
+    AppState,
+};
+
+use super::{
+    authorize::{back_to_login, parse_authorize_request, validate_request},
+    callback::{complete_flow, sign_in},
+    discover::enterprise_redirect,
+    states::{AuthorizeFlowState, AuthorizeFlowStateType},
+};
+
+/// Name of the connection, and the provider of the identities of passwordless users.
+const CONNECTION: &str = "email";
+
+/// How long the code and link can be used.
+const CODE_TTL_MINUTES: i64 = 10;
+
+/// Wrong codes after which the code and link are invalidated.
+const MAX_ATTEMPTS: u32 = 5;
+
+/// Emails that can be sent to an address within `RATE_LIMIT_MINUTES`.
+const MAX_EMAILS: u32 = 5;
+const RATE_LIMIT_MINUTES: i64 = 60;
+
+#[derive(Deserialize)]
+pub struct StartForm {
+    email: String,
+}
+
+#[derive(Deserialize)]
+pub struct LinkRequest {
+    token: String,
+}
+
+#[derive(Deserialize)]
+pub struct CodeRequest {
+    state: String,
+}
+
+#[derive(Deserialize)]
+pub struct CodeForm {
+    code: String,
+}
+
+fn code_page(state: &str, message: Option<&str>) -> Redirect {
+    let mut url = Url::parse(&format!("{}/oauth/passwordless/code", env!("DOMAIN"))).unwrap();
+
+    url.query_pairs_mut().append_pair("state", state);
+    if let Some(message) = message {
+        url.query_pairs_mut().append_pair("message", message);
+    }
+
+    Redirect::to(url.as_str())
+}
+
+async fn send_code_email(
+    mailer: &Mailer,
+    to: String,
+    code: &str,
+    link_token: &str,
+) -> Result<(), Error> {
+    mailer
+        .send(Email {
+            to,
+            subject: format!("Your sign in code is {code}"),
+            body: format!(
+                "Enter {code} to sign in, or open the following link:\n\n{}/oauth/passwordless/link?token={link_token}\n\nThe code and link expire in {CODE_TTL_MINUTES} minutes.",
+                env!("DOMAIN"),
+            ),
+        })
+        .await
+}
+
+async fn passwordless_start_impl(
+    state: AppState,
+    query: String,
+    form: StartForm,
+    device: Device,
+) -> Result<Response, Error> {
+    let req = parse_authorize_request(&query)?;
+    let scopes = validate_request(&state, &req).await?;
+
+    let email = normalize_email(&form.email);
+    if !email.contains('@') {
+        return Ok(back_to_login(&query, "invalid email address").into_response());
+    }
+
+    if let Some(redirect) = enterprise_redirect(&state, &query, &email).await? {
+        return Ok(redirect.into_response());
+    }
+
+    let mailer = Mailer::new(&state.env)?;
+
+    match check_rate_limit(
+        &state,
+        &format!("passwordless-rate-limit:{email}"),
+        MAX_EMAILS,
+        Duration::minutes(RATE_LIMIT_MINUTES),
+    )
+    .await
+    {
+        Ok(()) => {}
+        Err(e @ Error::RateLimited) => {
+            return Ok(back_to_login(&query, &e.to_string()).into_response())
+        }
+        Err(e) => return Err(e),
+    }
+
+    let csrf_token = CsrfToken::new_random();
+    let link_token = gen_string(32);
+    let code = format!("{:06}", thread_rng().gen_range(0..1_000_000));
+    let ttl = Duration::minutes(CODE_TTL_MINUTES).num_seconds() as u64;
+
+    state
+        .kv
+        .put(
+            &format!("state:{}", csrf_token.secret()),
+            AuthorizeFlowState {
+                ty: AuthorizeFlowStateType::Passwordless {
+                    email: email.clone(),
+                    code: code.clone(),
+                    link_token: link_token.clone(),
+                    attempts: 0,
+                },
+                connection: CONNECTION.to_string(),
+                state: req.state,
+                scopes,
+                client_id: req.client_id,
+                redirect_uri: req.redirect_uri,
+                prompt: req.prompt,
+                acr_values: req.acr_values,
+                device,
+            },
+        )
+        .unwrap()
+        .expiration_ttl(ttl)
+        .execute()
+        .await
+        .map_err(Error::Kv)?;
+
+    // The link only refers to the flow, so that the state that is shown to the browser can't be
+    // used to sign in without the code
+    state
+        .kv
+        .put(&format!("passwordless:{link_token}"), csrf_token.secret())
+        .unwrap()
+        .expiration_ttl(ttl)
+        .execute()
+        .await
+        .map_err(Error::Kv)?;
+
+    send_code_email(&mailer, email, &code, &link_token).await?;
+
+    Ok(code_page(csrf_token.secret(), None).into_response())
+}
+
+async fn get_flow(state: &AppState, id: &str) -> Result<AuthorizeFlowState, Error> {
+    state
+        .kv
+        .get(&format!("state:{id}"))
+        .json::<AuthorizeFlowState>()
+        .await
+        .map_err(Error::Kv)?
+        .ok_or(Error::InvalidLink)
+}
+
+async fn delete_flow(state: &AppState, id: &str, link_token: &str) -> Result<(), Error> {
+    state
+        .kv
+        .delete(&format!("state:{id}"))
+        .await
+        .map_err(Error::Kv)?;
+    state
+        .kv
+        .delete(&format!("passwordless:{link_token}"))
+        .await
+        .map_err(Error::Kv)
+}
+
+/// Sign in the owner of the email address, which is verified by them having the code or link.
+async fn finish(state: &AppState, id: &str, flow: AuthorizeFlowState) -> Result<Response, Error> {
+    let AuthorizeFlowStateType::Passwordless {
+        email, link_token, ..
+    } = &flow.ty
+    else {
+        return Err(Error::InvalidLink);
+    };
+
+    // The code and link can only be used once
+    delete_flow(state, id, link_token).await?;
+
+    let user = User {
+        email: Some(email.clone()),
+        email_verified: Some(true),
+        ..User::default_with_id(email.clone())
+    };
+    let user = sign_in(state, CONNECTION, user, None).await?;
+
+    Ok(complete_flow(state, flow, user).await?.into_response())
+}
+
+async fn get_link(state: &AppState, token: &str) -> Result<String, Error> {
+    state
+        .kv
+        .get(&format!("passwordless:{token}"))
+        .text()
+        .await
+        .map_err(Error::Kv)?
+        .ok_or(Error::InvalidLink)
+}
+
+/// Asks to confirm the sign in, as opening the link must not use it. Mail scanners and link
+/// previews open links before the user does.
+async fn passwordless_link_page_impl(state: AppState, req: LinkRequest) -> Result<Response, Error> {
+    let id = get_link(&state, &req.token).await?;
+    get_flow(&state, &id).await?;
+
+    Ok(Html(include_str!("../../public/passwordless-link.html")).into_response())
+}
+
+async fn passwordless_link_impl(state: AppState, req: LinkRequest) -> Result<Response, Error> {
+    let id = get_link(&state, &req.token).await?;
+    let flow = get_flow(&state, &id).await?;
+
+    finish(&state, &id, flow).await
+}
+
+async fn passwordless_code_page_impl(state: AppState, req: CodeRequest) -> Result<Response, Error> {
+    get_flow(&state, &req.state).await?;
+
+    Ok(Html(include_str!("../../public/passwordless.html")).into_response())
+}
+
+async fn passwordless_code_impl(
+    state: AppState,
+    req: CodeRequest,
+    form: CodeForm,
+) -> Result<Response, Error> {
+    let mut flow = get_flow(&state, &req.state).await?;
+
+    let AuthorizeFlowStateType::Passwordless {
+        code,
+        link_token,
+        attempts,
+        ..
+    } = &mut flow.ty
+    else {
+        return Err(Error::InvalidLink);
+    };
+
+    if form.code.trim() == code.as_str() {
+        return finish(&state, &req.state, flow).await;
+    }
+
+    *attempts += 1;
+    if *attempts >= MAX_ATTEMPTS {
+        delete_flow(&state, &req.state, link_token).await?;
+        return Err(Error::InvalidLink);
+    }
+
+    state
+        .kv
+        .put(&format!("state:{}", req.state), &flow)
+        .unwrap()
+        .expiration_ttl(Duration::minutes(CODE_TTL_MINUTES).num_seconds() as u64)
+        .execute()
+        .await
+        .map_err(Error::Kv)?;
+
+    Ok(code_page(&req.state, Some("invalid code")).into_response())
+}
+
+pub async fn passwordless_start(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    RawQuery(query): RawQuery,
+    Form(form): Form<StartForm>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let device = Device::from_headers(&headers);
+        let res = passwordless_start_impl(state, query.unwrap_or_default(), form, device).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
+
+pub async fn passwordless_link_page(
+    State(state): State<AppState>,
+    Query(req): Query<LinkRequest>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let res = passwordless_link_page_impl(state, req).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
+
+pub async fn passwordless_link(
+    State(state): State<AppState>,
+    Query(req): Query<LinkRequest>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let res = passwordless_link_impl(state, req).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
+
+pub async fn passwordless_code_page(
+    State(state): State<AppState>,
+    Query(req): Query<CodeRequest>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let res = passwordless_code_page_impl(state, req).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
+
+pub async fn passwordless_code(
+    State(state): State<AppState>,
+    Query(req): Query<CodeRequest>,
+    Form(form): Form<CodeForm>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let res = passwordless_code_impl(state, req, form).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use futures::executor::block_on;
+
+    use super::*;
+
+    #[test]
+    fn sends_code_and_link() {
+        let outbox = Rc::default();
+        let mailer = Mailer::Memory(Rc::clone(&outbox));
+
+        block_on(send_code_email(
+            &mailer,
+            "user@example.com".into(),
+            "012345",
+            "token",
+        ))
+        .unwrap();
+
+        let outbox = outbox.borrow();
+        assert_eq!(outbox.len(), 1);
+        assert_eq!(outbox[0].to, "user@example.com");
+        assert_eq!(outbox[0].subject, "Your sign in code is 012345");
+        assert!(outbox[0].body.contains(&format!(
+            "{}/oauth/passwordless/link?token=token\n",
+            env!("DOMAIN")
+        )));
+    }
+}
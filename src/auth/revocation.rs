@@ -0,0 +1,248 @@
+use chrono::{DateTime, Utc};
+use oauth2::ClientId;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    d1,
+    error::Error,
+    tokens::{access_token_key, refresh_token_key, AccessRefreshTokenSet},
+    AppState,
+};
+
+use super::states::TokenMetadata;
+
+/// An access and refresh token that were issued together, as indexed in D1 to list them.
+#[derive(Deserialize)]
+pub struct IssuedTokens {
+    pub id: String,
+    pub client_id: ClientId,
+    /// Name of the application.
+    pub name: String,
+    pub session_id: String,
+    /// Space separated scopes.
+    scopes: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+pub struct TokenInfo {
+    pub id: String,
+    pub client_id: ClientId,
+    pub name: String,
+    pub session_id: String,
+    pub scopes: Vec<String>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+impl From<IssuedTokens> for TokenInfo {
+    fn from(tokens: IssuedTokens) -> Self {
+        Self {
+            scopes: tokens
+                .scopes
+                .split_whitespace()
+                .map(str::to_string)
+                .collect(),
+            id: tokens.id,
+            client_id: tokens.client_id,
+            name: tokens.name,
+            session_id: tokens.session_id,
+            created_at: tokens.created_at,
+            expires_at: tokens.expires_at,
+            last_used_at: tokens.last_used_at,
+        }
+    }
+}
+
+/// Index an access and refresh token that were issued together in D1. Tokens are stored in KV by
+/// their secret, so their keys are kept to be able to revoke them.
+pub async fn index_tokens(
+    state: &AppState,
+    token_meta: &TokenMetadata,
+    tokens: &AccessRefreshTokenSet,
+) -> Result<(), Error> {
+    let mut scopes = token_meta
+        .scopes
+        .iter()
+        .map(|scope| scope.as_str())
+        .collect::<Vec<_>>();
+    scopes.sort_unstable();
+
+    let now = Utc::now();
+
+    d1::query!(
+        &state.db,
+        r#"
+INSERT INTO tokens (id, user_id, client_id, session_id, scopes, created_at, expires_at, access_key, refresh_key)
+VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
+        "#,
+        &token_meta.token_id,
+        &token_meta.user_id,
+        &token_meta.client_id,
+        &token_meta.session_id,
+        scopes.join(" "),
+        now,
+        now + tokens.refresh_expires_in,
+        access_token_key(tokens.access_token.secret()),
+        refresh_token_key(tokens.refresh_token.secret()),
+    )
+    .map_err(Error::D1)?
+    .run()
+    .await
+    .map_err(Error::D1)?;
+
+    Ok(())
+}
+
+/// The unexpired tokens of a user, most recent first.
+pub async fn list_tokens(db: &d1::Database, user_id: &str) -> Result<Vec<IssuedTokens>, Error> {
+    let tokens = d1::query!(
+        db,
+        r#"
+SELECT tokens.id, tokens.client_id, applications.name, tokens.session_id, tokens.scopes, tokens.created_at, tokens.expires_at, tokens.last_used_at
+FROM tokens
+JOIN applications ON applications.client_id = tokens.client_id
+WHERE tokens.user_id = ?
+ORDER BY tokens.created_at DESC
+        "#,
+        user_id,
+    )
+    .map_err(Error::D1)?
+    .all()
+    .await
+    .map_err(Error::D1)?
+    .results::<IssuedTokens>()
+    .map_err(Error::D1)?;
+
+    let now = Utc::now();
+
+    Ok(tokens
+        .into_iter()
+        .filter(|tokens| tokens.expires_at > now)
+        .collect())
+}
+
+/// Record that an access token was used.
+pub async fn touch_tokens(db: &d1::Database, token_id: &str) -> Result<(), Error> {
+    d1::query!(
+        db,
+        "UPDATE tokens SET last_used_at = ?1 WHERE id = ?2",
+        Utc::now(),
+        token_id,
+    )
+    .map_err(Error::D1)?
+    .run()
+    .await
+    .map_err(Error::D1)?;
+
+    Ok(())
+}
+
+/// Which of the tokens of a user to revoke.
+enum Filter<'a> {
+    All,
+    Client(&'a ClientId),
+    Session(&'a str),
+    TokenSet(&'a str),
+}
+
+/// Revoke every access and refresh token that was issued to a user.
+pub async fn revoke_user_tokens(state: &AppState, user_id: &str) -> Result<(), Error> {
+    revoke_tokens(state, user_id, Filter::All).await
+}
+
+/// Revoke the access and refresh tokens that were issued to a user for one application.
+pub async fn revoke_client_tokens(
+    state: &AppState,
+    user_id: &str,
+    client_id: &ClientId,
+) -> Result<(), Error> {
+    revoke_tokens(state, user_id, Filter::Client(client_id)).await
+}
+
+/// Revoke the access and refresh tokens that were issued through a session, such as when the user
+/// signs out.
+pub async fn revoke_session_tokens(
+    state: &AppState,
+    user_id: &str,
+    session_id: &str,
+) -> Result<(), Error> {
+    revoke_tokens(state, user_id, Filter::Session(session_id)).await
+}
+
+/// Revoke an access and refresh token that were issued together.
+pub async fn revoke_token_set(
+    state: &AppState,
+    user_id: &str,
+    token_id: &str,
+) -> Result<(), Error> {
+    d1::query!(
+        &state.db,
+        "SELECT id FROM tokens WHERE id = ? AND user_id = ?",
+        token_id,
+        user_id,
+    )
+    .map_err(Error::D1)?
+    .first::<String>(Some("id"))
+    .await
+    .map_err(Error::D1)?
+    .ok_or(Error::TokenSetNotFound)?;
+
+    revoke_tokens(state, user_id, Filter::TokenSet(token_id)).await
+}
+
+#[derive(Deserialize)]
+struct TokenKeys {
+    access_key: Option<String>,
+    refresh_key: Option<String>,
+}
+
+async fn revoke_tokens(state: &AppState, user_id: &str, filter: Filter<'_>) -> Result<(), Error> {
+    let query = match filter {
+        Filter::All => d1::query!(
+            &state.db,
+            "DELETE FROM tokens WHERE user_id = ? RETURNING access_key, refresh_key",
+            user_id,
+        ),
+        Filter::Client(client_id) => d1::query!(
+            &state.db,
+            "DELETE FROM tokens WHERE user_id = ? AND client_id = ? RETURNING access_key, refresh_key",
+            user_id,
+            client_id,
+        ),
+        Filter::Session(session_id) => d1::query!(
+            &state.db,
+            "DELETE FROM tokens WHERE user_id = ? AND session_id = ? RETURNING access_key, refresh_key",
+            user_id,
+            session_id,
+        ),
+        Filter::TokenSet(token_id) => d1::query!(
+            &state.db,
+            "DELETE FROM tokens WHERE user_id = ? AND id = ? RETURNING access_key, refresh_key",
+            user_id,
+            token_id,
+        ),
+    };
+
+    let keys = query
+        .map_err(Error::D1)?
+        .all()
+        .await
+        .map_err(Error::D1)?
+        .results::<TokenKeys>()
+        .map_err(Error::D1)?;
+
+    // Tokens indexed before their keys were stored expire on their own
+    for key in keys
+        .into_iter()
+        .flat_map(|keys| [keys.access_key, keys.refresh_key])
+        .flatten()
+    {
+        state.kv.delete(&key).await.map_err(Error::Kv)?;
+    }
+
+    Ok(())
+}
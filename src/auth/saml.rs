@@ -0,0 +1,80 @@
+use axum::{extract::State, http::header, response::IntoResponse, Form};
+use futures::channel::oneshot;
+use oauth2::basic::BasicErrorResponseType;
+use serde::Deserialize;
+
+use crate::{
+    error::Error,
+    providers::{get_provider, Provider},
+    saml, AppState,
+};
+
+use super::{
+    callback::{complete_flow, sign_in},
+    states::{AuthorizeFlowState, AuthorizeFlowStateType},
+};
+
+#[derive(Deserialize)]
+pub struct AcsRequest {
+    #[serde(rename = "SAMLResponse")]
+    saml_response: String,
+    #[serde(rename = "RelayState")]
+    relay_state: String,
+}
+
+pub async fn saml_metadata() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "application/samlmetadata+xml")],
+        saml::metadata(),
+    )
+}
+
+/// Assertion consumer service, which receives the responses of SAML identity providers.
+async fn saml_acs_impl(state: AppState, req: AcsRequest) -> Result<impl IntoResponse, Error> {
+    let key = format!("state:{}", req.relay_state);
+
+    let flow = state
+        .kv
+        .get(&key)
+        .json::<AuthorizeFlowState>()
+        .await
+        .map_err(Error::Kv)?
+        .ok_or(Error::OAuth2(
+            BasicErrorResponseType::InvalidGrant,
+            "could not find flow for the given state".into(),
+        ))?;
+
+    let AuthorizeFlowStateType::Saml { request_id } = &flow.ty else {
+        return Err(Error::InvalidConnection);
+    };
+
+    // A response can only be used once
+    state.kv.delete(&key).await.map_err(Error::Kv)?;
+
+    let provider = get_provider(&state.db, &flow.connection).await?;
+    let Provider::Saml(saml) = &provider.provider else {
+        return Err(Error::InvalidConnection);
+    };
+
+    let assertion = saml::parse_response(saml, &req.saml_response, request_id)?;
+    let user = saml
+        .attributes
+        .user(&assertion.name_id, &assertion.attributes);
+    let user = sign_in(&state, &provider.name, user, None).await?;
+
+    complete_flow(&state, flow, user).await
+}
+
+pub async fn saml_acs(
+    State(state): State<AppState>,
+    Form(req): Form<AcsRequest>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let res = saml_acs_impl(state, req).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
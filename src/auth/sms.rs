@@ -0,0 +1,261 @@
+use axum::{
+    extract::{Form, Query, RawQuery, State},
+    http::HeaderMap,
+    response::{Html, IntoResponse, Redirect, Response},
+};
+use chrono::Duration;
+use futures::channel::oneshot;
+use oauth2::CsrfToken;
+use reqwest::Url;
+use serde::Deserialize;
+
+use crate::{
+    error::Error,
+    sessions::Device,
+    sms::{self, normalize_phone_number, Sms, SmsSender},
+    users::User,
+    AppState,
+};
+
+use super::{
+    authorize::{back_to_login, parse_authorize_request, validate_request},
+    callback::{complete_flow, sign_in},
+    states::{AuthorizeFlowState, AuthorizeFlowStateType},
+};
+
+/// Name of the connection, and the provider of the identities of phone users.
+const CONNECTION: &str = "sms";
+
+/// How long the code can be used.
+const CODE_TTL_MINUTES: i64 = 10;
+
+/// Wrong codes after which the code is invalidated.
+const MAX_ATTEMPTS: u32 = 5;
+
+#[derive(Deserialize)]
+pub struct StartForm {
+    phone_number: String,
+}
+
+#[derive(Deserialize)]
+pub struct CodeRequest {
+    state: String,
+}
+
+#[derive(Deserialize)]
+pub struct CodeForm {
+    code: String,
+}
+
+fn code_page(state: &str, message: Option<&str>) -> Redirect {
+    let mut url = Url::parse(&format!("{}/oauth/sms/code", env!("DOMAIN"))).unwrap();
+
+    url.query_pairs_mut().append_pair("state", state);
+    if let Some(message) = message {
+        url.query_pairs_mut().append_pair("message", message);
+    }
+
+    Redirect::to(url.as_str())
+}
+
+async fn send_code(sender: &SmsSender, to: String, code: &str) -> Result<(), Error> {
+    sender
+        .send(Sms {
+            to,
+            body: format!("{code} is your sign in code. It expires in {CODE_TTL_MINUTES} minutes."),
+        })
+        .await
+}
+
+async fn sms_start_impl(
+    state: AppState,
+    query: String,
+    form: StartForm,
+    device: Device,
+) -> Result<Response, Error> {
+    let req = parse_authorize_request(&query)?;
+    let scopes = validate_request(&state, &req).await?;
+    let sender = SmsSender::new(&state.env)?;
+
+    let phone_number = match normalize_phone_number(&form.phone_number) {
+        Ok(phone_number) => phone_number,
+        Err(e) => return Ok(back_to_login(&query, &e.to_string()).into_response()),
+    };
+
+    match sms::check_rate_limit(&state, &phone_number).await {
+        Ok(()) => {}
+        Err(e @ Error::RateLimited) => {
+            return Ok(back_to_login(&query, &e.to_string()).into_response())
+        }
+        Err(e) => return Err(e),
+    }
+
+    let csrf_token = CsrfToken::new_random();
+    let code = sms::gen_code();
+
+    state
+        .kv
+        .put(
+            &format!("state:{}", csrf_token.secret()),
+            AuthorizeFlowState {
+                ty: AuthorizeFlowStateType::Sms {
+                    phone_number: phone_number.clone(),
+                    code: code.clone(),
+                    attempts: 0,
+                },
+                connection: CONNECTION.to_string(),
+                state: req.state,
+                scopes,
+                client_id: req.client_id,
+                redirect_uri: req.redirect_uri,
+                prompt: req.prompt,
+                acr_values: req.acr_values,
+                device,
+            },
+        )
+        .unwrap()
+        .expiration_ttl(Duration::minutes(CODE_TTL_MINUTES).num_seconds() as u64)
+        .execute()
+        .await
+        .map_err(Error::Kv)?;
+
+    send_code(&sender, phone_number, &code).await?;
+
+    Ok(code_page(csrf_token.secret(), None).into_response())
+}
+
+async fn get_flow(state: &AppState, id: &str) -> Result<AuthorizeFlowState, Error> {
+    state
+        .kv
+        .get(&format!("state:{id}"))
+        .json::<AuthorizeFlowState>()
+        .await
+        .map_err(Error::Kv)?
+        .ok_or(Error::InvalidLink)
+}
+
+async fn sms_code_page_impl(state: AppState, req: CodeRequest) -> Result<Response, Error> {
+    get_flow(&state, &req.state).await?;
+
+    Ok(Html(include_str!("../../public/sms.html")).into_response())
+}
+
+async fn sms_code_impl(
+    state: AppState,
+    req: CodeRequest,
+    form: CodeForm,
+) -> Result<Response, Error> {
+    let key = format!("state:{}", req.state);
+    let mut flow = get_flow(&state, &req.state).await?;
+
+    let AuthorizeFlowStateType::Sms {
+        phone_number,
+        code,
+        attempts,
+    } = &mut flow.ty
+    else {
+        return Err(Error::InvalidLink);
+    };
+
+    if form.code.trim() == code.as_str() {
+        // The code can only be used once
+        state.kv.delete(&key).await.map_err(Error::Kv)?;
+
+        let user = User {
+            phone_number: Some(phone_number.clone()),
+            phone_verified: Some(true),
+            ..User::default_with_id(phone_number.clone())
+        };
+        let user = sign_in(&state, CONNECTION, user, None).await?;
+
+        return Ok(complete_flow(&state, flow, user).await?.into_response());
+    }
+
+    *attempts += 1;
+    if *attempts >= MAX_ATTEMPTS {
+        state.kv.delete(&key).await.map_err(Error::Kv)?;
+        return Err(Error::InvalidLink);
+    }
+
+    state
+        .kv
+        .put(&key, &flow)
+        .unwrap()
+        .expiration_ttl(Duration::minutes(CODE_TTL_MINUTES).num_seconds() as u64)
+        .execute()
+        .await
+        .map_err(Error::Kv)?;
+
+    Ok(code_page(&req.state, Some("invalid code")).into_response())
+}
+
+pub async fn sms_start(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    RawQuery(query): RawQuery,
+    Form(form): Form<StartForm>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let device = Device::from_headers(&headers);
+        let res = sms_start_impl(state, query.unwrap_or_default(), form, device).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
+
+pub async fn sms_code_page(
+    State(state): State<AppState>,
+    Query(req): Query<CodeRequest>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let res = sms_code_page_impl(state, req).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
+
+pub async fn sms_code(
+    State(state): State<AppState>,
+    Query(req): Query<CodeRequest>,
+    Form(form): Form<CodeForm>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let res = sms_code_impl(state, req, form).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use futures::executor::block_on;
+
+    use super::*;
+
+    #[test]
+    fn sends_code() {
+        let outbox = Rc::default();
+        let sender = SmsSender::Memory(Rc::clone(&outbox));
+        let code = sms::gen_code();
+
+        block_on(send_code(&sender, "+14155552671".into(), &code)).unwrap();
+
+        let outbox = outbox.borrow();
+        assert_eq!(outbox.len(), 1);
+        assert_eq!(outbox[0].to, "+14155552671");
+        assert!(outbox[0]
+            .body
+            .starts_with(&format!("{code} is your sign in code.")));
+    }
+}
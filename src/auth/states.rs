@@ -1,13 +1,77 @@
 use std::collections::HashSet;
 
+use chrono::{DateTime, Duration, Utc};
 use oauth2::{AccessToken, ClientId, CsrfToken, PkceCodeVerifier, RefreshToken, Scope};
-use openidconnect::{core::CoreTokenResponse, Nonce};
+use openidconnect::Nonce;
 use serde::{Deserialize, Serialize};
 
+use crate::{
+    sessions::{Device, ACR_MFA, ACR_SINGLE_FACTOR},
+    tokens::TokenResponse,
+};
+
 #[derive(Serialize, Deserialize)]
 pub enum AuthorizeFlowStateType {
-    OAuth2,
-    Oidc { nonce: Nonce },
+    OAuth2 {
+        pkce_verifier: PkceCodeVerifier,
+    },
+    Oidc {
+        nonce: Nonce,
+        pkce_verifier: PkceCodeVerifier,
+    },
+    /// The ID of the AuthnRequest, which the response must be in response to.
+    Saml {
+        request_id: String,
+    },
+    /// Credentials checked by the worker itself, so there is no upstream state.
+    Password,
+    /// A one-time code and link sent to an email address.
+    Passwordless {
+        email: String,
+        code: String,
+        link_token: String,
+        attempts: u32,
+    },
+    /// A one-time code sent to a phone number.
+    Sms {
+        phone_number: String,
+        code: String,
+        attempts: u32,
+    },
+    /// A passkey assertion, which returns to the authorization request on failure.
+    Passkey {
+        challenge: String,
+        authorize_query: String,
+    },
+    /// A passkey registration of a new user.
+    PasskeyRegistration {
+        challenge: String,
+        authorize_query: String,
+        user_id: String,
+        email: Option<String>,
+        name: Option<String>,
+    },
+    /// An existing session, so the user doesn't sign in again. The flow is only stored when the
+    /// session has to be stepped up with a second factor.
+    Session {
+        session_id: String,
+    },
+}
+
+impl AuthorizeFlowStateType {
+    /// The authentication methods (RFC 8176) of signing in with this flow, before any second
+    /// factor.
+    pub fn amr(&self) -> &'static [&'static str] {
+        match self {
+            Self::OAuth2 { .. } | Self::Oidc { .. } | Self::Saml { .. } => &["fed"],
+            Self::Password => &["pwd"],
+            Self::Passwordless { .. } => &["otp"],
+            Self::Sms { .. } => &["sms"],
+            // Passkeys verify the user on the device, so they are multi-factor by themselves
+            Self::Passkey { .. } | Self::PasskeyRegistration { .. } => &["hwk", "mfa"],
+            Self::Session { .. } => &[],
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -15,28 +79,131 @@ pub struct AuthorizeFlowState {
     pub ty: AuthorizeFlowStateType,
     pub connection: String,
     pub state: CsrfToken,
-    pub pkce_verifier: PkceCodeVerifier,
     pub scopes: HashSet<Scope>,
     pub client_id: ClientId,
     pub redirect_uri: String,
+    /// The `prompt` of the authorization request.
+    pub prompt: Option<String>,
+    /// The `acr_values` of the authorization request.
+    pub acr_values: Option<String>,
+    /// The browser the flow was started in, which is stored with the session.
+    #[serde(default)]
+    pub device: Device,
+}
+
+impl AuthorizeFlowState {
+    pub fn has_prompt(&self, prompt: &str) -> bool {
+        self.prompt.as_deref().map_or(false, |prompts| {
+            prompts.split_whitespace().any(|p| p == prompt)
+        })
+    }
+
+    pub fn has_acr_value(&self, acr: &str) -> bool {
+        self.acr_values
+            .as_deref()
+            .map_or(false, |values| values.split_whitespace().any(|v| v == acr))
+    }
+
+    /// Whether the application only accepts a sign in with a second factor.
+    pub fn requires_mfa_acr(&self) -> bool {
+        self.has_acr_value(ACR_MFA) && !self.has_acr_value(ACR_SINGLE_FACTOR)
+    }
+}
+
+/// A pending email address verification, which returns to the authorization request it was
+/// started from.
+#[derive(Serialize, Deserialize)]
+pub struct EmailVerificationState {
+    pub user_id: String,
+    pub authorize_query: String,
+}
+
+/// A pending password reset, which returns to the authorization request it was started from.
+#[derive(Serialize, Deserialize)]
+pub struct PasswordResetState {
+    pub user_id: String,
+    pub authorize_query: String,
+}
+
+/// A sign in that is waiting for a second factor, before the authorization code is issued.
+#[derive(Serialize, Deserialize)]
+pub struct MfaState {
+    pub flow: AuthorizeFlowState,
+    pub user_id: String,
+    /// TOTP secret that is being enrolled, for users without a second factor.
+    pub enrollment: Option<String>,
+    /// Challenge of a passkey assertion.
+    pub passkey_challenge: Option<String>,
+    /// Recovery codes of a completed enrollment, which are shown once before continuing.
+    pub recovery_codes: Option<Vec<String>>,
+}
+
+/// A signed in user that is asked to approve the scopes of an application.
+#[derive(Serialize, Deserialize)]
+pub struct ConsentState {
+    pub flow: AuthorizeFlowState,
+    pub user_id: String,
+    pub session_id: String,
+}
+
+/// A pending TOTP enrollment of a signed in user.
+#[derive(Serialize, Deserialize)]
+pub struct TotpEnrollmentState {
+    pub user_id: String,
+    pub secret: String,
+}
+
+/// A pending phone number verification of a signed in user.
+#[derive(Serialize, Deserialize)]
+pub struct PhoneVerificationState {
+    pub phone_number: String,
+    pub code: String,
+    pub attempts: u32,
+}
+
+/// A pending passkey registration of a signed in user.
+#[derive(Serialize, Deserialize)]
+pub struct PasskeyRegistrationState {
+    pub user_id: String,
+    pub challenge: String,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct CodeFlowState {
-    pub reply: CoreTokenResponse,
+    pub reply: TokenResponse,
     pub client_id: ClientId,
     pub redirect_uri: String,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct TokenMetadata {
-    pub client_id: String,
+    pub user_id: String,
+    pub client_id: ClientId,
     pub scopes: HashSet<Scope>,
+    /// The session that signed the user in, to revoke the tokens when signing out.
+    pub session_id: Option<String>,
+    /// ID of the access and refresh token in the `tokens` table, to list and revoke them.
+    pub token_id: Option<String>,
+}
+
+impl TokenMetadata {
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.contains(&Scope::new(scope.to_string()))
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct ConnectionTokens {
     pub access_token: AccessToken,
     pub refresh_token: Option<RefreshToken>,
-    pub expires_in: std::time::Duration,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl ConnectionTokens {
+    /// Whether the access token has expired, or is about to expire within a minute.
+    pub fn is_expired(&self) -> bool {
+        self.expires_at.map_or(false, |expires_at| {
+            expires_at - Duration::minutes(1) <= Utc::now()
+        })
+    }
 }
@@ -0,0 +1,242 @@
+//! OpenID Connect back-channel logout, which notifies applications when a session they signed in
+//! through ends, see <https://openid.net/specs/openid-connect-backchannel-1_0.html>.
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Duration, Utc};
+use oauth2::ClientId;
+use openidconnect::{core::CoreJwsSigningAlgorithm, JsonWebKey, PrivateSigningKey};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{d1, error::Error, gen_string, http_client, keys::get_rsa_key, AppState};
+
+const EVENT: &str = "http://schemas.openid.net/event/backchannel-logout";
+
+/// Logout tokens are signed right before they are sent, so they don't have to be valid for long.
+const LOGOUT_TOKEN_TTL_MINUTES: i64 = 2;
+
+/// Failed notifications are retried by the scheduled handler, which runs every 15 minutes, waiting
+/// twice as long after every attempt.
+const RETRY_INTERVAL_MINUTES: i64 = 15;
+const MAX_ATTEMPTS: u32 = 6;
+
+#[derive(Deserialize)]
+struct Recipient {
+    client_id: ClientId,
+    backchannel_logout_uri: String,
+}
+
+#[derive(Deserialize)]
+struct Notification {
+    id: String,
+    client_id: ClientId,
+    /// Not set anymore when the application removed its URI since.
+    backchannel_logout_uri: Option<String>,
+    user_id: String,
+    session_id: String,
+    attempts: u32,
+}
+
+async fn logout_token(
+    state: &AppState,
+    client_id: &ClientId,
+    user_id: &str,
+    session_id: &str,
+) -> Result<String, Error> {
+    let signing_key = get_rsa_key(state).await?.ok_or(Error::MissingKeys)?;
+    let jwk = signing_key.as_verification_key();
+
+    let now = Utc::now();
+    let header = json!({
+        "alg": "RS256",
+        "typ": "logout+jwt",
+        "kid": jwk.key_id(),
+    });
+    let claims = json!({
+        "iss": env!("DOMAIN"),
+        "aud": client_id,
+        "iat": now.timestamp(),
+        "exp": (now + Duration::minutes(LOGOUT_TOKEN_TTL_MINUTES)).timestamp(),
+        "jti": gen_string(32),
+        "sub": user_id,
+        "sid": session_id,
+        "events": {
+            EVENT: {},
+        },
+    });
+
+    let message = format!(
+        "{}.{}",
+        URL_SAFE_NO_PAD.encode(header.to_string()),
+        URL_SAFE_NO_PAD.encode(claims.to_string()),
+    );
+    let signature = signing_key
+        .sign(
+            &CoreJwsSigningAlgorithm::RsaSsaPkcs1V15Sha256,
+            message.as_bytes(),
+        )
+        .map_err(Error::SigningError)?;
+
+    Ok(format!("{message}.{}", URL_SAFE_NO_PAD.encode(signature)))
+}
+
+/// Post a logout token to an application, returning whether it accepted it.
+async fn send(
+    state: &AppState,
+    client_id: &ClientId,
+    uri: &str,
+    user_id: &str,
+    session_id: &str,
+) -> Result<bool, Error> {
+    let logout_token = logout_token(state, client_id, user_id, session_id).await?;
+
+    let res = http_client()
+        .post(uri)
+        .form(&[("logout_token", logout_token)])
+        .send()
+        .await;
+
+    Ok(matches!(res, Ok(res) if res.status().is_success()))
+}
+
+fn next_attempt_at(attempts: u32) -> DateTime<Utc> {
+    Utc::now() + Duration::minutes(RETRY_INTERVAL_MINUTES * 2_i64.pow(attempts - 1))
+}
+
+/// Notify the applications a session signed in to that it ended. This has to happen before the
+/// session is deleted, which forgets the applications.
+pub async fn notify_session_end(
+    state: &AppState,
+    user_id: &str,
+    session_id: &str,
+) -> Result<(), Error> {
+    let recipients = d1::query!(
+        &state.db,
+        r#"
+SELECT session_applications.client_id, applications.backchannel_logout_uri
+FROM session_applications
+JOIN applications ON applications.client_id = session_applications.client_id
+WHERE session_applications.session_id = ? AND applications.backchannel_logout_uri IS NOT NULL
+        "#,
+        session_id,
+    )
+    .map_err(Error::D1)?
+    .all()
+    .await
+    .map_err(Error::D1)?
+    .results::<Recipient>()
+    .map_err(Error::D1)?;
+
+    for recipient in recipients {
+        if send(
+            state,
+            &recipient.client_id,
+            &recipient.backchannel_logout_uri,
+            user_id,
+            session_id,
+        )
+        .await?
+        {
+            continue;
+        }
+
+        d1::query!(
+            &state.db,
+            r#"
+INSERT INTO logout_notifications (id, client_id, user_id, session_id, attempts, next_attempt_at, created_at)
+VALUES (?, ?, ?, ?, ?, ?, ?)
+            "#,
+            gen_string(32),
+            &recipient.client_id,
+            user_id,
+            session_id,
+            1,
+            next_attempt_at(1),
+            Utc::now(),
+        )
+        .map_err(Error::D1)?
+        .run()
+        .await
+        .map_err(Error::D1)?;
+    }
+
+    Ok(())
+}
+
+/// Notify the applications of every session of a user, such as before signing them out
+/// everywhere.
+pub async fn notify_user_sessions_end(state: &AppState, user_id: &str) -> Result<(), Error> {
+    let session_ids = d1::query!(
+        &state.db,
+        "SELECT id FROM sessions WHERE user_id = ?",
+        user_id,
+    )
+    .map_err(Error::D1)?
+    .raw::<String>()
+    .await
+    .map_err(Error::D1)?;
+
+    for session_id in session_ids.into_iter().flatten() {
+        notify_session_end(state, user_id, &session_id).await?;
+    }
+
+    Ok(())
+}
+
+/// Retry the notifications that are due, giving up after [`MAX_ATTEMPTS`].
+pub async fn retry_logout_notifications(state: &AppState) -> Result<(), Error> {
+    let notifications = d1::query!(
+        &state.db,
+        r#"
+SELECT logout_notifications.id, logout_notifications.client_id, applications.backchannel_logout_uri, logout_notifications.user_id, logout_notifications.session_id, logout_notifications.attempts
+FROM logout_notifications
+JOIN applications ON applications.client_id = logout_notifications.client_id
+WHERE logout_notifications.next_attempt_at <= ?
+        "#,
+        Utc::now(),
+    )
+    .map_err(Error::D1)?
+    .all()
+    .await
+    .map_err(Error::D1)?
+    .results::<Notification>()
+    .map_err(Error::D1)?;
+
+    for notification in notifications {
+        let delivered = match &notification.backchannel_logout_uri {
+            Some(uri) => {
+                send(
+                    state,
+                    &notification.client_id,
+                    uri,
+                    &notification.user_id,
+                    &notification.session_id,
+                )
+                .await?
+            }
+            None => true,
+        };
+
+        let attempts = notification.attempts + 1;
+
+        let query = if delivered || attempts >= MAX_ATTEMPTS {
+            d1::query!(
+                &state.db,
+                "DELETE FROM logout_notifications WHERE id = ?",
+                &notification.id,
+            )
+        } else {
+            d1::query!(
+                &state.db,
+                "UPDATE logout_notifications SET attempts = ?1, next_attempt_at = ?2 WHERE id = ?3",
+                attempts,
+                next_attempt_at(attempts),
+                &notification.id,
+            )
+        };
+
+        query.map_err(Error::D1)?.run().await.map_err(Error::D1)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,108 @@
+use std::collections::BTreeMap;
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use wasm_bindgen_futures::JsFuture;
+use worker::Env;
+
+use crate::error::Error;
+
+mod sys {
+    use wasm_bindgen::prelude::wasm_bindgen;
+    use worker::js_sys::Promise;
+
+    #[wasm_bindgen(module = "/js/aes.js")]
+    extern "C" {
+        pub fn encrypt(key: &str, associated_data: &str, plaintext: &str) -> Promise;
+        pub fn decrypt(key: &str, associated_data: &str, sealed: &str) -> Promise;
+    }
+}
+
+/// A value encrypted with AES-GCM under a versioned key.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Encrypted {
+    pub version: u32,
+    pub ciphertext: String,
+}
+
+/// Read the encryption keys from the `ENCRYPTION_KEYS` secret.
+///
+/// The secret contains comma separated `version:key` pairs where the key is 32 base64 encoded
+/// bytes. New values are always encrypted with the highest version, older versions are only kept
+/// around to decrypt existing values until they have been re-encrypted.
+fn keys(env: &Env) -> Result<BTreeMap<u32, String>, Error> {
+    let secret = env
+        .secret("ENCRYPTION_KEYS")
+        .map_err(|_| Error::MissingEncryptionKey)?
+        .to_string();
+
+    secret
+        .split(',')
+        .map(|pair| {
+            let (version, key) = pair.trim().split_once(':')?;
+            Some((version.parse().ok()?, key.to_string()))
+        })
+        .collect::<Option<BTreeMap<_, _>>>()
+        .ok_or(Error::MissingEncryptionKey)
+}
+
+pub fn current_version(env: &Env) -> Result<u32, Error> {
+    keys(env)?
+        .into_keys()
+        .last()
+        .ok_or(Error::MissingEncryptionKey)
+}
+
+/// Encrypt a value with the current key. The associated data is authenticated but not encrypted,
+/// and binds the ciphertext to where it is stored.
+pub async fn encrypt<T: Serialize>(
+    env: &Env,
+    associated_data: &str,
+    value: &T,
+) -> Result<Encrypted, Error> {
+    let (version, key) = keys(env)?
+        .into_iter()
+        .last()
+        .ok_or(Error::MissingEncryptionKey)?;
+
+    let plaintext = serde_json::to_string(value).map_err(Error::SerdeJson)?;
+
+    let ciphertext = JsFuture::from(sys::encrypt(&key, associated_data, &plaintext))
+        .await
+        .map_err(Error::Crypto)?
+        .as_string()
+        .expect("ciphertext is not a string");
+
+    Ok(Encrypted {
+        version,
+        ciphertext,
+    })
+}
+
+pub async fn decrypt<T: DeserializeOwned>(
+    env: &Env,
+    associated_data: &str,
+    encrypted: &Encrypted,
+) -> Result<T, Error> {
+    let keys = keys(env)?;
+    let key = keys
+        .get(&encrypted.version)
+        .ok_or(Error::MissingEncryptionKey)?;
+
+    let plaintext = JsFuture::from(sys::decrypt(key, associated_data, &encrypted.ciphertext))
+        .await
+        .map_err(Error::Crypto)?
+        .as_string()
+        .expect("plaintext is not a string");
+
+    serde_json::from_str(&plaintext).map_err(Error::SerdeJson)
+}
+
+/// Encrypt a value again with the current key, so that older keys can be removed.
+pub async fn reencrypt(
+    env: &Env,
+    associated_data: &str,
+    encrypted: &Encrypted,
+) -> Result<Encrypted, Error> {
+    let value = decrypt::<serde_json::Value>(env, associated_data, encrypted).await?;
+    encrypt(env, associated_data, &value).await
+}
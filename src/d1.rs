@@ -1,4 +1,9 @@
-use serde::Deserialize;
+use std::fmt;
+
+use serde::{
+    de::{self, Visitor},
+    Deserialize, Deserializer,
+};
 use worker::{
     console_error,
     js_sys::{Array, ArrayBuffer, Object, Reflect, Uint8Array},
@@ -320,6 +325,59 @@ pub fn binding(env: &worker::Env, name: &str) -> Result<Database> {
     }
 }
 
+struct OptionBoolVisitor;
+
+impl<'de> Visitor<'de> for OptionBoolVisitor {
+    type Value = Option<bool>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a boolean or an integer")
+    }
+
+    fn visit_none<E: de::Error>(self) -> std::result::Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_unit<E: de::Error>(self) -> std::result::Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> std::result::Result<Self::Value, D::Error> {
+        deserializer.deserialize_any(self)
+    }
+
+    fn visit_bool<E: de::Error>(self, v: bool) -> std::result::Result<Self::Value, E> {
+        Ok(Some(v))
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> std::result::Result<Self::Value, E> {
+        Ok(Some(v != 0))
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> std::result::Result<Self::Value, E> {
+        Ok(Some(v != 0))
+    }
+
+    fn visit_f64<E: de::Error>(self, v: f64) -> std::result::Result<Self::Value, E> {
+        Ok(Some(v != 0.0))
+    }
+}
+
+/// Deserialize an optional boolean that is either a JSON boolean or a D1 integer column.
+///
+/// SQLite has no boolean type, so D1 returns `0` and `1` for columns that were bound from a `bool`.
+pub fn deserialize_option_bool<'de, D>(
+    deserializer: D,
+) -> std::result::Result<Option<bool>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserializer.deserialize_option(OptionBoolVisitor)
+}
+
 macro_rules! query {
     ($db:expr, $query:expr) => {
         $crate::d1::Database::prepare($db, $query)
@@ -0,0 +1,350 @@
+use chrono::Utc;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use worker::console_error;
+
+use crate::{
+    crypto::{self, Encrypted},
+    d1,
+    error::Error,
+    providers::{client_secret_key, ClientAuth, OidcProvider, Provider, ProviderConfig, Style},
+    AppState,
+};
+
+fn default_scopes() -> Vec<String> {
+    vec!["openid".into(), "profile".into(), "email".into()]
+}
+
+/// An OIDC connection to the identity provider of an organization, such as Okta, Entra ID or
+/// Keycloak. Users are routed to it by the domain of their email address.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct EnterpriseConnection {
+    pub name: String,
+    pub organization: String,
+    pub issuer_url: String,
+    pub client_id: String,
+    /// Email domains of the organization, such as `example.com`.
+    pub domains: Vec<String>,
+    #[serde(default = "default_scopes")]
+    pub scopes: Vec<String>,
+    /// Stored next to the definition and never serialized.
+    #[serde(skip)]
+    pub client_secret: Option<Encrypted>,
+}
+
+impl EnterpriseConnection {
+    pub fn validate(&self) -> Result<(), Error> {
+        let invalid = |reason: &str| Err(Error::InvalidProvider(reason.to_string()));
+
+        if self.name.is_empty()
+            || !self
+                .name
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        {
+            return invalid("name may only contain lowercase letters, digits and dashes");
+        }
+
+        if Url::parse(&self.issuer_url).is_err() {
+            return invalid("invalid url");
+        }
+
+        if self.domains.iter().any(|domain| {
+            domain.is_empty() || domain.contains('@') || domain != &domain.to_lowercase()
+        }) {
+            return invalid("domains must be lowercase and may not contain an @");
+        }
+
+        Ok(())
+    }
+
+    /// The connection as an OIDC provider, so that it signs users in like any other provider.
+    pub fn provider(self) -> ProviderConfig {
+        ProviderConfig {
+            name: self.name,
+            provider: Provider::Oidc(OidcProvider {
+                issuer_url: self.issuer_url,
+                response_mode: None,
+                client_auth: ClientAuth::Secret,
+                allowed_tenants: Vec::new(),
+            }),
+            scopes: self.scopes,
+            style: Style {
+                display_name: self.organization,
+                background_color: String::new(),
+                background_color_hover: String::new(),
+                icon: String::new(),
+            },
+            claims: None,
+            client_id: Some(self.client_id),
+            enabled: true,
+            client_secret: self.client_secret,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ConnectionRow {
+    definition: String,
+    client_secret: Option<String>,
+}
+
+impl TryFrom<ConnectionRow> for EnterpriseConnection {
+    type Error = Error;
+
+    fn try_from(row: ConnectionRow) -> Result<Self, Self::Error> {
+        let mut connection = serde_json::from_str::<EnterpriseConnection>(&row.definition)
+            .map_err(Error::SerdeJson)?;
+
+        connection.client_secret = row
+            .client_secret
+            .map(|secret| serde_json::from_str(&secret))
+            .transpose()
+            .map_err(Error::SerdeJson)?;
+
+        Ok(connection)
+    }
+}
+
+pub async fn get_connection(
+    db: &d1::Database,
+    name: &str,
+) -> Result<Option<EnterpriseConnection>, Error> {
+    d1::query!(
+        db,
+        r#"
+SELECT definition, client_secret
+FROM enterprise_connections
+WHERE name = ?
+        "#,
+        name,
+    )
+    .map_err(Error::D1)?
+    .first::<ConnectionRow>(None)
+    .await
+    .map_err(Error::D1)?
+    .map(EnterpriseConnection::try_from)
+    .transpose()
+}
+
+/// Find the connection of the organization that owns the domain of an email address.
+pub async fn find_connection_by_email(
+    db: &d1::Database,
+    email: &str,
+) -> Result<Option<EnterpriseConnection>, Error> {
+    let Some((_, domain)) = email.rsplit_once('@') else {
+        return Ok(None);
+    };
+
+    d1::query!(
+        db,
+        r#"
+SELECT c.definition, c.client_secret
+FROM enterprise_connection_domains d
+JOIN enterprise_connections c ON c.name = d.connection
+WHERE d.domain = ?
+        "#,
+        domain.to_lowercase(),
+    )
+    .map_err(Error::D1)?
+    .first::<ConnectionRow>(None)
+    .await
+    .map_err(Error::D1)?
+    .map(EnterpriseConnection::try_from)
+    .transpose()
+}
+
+#[derive(Deserialize)]
+struct ClientSecretRow {
+    name: String,
+    client_secret: String,
+}
+
+/// Re-encrypt client secrets that were encrypted with an older key, so that the old key can be
+/// removed after a rotation.
+pub async fn reencrypt_client_secrets(state: &AppState) -> Result<(), Error> {
+    let version = crypto::current_version(&state.env)?;
+
+    let rows = d1::query!(
+        &state.db,
+        r#"
+SELECT name, client_secret
+FROM enterprise_connections
+WHERE json_extract(client_secret, '$.version') != ?
+        "#,
+        version,
+    )
+    .map_err(Error::D1)?
+    .all()
+    .await
+    .map_err(Error::D1)?
+    .results::<ClientSecretRow>()
+    .map_err(Error::D1)?;
+
+    for row in rows {
+        // A single secret that can't be re-encrypted shouldn't hold back the others
+        if let Err(err) = reencrypt_client_secret(state, &row).await {
+            console_error!(
+                "failed to re-encrypt the client secret of {}: {err:?}",
+                row.name
+            );
+        }
+    }
+
+    Ok(())
+}
+
+async fn reencrypt_client_secret(state: &AppState, row: &ClientSecretRow) -> Result<(), Error> {
+    let encrypted =
+        serde_json::from_str::<Encrypted>(&row.client_secret).map_err(Error::SerdeJson)?;
+    let encrypted =
+        crypto::reencrypt(&state.env, &client_secret_key(&row.name), &encrypted).await?;
+    let client_secret = serde_json::to_string(&encrypted).map_err(Error::SerdeJson)?;
+
+    // The secret is left alone when it was replaced after it was read
+    d1::query!(
+        &state.db,
+        r#"
+UPDATE enterprise_connections
+SET client_secret = ?1
+WHERE name = ?2 AND client_secret = ?3
+        "#,
+        client_secret,
+        row.name,
+        row.client_secret,
+    )
+    .map_err(Error::D1)?
+    .run()
+    .await
+    .map_err(Error::D1)?;
+
+    Ok(())
+}
+
+/// List all connections sorted by organization and name.
+pub async fn list_connections(db: &d1::Database) -> Result<Vec<EnterpriseConnection>, Error> {
+    d1::query!(
+        db,
+        r#"
+SELECT definition, client_secret
+FROM enterprise_connections
+ORDER BY organization, name
+        "#
+    )
+    .all()
+    .await
+    .map_err(Error::D1)?
+    .results::<ConnectionRow>()
+    .map_err(Error::D1)?
+    .into_iter()
+    .map(EnterpriseConnection::try_from)
+    .collect()
+}
+
+pub async fn upsert_connection(
+    db: &d1::Database,
+    connection: &EnterpriseConnection,
+) -> Result<(), Error> {
+    let definition = serde_json::to_string(connection).map_err(Error::SerdeJson)?;
+    let client_secret = connection
+        .client_secret
+        .as_ref()
+        .map(serde_json::to_string)
+        .transpose()
+        .map_err(Error::SerdeJson)?;
+
+    let mut statements = vec![
+        d1::query!(
+            db,
+            r#"
+INSERT INTO enterprise_connections (name, organization, definition, client_secret, created_at, updated_at)
+VALUES (?1, ?2, ?3, ?4, ?5, ?5)
+ON CONFLICT (name) DO UPDATE SET
+    organization = excluded.organization,
+    definition = excluded.definition,
+    client_secret = COALESCE(excluded.client_secret, enterprise_connections.client_secret),
+    updated_at = excluded.updated_at
+            "#,
+            connection.name,
+            connection.organization,
+            definition,
+            client_secret,
+            Utc::now(),
+        )
+        .map_err(Error::D1)?,
+        d1::query!(
+            db,
+            r#"
+DELETE FROM enterprise_connection_domains
+WHERE connection = ?
+            "#,
+            connection.name,
+        )
+        .map_err(Error::D1)?,
+    ];
+
+    for domain in &connection.domains {
+        statements.push(
+            d1::query!(
+                db,
+                r#"
+INSERT INTO enterprise_connection_domains (domain, connection)
+VALUES (?1, ?2)
+                "#,
+                domain,
+                connection.name,
+            )
+            .map_err(Error::D1)?,
+        );
+    }
+
+    db.batch(statements).await.map_err(Error::D1)?;
+
+    Ok(())
+}
+
+/// Find a domain of the connection that is already owned by another connection.
+pub async fn find_taken_domain(
+    db: &d1::Database,
+    connection: &EnterpriseConnection,
+) -> Result<Option<String>, Error> {
+    for domain in &connection.domains {
+        let owner = d1::query!(
+            db,
+            r#"
+SELECT connection
+FROM enterprise_connection_domains
+WHERE domain = ?
+            "#,
+            domain,
+        )
+        .map_err(Error::D1)?
+        .first::<String>(Some("connection"))
+        .await
+        .map_err(Error::D1)?;
+
+        if owner.map_or(false, |owner| owner != connection.name) {
+            return Ok(Some(domain.clone()));
+        }
+    }
+
+    Ok(None)
+}
+
+pub async fn delete_connection(db: &d1::Database, name: &str) -> Result<bool, Error> {
+    let deleted = d1::query!(
+        db,
+        r#"
+DELETE FROM enterprise_connections
+WHERE name = ?
+RETURNING name
+        "#,
+        name,
+    )
+    .map_err(Error::D1)?
+    .first::<String>(Some("name"))
+    .await
+    .map_err(Error::D1)?;
+
+    Ok(deleted.is_some())
+}
@@ -11,6 +11,7 @@ use openidconnect::{
     ClaimsVerificationError, DiscoveryError, JsonWebTokenError, SigningError, UserInfoError,
 };
 use std::fmt;
+use wasm_bindgen::JsValue;
 use worker::kv::KvError;
 
 #[derive(Debug)]
@@ -28,10 +29,39 @@ pub enum Error {
     ClaimsVerificationError(ClaimsVerificationError),
     SigningError(SigningError),
     ConfigurationError(ConfigurationError),
+    SerdeJson(serde_json::Error),
+    Crypto(JsValue),
+    MissingEncryptionKey,
+    InvalidUserProfile,
     InvalidConnection,
     InvalidAccessToken,
     MissingPermission,
     TokensNotFound,
+    UserNotFound,
+    InvalidProvider(String),
+    ProviderNotFound,
+    MissingClientCredentials,
+    MailerNotConfigured,
+    SmsSenderNotConfigured,
+    TenantNotAllowed,
+    ConnectionNotFound,
+    InvalidSamlResponse(String),
+    InvalidCredentials,
+    UserBlocked,
+    EmailNotVerified,
+    WeakPassword,
+    InvalidLink,
+    InvalidPasskey(String),
+    PasskeyNotFound,
+    InvalidMfaCode,
+    MfaLocked,
+    MfaRequired,
+    MfaNotEnrolled,
+    InvalidPhoneNumber,
+    RateLimited,
+    GrantNotFound,
+    SessionNotFound,
+    TokenSetNotFound,
 }
 
 unsafe impl Send for Error {}
@@ -51,11 +81,45 @@ impl fmt::Display for Error {
             | Self::MissingIdToken
             | Self::ClaimsVerificationError(_)
             | Self::SigningError(_)
-            | Self::ConfigurationError(_) => write!(f, "internal error"),
+            | Self::ConfigurationError(_)
+            | Self::SerdeJson(_)
+            | Self::Crypto(_)
+            | Self::MissingEncryptionKey
+            | Self::InvalidUserProfile => write!(f, "internal error"),
             Self::InvalidConnection => write!(f, "invalid connection"),
             Self::InvalidAccessToken => write!(f, "invalid access token"),
             Self::MissingPermission => write!(f, "missing permission"),
             Self::TokensNotFound => write!(f, "tokens not found"),
+            Self::UserNotFound => write!(f, "user not found"),
+            Self::InvalidProvider(reason) => write!(f, "invalid provider: {reason}"),
+            Self::ProviderNotFound => write!(f, "provider not found"),
+            Self::MissingClientCredentials => write!(f, "provider client credentials not set"),
+            Self::MailerNotConfigured => write!(f, "email delivery is not configured"),
+            Self::SmsSenderNotConfigured => write!(f, "text messages are not configured"),
+            Self::TenantNotAllowed => write!(f, "tenant not allowed"),
+            Self::ConnectionNotFound => write!(f, "connection not found"),
+            Self::InvalidSamlResponse(reason) => write!(f, "invalid saml response: {reason}"),
+            Self::InvalidCredentials => write!(f, "invalid email or password"),
+            Self::UserBlocked => write!(f, "user is blocked"),
+            Self::EmailNotVerified => write!(f, "email address is not verified"),
+            Self::WeakPassword => write!(f, "password must be at least 8 characters"),
+            Self::InvalidLink => write!(f, "invalid or expired link"),
+            Self::InvalidPasskey(reason) => write!(f, "invalid passkey: {reason}"),
+            Self::PasskeyNotFound => write!(f, "passkey not found"),
+            Self::InvalidMfaCode => write!(f, "invalid code"),
+            Self::MfaLocked => write!(f, "too many wrong codes, try again later"),
+            Self::MfaRequired => write!(f, "a TOTP or recovery code is required"),
+            Self::MfaNotEnrolled => write!(f, "multi-factor authentication is not enrolled"),
+            Self::InvalidPhoneNumber => {
+                write!(
+                    f,
+                    "phone number must include the country code, such as +14155552671"
+                )
+            }
+            Self::RateLimited => write!(f, "too many codes sent, try again later"),
+            Self::GrantNotFound => write!(f, "grant not found"),
+            Self::SessionNotFound => write!(f, "session not found"),
+            Self::TokenSetNotFound => write!(f, "token not found"),
         }
     }
 }
@@ -83,11 +147,43 @@ impl IntoResponse for Error {
             | Self::MissingIdToken
             | Self::ClaimsVerificationError(_)
             | Self::SigningError(_)
-            | Self::ConfigurationError(_) => (StatusCode::INTERNAL_SERVER_ERROR, s).into_response(),
-            Self::InvalidConnection | Self::InvalidAccessToken | Self::MissingPermission => {
-                (StatusCode::BAD_REQUEST, s).into_response()
+            | Self::ConfigurationError(_)
+            | Self::SerdeJson(_)
+            | Self::Crypto(_)
+            | Self::MissingEncryptionKey
+            | Self::InvalidUserProfile
+            | Self::MissingClientCredentials
+            | Self::MailerNotConfigured
+            | Self::SmsSenderNotConfigured => {
+                (StatusCode::INTERNAL_SERVER_ERROR, s).into_response()
+            }
+            Self::InvalidConnection
+            | Self::InvalidAccessToken
+            | Self::MissingPermission
+            | Self::InvalidProvider(_)
+            | Self::InvalidSamlResponse(_)
+            | Self::WeakPassword
+            | Self::InvalidLink
+            | Self::InvalidPasskey(_)
+            | Self::InvalidMfaCode
+            | Self::InvalidPhoneNumber => (StatusCode::BAD_REQUEST, s).into_response(),
+            Self::TokensNotFound
+            | Self::UserNotFound
+            | Self::ProviderNotFound
+            | Self::ConnectionNotFound
+            | Self::PasskeyNotFound
+            | Self::GrantNotFound
+            | Self::SessionNotFound
+            | Self::TokenSetNotFound
+            | Self::MfaNotEnrolled => (StatusCode::NOT_FOUND, s).into_response(),
+            Self::InvalidCredentials => (StatusCode::UNAUTHORIZED, s).into_response(),
+            Self::TenantNotAllowed
+            | Self::UserBlocked
+            | Self::EmailNotVerified
+            | Self::MfaRequired => (StatusCode::FORBIDDEN, s).into_response(),
+            Self::MfaLocked | Self::RateLimited => {
+                (StatusCode::TOO_MANY_REQUESTS, s).into_response()
             }
-            Self::TokensNotFound => (StatusCode::NOT_FOUND, s).into_response(),
         }
     }
 }
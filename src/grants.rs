@@ -0,0 +1,176 @@
+//! Scopes users approved for applications, so that they are only asked for consent once.
+
+use std::collections::HashSet;
+
+use chrono::{DateTime, Utc};
+use oauth2::{ClientId, Scope};
+use serde::{Deserialize, Serialize};
+
+use crate::{d1, error::Error};
+
+#[derive(Deserialize)]
+pub struct Grant {
+    pub client_id: ClientId,
+    /// Name of the application.
+    pub name: String,
+    /// Space separated scopes.
+    scopes: String,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl Grant {
+    pub fn scopes(&self) -> HashSet<Scope> {
+        self.scopes
+            .split_whitespace()
+            .map(|s| Scope::new(s.to_string()))
+            .collect()
+    }
+}
+
+#[derive(Serialize)]
+pub struct GrantInfo {
+    pub client_id: ClientId,
+    pub name: String,
+    pub scopes: Vec<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl From<Grant> for GrantInfo {
+    fn from(grant: Grant) -> Self {
+        Self {
+            scopes: grant
+                .scopes
+                .split_whitespace()
+                .map(str::to_string)
+                .collect(),
+            client_id: grant.client_id,
+            name: grant.name,
+            created_at: grant.created_at,
+            updated_at: grant.updated_at,
+        }
+    }
+}
+
+pub async fn get_grant(
+    db: &d1::Database,
+    user_id: &str,
+    client_id: &ClientId,
+) -> Result<Option<Grant>, Error> {
+    d1::query!(
+        db,
+        r#"
+SELECT user_grants.client_id, applications.name, user_grants.scopes, user_grants.created_at, user_grants.updated_at
+FROM user_grants
+JOIN applications ON applications.client_id = user_grants.client_id
+WHERE user_grants.user_id = ? AND user_grants.client_id = ?
+        "#,
+        user_id,
+        client_id,
+    )
+    .map_err(Error::D1)?
+    .first::<Grant>(None)
+    .await
+    .map_err(Error::D1)
+}
+
+pub async fn list_grants(db: &d1::Database, user_id: &str) -> Result<Vec<Grant>, Error> {
+    d1::query!(
+        db,
+        r#"
+SELECT user_grants.client_id, applications.name, user_grants.scopes, user_grants.created_at, user_grants.updated_at
+FROM user_grants
+JOIN applications ON applications.client_id = user_grants.client_id
+WHERE user_grants.user_id = ?
+ORDER BY user_grants.created_at
+        "#,
+        user_id,
+    )
+    .map_err(Error::D1)?
+    .all()
+    .await
+    .map_err(Error::D1)?
+    .results::<Grant>()
+    .map_err(Error::D1)
+}
+
+/// Whether the user approved every scope for the application.
+pub async fn is_granted(
+    db: &d1::Database,
+    user_id: &str,
+    client_id: &ClientId,
+    scopes: &HashSet<Scope>,
+) -> Result<bool, Error> {
+    Ok(get_grant(db, user_id, client_id)
+        .await?
+        .map_or(false, |grant| scopes.is_subset(&grant.scopes())))
+}
+
+/// Remember that the user approved scopes for an application, in addition to the scopes they
+/// approved before.
+pub async fn save_grant(
+    db: &d1::Database,
+    user_id: &str,
+    client_id: &ClientId,
+    scopes: &HashSet<Scope>,
+) -> Result<(), Error> {
+    let mut granted = get_grant(db, user_id, client_id)
+        .await?
+        .map(|grant| grant.scopes())
+        .unwrap_or_default();
+    granted.extend(scopes.iter().cloned());
+
+    let mut granted = granted
+        .iter()
+        .map(|scope| scope.as_str())
+        .collect::<Vec<_>>();
+    granted.sort_unstable();
+
+    let now = Utc::now();
+
+    d1::query!(
+        db,
+        r#"
+INSERT INTO user_grants (user_id, client_id, scopes, created_at, updated_at)
+VALUES (?1, ?2, ?3, ?4, ?4)
+ON CONFLICT (user_id, client_id) DO UPDATE SET
+    scopes = excluded.scopes,
+    updated_at = excluded.updated_at
+        "#,
+        user_id,
+        client_id,
+        granted.join(" "),
+        now,
+    )
+    .map_err(Error::D1)?
+    .run()
+    .await
+    .map_err(Error::D1)?;
+
+    Ok(())
+}
+
+/// Remove the grant of an application, returning whether the user had approved it.
+pub async fn delete_grant(
+    db: &d1::Database,
+    user_id: &str,
+    client_id: &ClientId,
+) -> Result<bool, Error> {
+    let deleted = d1::query!(
+        db,
+        r#"
+DELETE FROM user_grants
+WHERE user_id = ? AND client_id = ?
+RETURNING client_id
+        "#,
+        user_id,
+        client_id,
+    )
+    .map_err(Error::D1)?
+    .first::<String>(Some("client_id"))
+    .await
+    .map_err(Error::D1)?;
+
+    Ok(deleted.is_some())
+}
@@ -9,28 +9,49 @@ use axum::{
     Json, Router, TypedHeader,
 };
 use futures::channel::oneshot;
-use oauth2::Scope;
 use rand::{
     distributions::{Alphanumeric, DistString},
     thread_rng,
 };
 use reqwest::header;
+use serde_json::json;
 use tower::Service;
-use worker::{body::Body, event, kv::KvStore, Context, Env, ScheduleContext, ScheduledEvent};
+use worker::{
+    body::Body, console_error, event, kv::KvStore, Context, Env, ScheduleContext, ScheduledEvent,
+};
 
+mod account;
+mod admin;
 mod applications;
+mod audit;
 mod auth;
+mod backchannel;
+mod crypto;
 mod d1;
+mod enterprise;
 mod error;
+mod grants;
 mod keys;
+mod login;
+mod mailer;
+mod mfa;
 mod oauth;
 mod oidc;
+mod password;
 mod providers;
+mod rate_limit;
+mod saml;
+mod sessions;
+mod sms;
 mod tokens;
+mod userinfo;
 mod users;
+mod webauthn;
 mod well_known;
 
-use auth::states::TokenMetadata;
+use audit::AuditEvent;
+use auth::connections::{get_connection_tokens, reencrypt_connection_tokens};
+use backchannel::retry_logout_notifications;
 use error::Error;
 use keys::{get_jwks, rotate_keys};
 
@@ -91,31 +112,34 @@ async fn application(
     rx.await.unwrap()
 }
 
+/// Tokens of the provider the signed in user signed up with. Deprecated in favor of
+/// `/users/:user_id/connections/:provider/token`, which works for every connection.
 async fn users_impl(state: AppState, authorization: Authorization<Bearer>) -> impl IntoResponse {
-    let token = authorization.token();
-
-    let token_meta = state
-        .kv
-        .get(&format!("token:access:{}", token))
-        .json::<TokenMetadata>()
-        .await
-        .map_err(Error::Kv)?
-        .ok_or(Error::InvalidAccessToken)?;
+    let token_meta = tokens::get_access_token(&state, authorization.token()).await?;
 
-    if !token_meta
-        .scopes
-        .contains(&Scope::new("read:user_idp_tokens".to_string()))
-    {
+    if !token_meta.has_scope("read:user_idp_tokens") {
         return Err(Error::MissingPermission);
     }
 
-    let tokens = state
-        .kv
-        .get(&format!("connection:{}:tokens", token_meta.client_id))
-        .json()
-        .await
-        .map_err(Error::Kv)?
-        .ok_or(Error::TokensNotFound)?;
+    let (provider, _) = token_meta
+        .user_id
+        .split_once('|')
+        .ok_or(Error::InvalidConnection)?;
+
+    let tokens = get_connection_tokens(&state, &token_meta.user_id, provider).await?;
+
+    audit::record(
+        &state.db,
+        AuditEvent {
+            action: audit::CONNECTION_TOKEN_READ,
+            actor_id: Some(&token_meta.user_id),
+            client_id: Some(&token_meta.client_id),
+            target_id: Some(&token_meta.user_id),
+            details: Some(json!({ "provider": provider })),
+        },
+    )
+    .await
+    .map_err(Error::D1)?;
 
     Ok(Json(tokens))
 }
@@ -149,7 +173,10 @@ fn router() -> Router<AppState, Body> {
     Router::new()
         .route("/application", post(application))
         .route("/users", get(users))
+        .route("/userinfo", get(userinfo::userinfo))
         .route("/jwks", get(jwks))
+        .merge(account::router())
+        .merge(admin::router())
         .nest("/oauth", auth::router())
         .nest("/.well-known", well_known::router())
 }
@@ -170,5 +197,24 @@ async fn fetch(req: Request<Body>, env: Env, _ctx: Context) -> worker::Result<Re
 #[event(scheduled)]
 async fn scheduled(_event: ScheduledEvent, env: Env, _ctx: ScheduleContext) {
     let state = AppState::new(env);
-    rotate_keys(&state).await.expect("failed to rotate keys");
+
+    // The jobs are independent, so one failing doesn't keep the others from running
+    if let Err(err) = rotate_keys(&state).await {
+        console_error!("failed to rotate keys: {err:?}");
+    }
+    if let Err(err) = reencrypt_connection_tokens(&state).await {
+        console_error!("failed to re-encrypt connection tokens: {err:?}");
+    }
+    if let Err(err) = providers::reencrypt_client_secrets(&state).await {
+        console_error!("failed to re-encrypt provider client secrets: {err:?}");
+    }
+    if let Err(err) = enterprise::reencrypt_client_secrets(&state).await {
+        console_error!("failed to re-encrypt enterprise connection client secrets: {err:?}");
+    }
+    if let Err(err) = mfa::reencrypt_secrets(&state).await {
+        console_error!("failed to re-encrypt TOTP secrets: {err:?}");
+    }
+    if let Err(err) = retry_logout_notifications(&state).await {
+        console_error!("failed to retry logout notifications: {err:?}");
+    }
 }
@@ -0,0 +1,65 @@
+use axum::response::Html;
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+use crate::{
+    error::Error,
+    providers::{is_color, list_providers, ProviderConfig},
+    AppState,
+};
+
+pub fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// Used for colors of providers that were stored before colors were validated.
+const FALLBACK_COLOR: &str = "#333333";
+
+fn color(value: &str) -> &str {
+    if is_color(value) {
+        value
+    } else {
+        FALLBACK_COLOR
+    }
+}
+
+fn provider_button(provider: &ProviderConfig) -> String {
+    let name = escape_html(&provider.name);
+    let display_name = escape_html(&provider.style.display_name);
+    let background_color = color(&provider.style.background_color);
+    let background_color_hover = color(&provider.style.background_color_hover);
+    // Icons of providers created at runtime can't be trusted, and scripts in SVG don't run when it
+    // is loaded as an image
+    let icon = STANDARD.encode(&provider.style.icon);
+
+    format!(
+        r#"
+<button
+    class="oauth-provider"
+    onclick="oauth('{name}')"
+    style="--provider-bg-color: {background_color}; --provider-bg-color-hover: {background_color_hover}"
+>
+    <div class="oauth-icon">
+        <img src="data:image/svg+xml;base64,{icon}" alt="" />
+    </div>
+    <div class="oauth-name">{display_name}</div>
+</button>
+        "#
+    )
+}
+
+pub async fn login_page(state: &AppState) -> Result<Html<String>, Error> {
+    let buttons = list_providers(&state.db)
+        .await?
+        .iter()
+        .filter(|provider| provider.enabled)
+        .map(provider_button)
+        .collect::<String>();
+
+    let login = include_str!("../public/login.html").replace("<!-- OAUTH_PROVIDERS -->", &buttons);
+
+    Ok(Html(login))
+}
@@ -0,0 +1,120 @@
+#[cfg(test)]
+use std::{cell::RefCell, rc::Rc};
+
+use serde_json::json;
+use worker::{console_log, Env};
+
+use crate::{error::Error, http_client};
+
+const MAILCHANNELS_URL: &str = "https://api.mailchannels.net/tx/v1/send";
+
+#[derive(Clone, Debug)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// Sends transactional emails, such as email address verifications.
+pub enum Mailer {
+    /// Sends emails through MailChannels from the `MAIL_FROM` address, authenticated with the
+    /// `MAILCHANNELS_API_KEY` secret.
+    MailChannels { from: String, api_key: String },
+    /// Only logs emails, for local development. Requires `MAIL_LOG` to be `true`, so that a missing
+    /// `MAIL_FROM` doesn't leave links in the logs in production.
+    Log,
+    /// Keeps emails in memory, for tests.
+    #[cfg(test)]
+    Memory(Rc<RefCell<Vec<Email>>>),
+}
+
+impl Mailer {
+    pub fn new(env: &Env) -> Result<Self, Error> {
+        Self::from_config(
+            env.var("MAIL_FROM").ok().map(|from| from.to_string()),
+            env.secret("MAILCHANNELS_API_KEY")
+                .ok()
+                .map(|secret| secret.to_string()),
+            env.var("MAIL_LOG")
+                .map_or(false, |var| var.to_string() == "true"),
+        )
+    }
+
+    /// MailChannels when it is configured, otherwise emails are only logged when that is enabled.
+    /// MailChannels being partly configured is an error rather than a reason to log emails.
+    fn from_config(
+        from: Option<String>,
+        api_key: Option<String>,
+        log: bool,
+    ) -> Result<Self, Error> {
+        match (from, api_key) {
+            (Some(from), Some(api_key)) => Ok(Self::MailChannels { from, api_key }),
+            (None, None) if log => Ok(Self::Log),
+            _ => Err(Error::MailerNotConfigured),
+        }
+    }
+
+    pub async fn send(&self, email: Email) -> Result<(), Error> {
+        match self {
+            Self::MailChannels { from, api_key } => {
+                http_client()
+                    .post(MAILCHANNELS_URL)
+                    .header("X-Api-Key", api_key)
+                    .json(&json!({
+                        "personalizations": [{ "to": [{ "email": email.to }] }],
+                        "from": { "email": from },
+                        "subject": email.subject,
+                        "content": [{ "type": "text/plain", "value": email.body }],
+                    }))
+                    .send()
+                    .await?
+                    .error_for_status()?;
+            }
+            Self::Log => console_log!("email to {}: {}\n\n{}", email.to, email.subject, email.body),
+            #[cfg(test)]
+            Self::Memory(outbox) => outbox.borrow_mut().push(email),
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sends_through_mailchannels() {
+        let mailer =
+            Mailer::from_config(Some("auth@example.com".into()), Some("key".into()), true).unwrap();
+
+        assert!(matches!(
+            mailer,
+            Mailer::MailChannels { from, api_key } if from == "auth@example.com" && api_key == "key"
+        ));
+    }
+
+    #[test]
+    fn rejects_partial_mailchannels_config() {
+        assert!(matches!(
+            Mailer::from_config(Some("auth@example.com".into()), None, true),
+            Err(Error::MailerNotConfigured)
+        ));
+        assert!(matches!(
+            Mailer::from_config(None, Some("key".into()), true),
+            Err(Error::MailerNotConfigured)
+        ));
+    }
+
+    #[test]
+    fn logs_only_when_enabled() {
+        assert!(matches!(
+            Mailer::from_config(None, None, true),
+            Ok(Mailer::Log)
+        ));
+        assert!(matches!(
+            Mailer::from_config(None, None, false),
+            Err(Error::MailerNotConfigured)
+        ));
+    }
+}
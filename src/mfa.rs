@@ -0,0 +1,603 @@
+//! Time-based one-time passwords (RFC 6238) and recovery codes, used as a second factor.
+
+use chrono::{Duration, Utc};
+use hmac::{Hmac, Mac};
+use oauth2::ClientId;
+use rand::{rngs::OsRng, RngCore};
+use reqwest::Url;
+use serde::Deserialize;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use worker::console_error;
+
+use crate::{
+    applications,
+    crypto::{self, Encrypted},
+    d1,
+    error::Error,
+    gen_string, AppState,
+};
+
+/// Value of `users.multifactor` for users that enrolled TOTP.
+pub const TOTP: &str = "totp";
+
+const DIGITS: u32 = 6;
+const PERIOD_SECONDS: i64 = 30;
+
+/// Steps before and after the current one that are accepted, to allow for clock drift.
+const SKEW_STEPS: i64 = 1;
+
+/// 160 bits, as recommended by RFC 4226.
+const SECRET_LEN: usize = 20;
+
+/// Wrong codes after which codes are refused for `LOCKOUT_MINUTES`.
+const MAX_FAILED_ATTEMPTS: u32 = 5;
+const LOCKOUT_MINUTES: i64 = 15;
+
+/// Maximum number of secrets re-encrypted per scheduled run, to stay within subrequest limits.
+const REENCRYPT_BATCH_SIZE: u32 = 50;
+
+const RECOVERY_CODES: usize = 10;
+const RECOVERY_CODE_LEN: usize = 10;
+
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+fn base32_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::new();
+
+    for chunk in bytes.chunks(5) {
+        let mut buf = [0u8; 5];
+        buf[..chunk.len()].copy_from_slice(chunk);
+        let bits = buf.iter().fold(0u64, |acc, &b| acc << 8 | b as u64);
+
+        for i in 0..(chunk.len() * 8 + 4) / 5 {
+            encoded.push(BASE32_ALPHABET[(bits >> (35 - i * 5) & 0x1f) as usize] as char);
+        }
+    }
+
+    encoded
+}
+
+fn base32_decode(s: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::new();
+    let (mut bits, mut len) = (0u64, 0);
+
+    for c in s.bytes().filter(|c| *c != b'=') {
+        let value = BASE32_ALPHABET
+            .iter()
+            .position(|a| *a == c.to_ascii_uppercase())?;
+
+        bits = bits << 5 | value as u64;
+        len += 5;
+
+        if len >= 8 {
+            len -= 8;
+            decoded.push((bits >> len) as u8);
+        }
+    }
+
+    Some(decoded)
+}
+
+pub fn generate_secret() -> String {
+    let mut secret = [0u8; SECRET_LEN];
+    OsRng.fill_bytes(&mut secret);
+
+    base32_encode(&secret)
+}
+
+/// URI for authenticator apps, which is usually shown as a QR code.
+///
+/// See <https://github.com/google/google-authenticator/wiki/Key-Uri-Format>.
+pub fn otpauth_uri(secret: &str, account: &str) -> String {
+    let issuer = Url::parse(env!("DOMAIN"))
+        .unwrap()
+        .host_str()
+        .unwrap()
+        .to_string();
+
+    let mut url = Url::parse("otpauth://totp/").unwrap();
+    url.set_path(&format!("{issuer}:{account}"));
+    url.query_pairs_mut()
+        .append_pair("secret", secret)
+        .append_pair("issuer", &issuer)
+        .append_pair("algorithm", "SHA1")
+        .append_pair("digits", &DIGITS.to_string())
+        .append_pair("period", &PERIOD_SECONDS.to_string());
+
+    url.to_string()
+}
+
+/// HOTP (RFC 4226) with HMAC-SHA1 and dynamic truncation.
+fn hotp(key: &[u8], counter: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let code = u32::from_be_bytes(hash[offset..offset + 4].try_into().unwrap()) & 0x7fff_ffff;
+
+    code % 10u32.pow(DIGITS)
+}
+
+/// Check a code against the time steps around a Unix timestamp, returning the step it matches.
+/// Steps up to the last used step are rejected, so that a code can't be used twice.
+fn verify_code(
+    secret: &str,
+    code: &str,
+    last_used_step: Option<i64>,
+    timestamp: i64,
+) -> Option<i64> {
+    let key = base32_decode(secret)?;
+
+    let code = code.replace(' ', "");
+    if code.len() != DIGITS as usize || !code.bytes().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let code = code.parse::<u32>().ok()?;
+
+    let now = timestamp / PERIOD_SECONDS;
+
+    (now - SKEW_STEPS..=now + SKEW_STEPS)
+        .filter(|step| last_used_step.map_or(true, |last| *step > last))
+        .find(|step| hotp(&key, *step as u64) == code)
+}
+
+/// Check a code for a secret that is being enrolled, returning the step it matches.
+pub fn verify_enrollment(secret: &str, code: &str) -> Option<i64> {
+    verify_code(secret, code.trim(), None, Utc::now().timestamp())
+}
+
+fn secret_key(user_id: &str) -> String {
+    format!("totp:{user_id}")
+}
+
+fn hash_recovery_code(code: &str) -> String {
+    format!("{:x}", Sha256::digest(code.trim().to_lowercase()))
+}
+
+#[derive(Deserialize)]
+struct TotpRow {
+    secret: String,
+    last_used_step: Option<i64>,
+}
+
+async fn get_totp(db: &d1::Database, user_id: &str) -> Result<Option<TotpRow>, Error> {
+    d1::query!(
+        db,
+        r#"
+SELECT secret, last_used_step
+FROM totp_credentials
+WHERE user_id = ?
+        "#,
+        user_id,
+    )
+    .map_err(Error::D1)?
+    .first::<TotpRow>(None)
+    .await
+    .map_err(Error::D1)
+}
+
+pub async fn has_totp(db: &d1::Database, user_id: &str) -> Result<bool, Error> {
+    Ok(get_totp(db, user_id).await?.is_some())
+}
+
+/// Verify a code of a user that enrolled TOTP.
+pub async fn verify_totp(state: &AppState, user_id: &str, code: &str) -> Result<bool, Error> {
+    let Some(row) = get_totp(&state.db, user_id).await? else {
+        return Ok(false);
+    };
+
+    let encrypted = serde_json::from_str::<Encrypted>(&row.secret).map_err(Error::SerdeJson)?;
+    let secret = crypto::decrypt::<String>(&state.env, &secret_key(user_id), &encrypted).await?;
+
+    let Some(step) = verify_code(
+        &secret,
+        code.trim(),
+        row.last_used_step,
+        Utc::now().timestamp(),
+    ) else {
+        return Ok(false);
+    };
+
+    // Only one of concurrent requests with the same code can move the step forward
+    let updated = d1::query!(
+        &state.db,
+        r#"
+UPDATE totp_credentials
+SET last_used_step = ?1
+WHERE user_id = ?2 AND (last_used_step IS NULL OR last_used_step < ?1)
+RETURNING user_id
+        "#,
+        step,
+        user_id,
+    )
+    .map_err(Error::D1)?
+    .first::<String>(Some("user_id"))
+    .await
+    .map_err(Error::D1)?;
+
+    Ok(updated.is_some())
+}
+
+/// Count an attempt at a TOTP or recovery code before it is checked, returning whether codes can
+/// be checked at all. Attempts are counted per user in the database before checking, so that
+/// neither starting over nor parallel requests allow more guesses. Users without TOTP can't
+/// attempt codes.
+pub async fn count_attempt(db: &d1::Database, user_id: &str) -> Result<bool, Error> {
+    let now = Utc::now();
+
+    let counted = d1::query!(
+        db,
+        r#"
+UPDATE totp_credentials
+SET
+    failed_attempts = CASE WHEN failed_attempts + 1 >= ?1 THEN 0 ELSE failed_attempts + 1 END,
+    locked_until = CASE WHEN failed_attempts + 1 >= ?1 THEN ?2 ELSE locked_until END
+WHERE user_id = ?3 AND (locked_until IS NULL OR locked_until <= ?4)
+RETURNING user_id
+        "#,
+        MAX_FAILED_ATTEMPTS,
+        now + Duration::minutes(LOCKOUT_MINUTES),
+        user_id,
+        now,
+    )
+    .map_err(Error::D1)?
+    .first::<String>(Some("user_id"))
+    .await
+    .map_err(Error::D1)?;
+
+    Ok(counted.is_some())
+}
+
+/// Forget the attempts before a correct code.
+pub async fn reset_failed_attempts(db: &d1::Database, user_id: &str) -> Result<(), Error> {
+    d1::query!(
+        db,
+        r#"
+UPDATE totp_credentials
+SET failed_attempts = 0, locked_until = NULL
+WHERE user_id = ?
+        "#,
+        user_id,
+    )
+    .map_err(Error::D1)?
+    .run()
+    .await
+    .map_err(Error::D1)?;
+
+    Ok(())
+}
+
+/// Store a confirmed TOTP secret, replacing any previous one, and return new recovery codes. The
+/// step the secret was confirmed with can't be used to sign in.
+pub async fn enroll_totp(
+    state: &AppState,
+    user_id: &str,
+    secret: &str,
+    step: i64,
+) -> Result<Vec<String>, Error> {
+    let encrypted = crypto::encrypt(&state.env, &secret_key(user_id), &secret).await?;
+    let encrypted = serde_json::to_string(&encrypted).map_err(Error::SerdeJson)?;
+    let now = Utc::now();
+
+    state
+        .db
+        .batch(vec![
+            d1::query!(
+                &state.db,
+                r#"
+INSERT INTO totp_credentials (user_id, secret, last_used_step, created_at)
+VALUES (?1, ?2, ?3, ?4)
+ON CONFLICT (user_id) DO UPDATE SET
+    secret = excluded.secret,
+    last_used_step = excluded.last_used_step,
+    failed_attempts = 0,
+    locked_until = NULL,
+    created_at = excluded.created_at
+                "#,
+                user_id,
+                encrypted,
+                step,
+                now,
+            )
+            .map_err(Error::D1)?,
+            d1::query!(
+                &state.db,
+                r#"
+UPDATE users
+SET multifactor = ?1, updated_at = ?2
+WHERE id = ?3
+                "#,
+                TOTP,
+                now,
+                user_id,
+            )
+            .map_err(Error::D1)?,
+        ])
+        .await
+        .map_err(Error::D1)?;
+
+    regenerate_recovery_codes(&state.db, user_id).await
+}
+
+/// Remove TOTP and the recovery codes of a user, returning whether TOTP was enrolled.
+pub async fn disable_totp(db: &d1::Database, user_id: &str) -> Result<bool, Error> {
+    if !has_totp(db, user_id).await? {
+        return Ok(false);
+    }
+
+    db.batch(vec![
+        d1::query!(
+            db,
+            "DELETE FROM totp_credentials WHERE user_id = ?",
+            user_id
+        )
+        .map_err(Error::D1)?,
+        d1::query!(db, "DELETE FROM recovery_codes WHERE user_id = ?", user_id)
+            .map_err(Error::D1)?,
+        d1::query!(
+            db,
+            r#"
+UPDATE users
+SET multifactor = NULL, updated_at = ?1
+WHERE id = ?2
+            "#,
+            Utc::now(),
+            user_id,
+        )
+        .map_err(Error::D1)?,
+    ])
+    .await
+    .map_err(Error::D1)?;
+
+    Ok(true)
+}
+
+/// Replace the recovery codes of a user. Only their hashes are stored, so the codes can only be
+/// shown once.
+pub async fn regenerate_recovery_codes(
+    db: &d1::Database,
+    user_id: &str,
+) -> Result<Vec<String>, Error> {
+    let codes = (0..RECOVERY_CODES)
+        .map(|_| gen_string(RECOVERY_CODE_LEN).to_lowercase())
+        .collect::<Vec<_>>();
+    let now = Utc::now();
+
+    let mut statements =
+        vec![
+            d1::query!(db, "DELETE FROM recovery_codes WHERE user_id = ?", user_id)
+                .map_err(Error::D1)?,
+        ];
+    for code in &codes {
+        statements.push(
+            d1::query!(
+                db,
+                r#"
+INSERT INTO recovery_codes (user_id, code_hash, created_at)
+VALUES (?, ?, ?)
+                "#,
+                user_id,
+                hash_recovery_code(code),
+                now,
+            )
+            .map_err(Error::D1)?,
+        );
+    }
+
+    db.batch(statements).await.map_err(Error::D1)?;
+
+    Ok(codes)
+}
+
+/// Use a recovery code instead of a TOTP code, which can only be done once per code.
+pub async fn use_recovery_code(
+    db: &d1::Database,
+    user_id: &str,
+    code: &str,
+) -> Result<bool, Error> {
+    let used = d1::query!(
+        db,
+        r#"
+UPDATE recovery_codes
+SET used_at = ?1
+WHERE user_id = ?2 AND code_hash = ?3 AND used_at IS NULL
+RETURNING code_hash
+        "#,
+        Utc::now(),
+        user_id,
+        hash_recovery_code(code),
+    )
+    .map_err(Error::D1)?
+    .first::<String>(Some("code_hash"))
+    .await
+    .map_err(Error::D1)?;
+
+    Ok(used.is_some())
+}
+
+#[derive(Deserialize)]
+struct SecretRow {
+    user_id: String,
+    secret: String,
+}
+
+/// Re-encrypt TOTP secrets that were encrypted with an older key, so that the old key can be
+/// removed after a rotation.
+pub async fn reencrypt_secrets(state: &AppState) -> Result<(), Error> {
+    let version = crypto::current_version(&state.env)?;
+
+    let mut reencrypted = 0;
+    let mut after = String::new();
+
+    loop {
+        let rows = d1::query!(
+            &state.db,
+            r#"
+SELECT user_id, secret
+FROM totp_credentials
+WHERE json_extract(secret, '$.version') != ?1 AND user_id > ?2
+ORDER BY user_id
+LIMIT ?3
+            "#,
+            version,
+            after,
+            REENCRYPT_BATCH_SIZE,
+        )
+        .map_err(Error::D1)?
+        .all()
+        .await
+        .map_err(Error::D1)?
+        .results::<SecretRow>()
+        .map_err(Error::D1)?;
+
+        for row in &rows {
+            // Secrets that can't be re-encrypted are skipped, so that they don't hold back the
+            // others in later runs
+            match reencrypt_secret(state, row).await {
+                Ok(()) => reencrypted += 1,
+                Err(err) => console_error!(
+                    "failed to re-encrypt the TOTP secret of {}: {err:?}",
+                    row.user_id
+                ),
+            }
+
+            if reencrypted == REENCRYPT_BATCH_SIZE {
+                return Ok(());
+            }
+        }
+
+        match rows.last() {
+            Some(row) if rows.len() == REENCRYPT_BATCH_SIZE as usize => after = row.user_id.clone(),
+            _ => return Ok(()),
+        }
+    }
+}
+
+async fn reencrypt_secret(state: &AppState, row: &SecretRow) -> Result<(), Error> {
+    let encrypted = serde_json::from_str::<Encrypted>(&row.secret).map_err(Error::SerdeJson)?;
+    let encrypted = crypto::reencrypt(&state.env, &secret_key(&row.user_id), &encrypted).await?;
+    let secret = serde_json::to_string(&encrypted).map_err(Error::SerdeJson)?;
+
+    // The secret is left alone when it was replaced after it was read
+    d1::query!(
+        &state.db,
+        r#"
+UPDATE totp_credentials
+SET secret = ?1
+WHERE user_id = ?2 AND secret = ?3
+        "#,
+        secret,
+        row.user_id,
+        row.secret,
+    )
+    .map_err(Error::D1)?
+    .run()
+    .await
+    .map_err(Error::D1)?;
+
+    Ok(())
+}
+
+/// Whether every sign in requires a second factor, either for all applications with the
+/// `REQUIRE_MFA` variable or for the application itself.
+pub async fn is_required(state: &AppState, client_id: &ClientId) -> bool {
+    state
+        .env
+        .var("REQUIRE_MFA")
+        .map_or(false, |var| var.to_string() == "true")
+        || applications::requires_mfa(&state.db, client_id).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The secret of the test vectors in RFC 4226 and RFC 6238.
+    const SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn encodes_base32() {
+        // Test vectors from RFC 4648, without padding
+        for (bytes, encoded) in [
+            ("", ""),
+            ("f", "MY"),
+            ("fo", "MZXQ"),
+            ("foo", "MZXW6"),
+            ("foob", "MZXW6YQ"),
+            ("fooba", "MZXW6YTB"),
+            ("foobar", "MZXW6YTBOI"),
+        ] {
+            assert_eq!(base32_encode(bytes.as_bytes()), encoded);
+            assert_eq!(base32_decode(encoded).unwrap(), bytes.as_bytes());
+        }
+
+        assert_eq!(base32_encode(SECRET), "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+    }
+
+    #[test]
+    fn decodes_base32_leniently() {
+        assert_eq!(base32_decode("mzxw6ytboi======").unwrap(), b"foobar");
+        assert_eq!(base32_decode("MZXW6YTB1"), None);
+    }
+
+    #[test]
+    fn generates_hotp() {
+        // Appendix D of RFC 4226
+        let codes = [
+            755224, 287082, 359152, 969429, 338314, 254676, 287922, 162583, 399871, 520489,
+        ];
+
+        for (counter, code) in codes.into_iter().enumerate() {
+            assert_eq!(hotp(SECRET, counter as u64), code);
+        }
+    }
+
+    #[test]
+    fn verifies_totp() {
+        let secret = base32_encode(SECRET);
+
+        // Appendix B of RFC 6238, truncated to 6 digits
+        for (timestamp, code) in [
+            (59, "287082"),
+            (1111111109, "081804"),
+            (1111111111, "050471"),
+            (1234567890, "005924"),
+            (2000000000, "279037"),
+            (20000000000, "353130"),
+        ] {
+            assert_eq!(
+                verify_code(&secret, code, None, timestamp),
+                Some(timestamp / PERIOD_SECONDS)
+            );
+        }
+    }
+
+    #[test]
+    fn accepts_adjacent_steps() {
+        let secret = base32_encode(SECRET);
+
+        assert_eq!(verify_code(&secret, "287082", None, 59 + 30), Some(1));
+        assert_eq!(verify_code(&secret, "287082", None, 59 - 30), Some(1));
+        assert_eq!(verify_code(&secret, "287082", None, 59 + 60), None);
+    }
+
+    #[test]
+    fn rejects_used_steps() {
+        let secret = base32_encode(SECRET);
+
+        assert_eq!(verify_code(&secret, "287082", Some(0), 59), Some(1));
+        assert_eq!(verify_code(&secret, "287082", Some(1), 59), None);
+    }
+
+    #[test]
+    fn rejects_malformed_codes() {
+        let secret = base32_encode(SECRET);
+
+        assert_eq!(verify_code(&secret, "287 082", None, 59), Some(1));
+        for code in ["28708", "2870820", "28708a", "+28708"] {
+            assert_eq!(verify_code(&secret, code, None, 59), None);
+        }
+    }
+}
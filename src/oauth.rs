@@ -2,7 +2,7 @@ use oauth2::{
     basic::{BasicClient, BasicTokenResponse},
     reqwest::async_http_client,
     AuthUrl, AuthorizationCode, ClientId, ClientSecret, CsrfToken, PkceCodeChallenge,
-    PkceCodeVerifier, RedirectUrl, Scope, TokenUrl,
+    PkceCodeVerifier, RedirectUrl, RefreshToken, Scope, TokenUrl,
 };
 use reqwest::Url;
 
@@ -14,12 +14,17 @@ pub struct OAuthClient {
 }
 
 impl OAuthClient {
-    pub fn new(client_id: ClientId, client_secret: ClientSecret, provider: OAuth2Provider) -> Self {
+    pub fn new(
+        client_id: ClientId,
+        client_secret: ClientSecret,
+        provider: &OAuth2Provider,
+        scopes: &[String],
+    ) -> Self {
         let client = BasicClient::new(
             client_id,
             Some(client_secret),
-            AuthUrl::new(provider.auth_url.into()).expect("invalid auth url"),
-            Some(TokenUrl::new(provider.token_url.into()).expect("invalid token url")),
+            AuthUrl::new(provider.auth_url.clone()).expect("invalid auth url"),
+            Some(TokenUrl::new(provider.token_url.clone()).expect("invalid token url")),
         )
         .set_redirect_uri(
             RedirectUrl::new(format!("{}/oauth/callback", env!("DOMAIN")).into()).unwrap(),
@@ -27,11 +32,7 @@ impl OAuthClient {
 
         Self {
             client,
-            scopes: provider
-                .scopes
-                .iter()
-                .map(|&scope| Scope::new(scope.into()))
-                .collect(),
+            scopes: scopes.iter().cloned().map(Scope::new).collect(),
         }
     }
 
@@ -60,4 +61,15 @@ impl OAuthClient {
             .await
             .map_err(Error::TokenExchangeError)
     }
+
+    pub async fn exchange_refresh_token(
+        &self,
+        refresh_token: &RefreshToken,
+    ) -> Result<BasicTokenResponse, Error> {
+        self.client
+            .exchange_refresh_token(refresh_token)
+            .request_async(async_http_client)
+            .await
+            .map_err(Error::TokenExchangeError)
+    }
 }
@@ -1,52 +1,136 @@
+use std::sync::Arc;
+
 use oauth2::{
-    reqwest::async_http_client, AccessToken, AuthorizationCode, ClientId, ClientSecret, CsrfToken,
-    PkceCodeChallenge, PkceCodeVerifier, RedirectUrl, Scope,
+    reqwest::async_http_client, AccessToken, AuthType, AuthorizationCode, ClientId, ClientSecret,
+    CsrfToken, PkceCodeChallenge, PkceCodeVerifier, RedirectUrl, RefreshToken, Scope,
 };
 use openidconnect::{
     core::{
-        CoreAuthenticationFlow, CoreClient, CoreProviderMetadata, CoreTokenResponse,
-        CoreUserInfoClaims,
+        CoreAuthenticationFlow, CoreClient, CoreClientAuthMethod, CoreIdTokenClaims,
+        CoreIdTokenVerifier, CoreProviderMetadata, CoreTokenResponse, CoreUserInfoClaims,
     },
-    AccessTokenHash, IssuerUrl, Nonce, TokenResponse,
+    AccessTokenHash, ClaimsVerificationError, IssuerUrl, LanguageTag, LoginHint, Nonce,
+    SignatureVerificationError, TokenResponse,
 };
 use reqwest::Url;
+use worker::kv::KvStore;
 
 use crate::{error::Error, providers::OidcProvider};
 
+pub mod apple;
+mod discovery;
+
+/// Multi-tenant providers such as Microsoft Entra ID advertise an issuer containing this
+/// placeholder, while ID tokens are issued by the tenant of the user.
+const TENANT_ID_PLACEHOLDER: &str = "{tenantid}";
+
 pub struct OidcClient {
     pub client: CoreClient,
     pub scopes: Vec<Scope>,
+    response_mode: Option<String>,
+    issuer_template: Option<String>,
+    allowed_tenants: Vec<String>,
+    metadata: CoreProviderMetadata,
+    client_id: ClientId,
+    client_secret: ClientSecret,
+    kv: Arc<KvStore>,
+}
+
+/// Extract the tenant ID from an issuer, by matching it against the issuer template.
+fn tenant_id_from_issuer(template: &str, issuer: &str) -> Option<String> {
+    let (prefix, suffix) = template.split_once(TENANT_ID_PLACEHOLDER)?;
+    let tenant_id = issuer.strip_prefix(prefix)?.strip_suffix(suffix)?;
+
+    (!tenant_id.is_empty() && !tenant_id.contains('/')).then(|| tenant_id.to_string())
+}
+
+fn core_client(
+    metadata: CoreProviderMetadata,
+    client_id: ClientId,
+    client_secret: ClientSecret,
+) -> CoreClient {
+    // Providers such as Apple only accept the client credentials in the request body
+    let auth_type = match metadata.token_endpoint_auth_methods_supported() {
+        Some(methods)
+            if !methods.contains(&CoreClientAuthMethod::ClientSecretBasic)
+                && methods.contains(&CoreClientAuthMethod::ClientSecretPost) =>
+        {
+            AuthType::RequestBody
+        }
+        _ => AuthType::BasicAuth,
+    };
+
+    CoreClient::from_provider_metadata(metadata, client_id, Some(client_secret))
+        .set_auth_type(auth_type)
+        .set_redirect_uri(
+            RedirectUrl::new(format!("{}/oauth/callback", env!("DOMAIN")).into()).unwrap(),
+        )
 }
 
 impl OidcClient {
     pub async fn new(
+        kv: Arc<KvStore>,
         client_id: ClientId,
         client_secret: ClientSecret,
-        provider: OidcProvider,
+        provider: &OidcProvider,
+        scopes: &[String],
     ) -> Result<Self, Error> {
-        let metadata = CoreProviderMetadata::discover_async(
-            IssuerUrl::new(provider.issuer_url.into()).unwrap(),
-            async_http_client,
+        let metadata = discovery::discover(
+            &kv,
+            &IssuerUrl::new(provider.issuer_url.clone()).expect("invalid issuer url"),
         )
-        .await
-        .map_err(Error::DiscoveryError)?;
+        .await?;
 
-        let client = CoreClient::from_provider_metadata(metadata, client_id, Some(client_secret))
-            .set_redirect_uri(
-                RedirectUrl::new(format!("{}/oauth/callback", env!("DOMAIN")).into()).unwrap(),
-            );
+        let issuer_template = Some(metadata.issuer().as_str())
+            .filter(|issuer| issuer.contains(TENANT_ID_PLACEHOLDER))
+            .map(str::to_string);
+
+        let client = core_client(metadata.clone(), client_id.clone(), client_secret.clone());
 
         Ok(Self {
             client,
-            scopes: provider
-                .scopes
-                .iter()
-                .map(|&scope| Scope::new(scope.into()))
-                .collect(),
+            scopes: scopes.iter().cloned().map(Scope::new).collect(),
+            response_mode: provider.response_mode.clone(),
+            issuer_template,
+            allowed_tenants: provider.allowed_tenants.clone(),
+            metadata,
+            client_id,
+            client_secret,
+            kv,
         })
     }
 
-    pub fn authorize_url(&self) -> (Url, CsrfToken, Nonce, PkceCodeVerifier) {
+    /// Build a client with freshly fetched keys, for when the provider has rotated its keys since
+    /// they were cached.
+    async fn with_refreshed_jwks(&self) -> Result<CoreClient, Error> {
+        let metadata = discovery::refresh_jwks(&self.kv, self.metadata.clone()).await?;
+
+        Ok(core_client(
+            metadata,
+            self.client_id.clone(),
+            self.client_secret.clone(),
+        ))
+    }
+
+    /// The issuer of multi-tenant providers is checked against the issuer template instead.
+    fn id_token_verifier<'a>(&self, client: &'a CoreClient) -> CoreIdTokenVerifier<'a> {
+        client
+            .id_token_verifier()
+            .require_issuer_match(self.issuer_template.is_none())
+    }
+
+    /// The tenant that issued the ID token, for multi-tenant providers.
+    pub fn tenant_id(&self, claims: &CoreIdTokenClaims) -> Option<String> {
+        tenant_id_from_issuer(self.issuer_template.as_deref()?, claims.issuer().as_str())
+    }
+
+    /// URL to sign in at the provider, passing along the `login_hint` and space separated
+    /// `ui_locales` of the application.
+    pub fn authorize_url(
+        &self,
+        login_hint: Option<&str>,
+        ui_locales: Option<&str>,
+    ) -> (Url, CsrfToken, Nonce, PkceCodeVerifier) {
         let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
 
         let mut req = self.client.authorize_url(
@@ -59,6 +143,18 @@ impl OidcClient {
             req = req.add_scope(scope);
         }
 
+        if let Some(response_mode) = &self.response_mode {
+            req = req.add_extra_param("response_mode", response_mode.clone());
+        }
+
+        if let Some(login_hint) = login_hint {
+            req = req.set_login_hint(LoginHint::new(login_hint.to_string()));
+        }
+
+        for ui_locale in ui_locales.unwrap_or_default().split_whitespace() {
+            req = req.add_ui_locale(LanguageTag::new(ui_locale.to_string()));
+        }
+
         let (url, csrf, nonce) = req.set_pkce_challenge(pkce_challenge).url();
         (url, csrf, nonce, pkce_verifier)
     }
@@ -68,7 +164,7 @@ impl OidcClient {
         code: AuthorizationCode,
         pkce_verifier: PkceCodeVerifier,
         nonce: &Nonce,
-    ) -> Result<CoreTokenResponse, Error> {
+    ) -> Result<(CoreTokenResponse, CoreIdTokenClaims), Error> {
         let res = self
             .client
             .exchange_code(code)
@@ -78,9 +174,29 @@ impl OidcClient {
             .map_err(Error::TokenExchangeError)?;
 
         let id_token = res.id_token().ok_or(Error::MissingIdToken)?;
-        let claims = id_token
-            .claims(&self.client.id_token_verifier(), nonce)
-            .map_err(Error::ClaimsVerificationError)?;
+        let claims = match id_token.claims(&self.id_token_verifier(&self.client), nonce) {
+            Err(ClaimsVerificationError::SignatureVerification(
+                SignatureVerificationError::NoMatchingKey,
+            )) => {
+                let client = self.with_refreshed_jwks().await?;
+                id_token.claims(&self.id_token_verifier(&client), nonce)
+            }
+            claims => claims,
+        }
+        .map_err(Error::ClaimsVerificationError)?;
+
+        if let Some(template) = &self.issuer_template {
+            let tenant_id = self.tenant_id(claims).ok_or_else(|| {
+                Error::ClaimsVerificationError(ClaimsVerificationError::InvalidIssuer(format!(
+                    "issuer `{}` does not match `{template}`",
+                    claims.issuer().as_str()
+                )))
+            })?;
+
+            if !self.allowed_tenants.is_empty() && !self.allowed_tenants.contains(&tenant_id) {
+                return Err(Error::TenantNotAllowed);
+            }
+        }
 
         if let Some(at_hash) = claims.access_token_hash() {
             let actual_at_hash = AccessTokenHash::from_token(
@@ -94,10 +210,34 @@ impl OidcClient {
             }
         }
 
-        Ok(res)
+        let claims = claims.clone();
+
+        Ok((res, claims))
     }
 
-    pub async fn user_info(&self, access_token: AccessToken) -> Result<CoreUserInfoClaims, Error> {
+    pub async fn exchange_refresh_token(
+        &self,
+        refresh_token: &RefreshToken,
+    ) -> Result<CoreTokenResponse, Error> {
+        self.client
+            .exchange_refresh_token(refresh_token)
+            .request_async(async_http_client)
+            .await
+            .map_err(Error::TokenExchangeError)
+    }
+
+    /// Fetch the claims of the user. Providers without a userinfo endpoint, such as Apple, only
+    /// provide the claims of the ID token.
+    pub async fn user_info(
+        &self,
+        access_token: AccessToken,
+        id_token_claims: &CoreIdTokenClaims,
+    ) -> Result<CoreUserInfoClaims, Error> {
+        if self.metadata.userinfo_endpoint().is_none() {
+            let claims = serde_json::to_value(id_token_claims).map_err(Error::SerdeJson)?;
+            return serde_json::from_value(claims).map_err(Error::SerdeJson);
+        }
+
         self.client
             .user_info(access_token, None)
             .map_err(Error::ConfigurationError)?
@@ -0,0 +1,130 @@
+use chrono::{Duration, Utc};
+use oauth2::{ClientId, ClientSecret};
+use serde::Deserialize;
+use serde_json::json;
+use wasm_bindgen_futures::JsFuture;
+
+use crate::{
+    crypto::{self, Encrypted},
+    error::Error,
+    providers::ProviderConfig,
+    users::User,
+    AppState,
+};
+
+mod sys {
+    use wasm_bindgen::prelude::wasm_bindgen;
+    use worker::js_sys::Promise;
+
+    #[wasm_bindgen(module = "/js/es256.js")]
+    extern "C" {
+        pub fn sign_jwt(pem: &str, header: &str, claims: &str) -> Promise;
+    }
+}
+
+const AUDIENCE: &str = "https://appleid.apple.com";
+/// Apple accepts client secrets that are valid for up to six months. They are regenerated a day
+/// before they expire.
+const CLIENT_SECRET_LIFETIME_DAYS: i64 = 30;
+
+/// Get the client secret for Sign in with Apple, which is an ES256 JWT signed with the private key
+/// of the team.
+pub async fn client_secret(
+    state: &AppState,
+    provider: &ProviderConfig,
+    client_id: &ClientId,
+    private_key: &str,
+) -> Result<ClientSecret, Error> {
+    let team_id = provider
+        .env_var(&state.env, "TEAM_ID")
+        .ok_or(Error::MissingClientCredentials)?;
+    let key_id = provider
+        .env_var(&state.env, "KEY_ID")
+        .ok_or(Error::MissingClientCredentials)?;
+
+    let key = format!(
+        "provider:{}:client_secret_jwt:{key_id}:{}",
+        provider.name,
+        client_id.as_str()
+    );
+
+    if let Some(encrypted) = state
+        .kv
+        .get(&key)
+        .json::<Encrypted>()
+        .await
+        .map_err(Error::Kv)?
+    {
+        return Ok(ClientSecret::new(
+            crypto::decrypt(&state.env, &key, &encrypted).await?,
+        ));
+    }
+
+    let now = Utc::now();
+    let header = json!({
+        "alg": "ES256",
+        "kid": key_id,
+    });
+    let claims = json!({
+        "iss": team_id,
+        "iat": now.timestamp(),
+        "exp": (now + Duration::days(CLIENT_SECRET_LIFETIME_DAYS)).timestamp(),
+        "aud": AUDIENCE,
+        "sub": client_id.as_str(),
+    });
+
+    let jwt = JsFuture::from(sys::sign_jwt(
+        private_key,
+        &header.to_string(),
+        &claims.to_string(),
+    ))
+    .await
+    .map_err(Error::Crypto)?
+    .as_string()
+    .expect("jwt is not a string");
+
+    let encrypted = crypto::encrypt(&state.env, &key, &jwt).await?;
+
+    state
+        .kv
+        .put(&key, &encrypted)
+        .map_err(Error::Kv)?
+        .expiration_ttl(Duration::days(CLIENT_SECRET_LIFETIME_DAYS - 1).num_seconds() as u64)
+        .execute()
+        .await
+        .map_err(Error::Kv)?;
+
+    Ok(ClientSecret::new(jwt))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FormUserName {
+    first_name: Option<String>,
+    last_name: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct FormUser {
+    name: Option<FormUserName>,
+}
+
+/// Apple never includes the name in the ID token, it is only sent as the `user` field of the form
+/// body on the first sign in.
+pub fn apply_form_user(form_user: &str, user: &mut User) {
+    let Ok(FormUser { name: Some(name) }) = serde_json::from_str(form_user) else {
+        return;
+    };
+
+    let full_name = [name.first_name.as_deref(), name.last_name.as_deref()]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    user.given_name = user.given_name.take().or(name.first_name);
+    user.family_name = user.family_name.take().or(name.last_name);
+    if user.name.is_none() && !full_name.is_empty() {
+        user.name = Some(full_name);
+    }
+}
@@ -0,0 +1,181 @@
+use chrono::{DateTime, Duration, Utc};
+use openidconnect::{
+    core::{CoreJsonWebKeySet, CoreProviderMetadata},
+    DiscoveryError, IssuerUrl,
+};
+use reqwest::header::{self, HeaderMap};
+use serde::{Deserialize, Serialize};
+use worker::{console_error, kv::KvStore};
+
+use crate::{error::Error, http_client};
+
+use super::TENANT_ID_PLACEHOLDER;
+
+/// Documents are considered fresh for at least this long, regardless of the cache headers.
+const MIN_MAX_AGE: i64 = 60;
+/// Used when the provider does not send a `max-age`.
+const DEFAULT_MAX_AGE: i64 = 60 * 60;
+const MAX_MAX_AGE: i64 = 24 * 60 * 60;
+/// How long a document is kept around after it went stale, to fall back on when the provider is
+/// unavailable.
+const STALE_TTL: u64 = 7 * 24 * 60 * 60;
+
+#[derive(Serialize, Deserialize)]
+struct CachedDocument {
+    body: String,
+    fetched_at: DateTime<Utc>,
+    fresh_until: DateTime<Utc>,
+}
+
+fn cache_key(url: &str) -> String {
+    format!("oidc:document:{url}")
+}
+
+/// Read the lifetime of a response from its `Cache-Control` and `Age` headers.
+fn max_age(headers: &HeaderMap) -> i64 {
+    let cache_control = headers
+        .get_all(header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|directive| directive.trim().to_ascii_lowercase())
+        .collect::<Vec<_>>();
+
+    if cache_control
+        .iter()
+        .any(|directive| directive == "no-store" || directive == "no-cache")
+    {
+        return MIN_MAX_AGE;
+    }
+
+    let age = headers
+        .get(header::AGE)
+        .and_then(|age| age.to_str().ok())
+        .and_then(|age| age.parse::<i64>().ok())
+        .unwrap_or(0);
+
+    cache_control
+        .iter()
+        .find_map(|directive| directive.strip_prefix("max-age="))
+        .and_then(|max_age| max_age.parse::<i64>().ok())
+        .map_or(DEFAULT_MAX_AGE, |max_age| max_age - age)
+        .clamp(MIN_MAX_AGE, MAX_MAX_AGE)
+}
+
+async fn fetch(url: &str) -> Result<(String, i64), Error> {
+    let res = http_client()
+        .get(url)
+        .header(header::ACCEPT, "application/json")
+        .send()
+        .await?
+        .error_for_status()?;
+
+    let max_age = max_age(res.headers());
+    let body = res.text().await?;
+
+    Ok((body, max_age))
+}
+
+/// Get a document through the KV cache. A stale document is served when the provider cannot be
+/// reached.
+///
+/// With `force` the document is fetched even when it is fresh, unless it was fetched less than
+/// `MIN_MAX_AGE` seconds ago.
+async fn fetch_cached(kv: &KvStore, url: &str, force: bool) -> Result<String, Error> {
+    let key = cache_key(url);
+
+    let cached = kv.get(&key).json::<CachedDocument>().await.ok().flatten();
+
+    if let Some(cached) = &cached {
+        let now = Utc::now();
+        let fresh = if force {
+            cached.fetched_at + Duration::seconds(MIN_MAX_AGE) > now
+        } else {
+            cached.fresh_until > now
+        };
+
+        if fresh {
+            return Ok(cached.body.clone());
+        }
+    }
+
+    let (body, max_age) = match fetch(url).await {
+        Ok(res) => res,
+        Err(err) => match cached {
+            Some(cached) => {
+                console_error!("failed to fetch {url}, serving stale document: {err:?}");
+                return Ok(cached.body);
+            }
+            None => return Err(err),
+        },
+    };
+
+    let now = Utc::now();
+    let document = CachedDocument {
+        body,
+        fetched_at: now,
+        fresh_until: now + Duration::seconds(max_age),
+    };
+
+    kv.put(&key, &document)
+        .map_err(Error::Kv)?
+        .expiration_ttl(max_age as u64 + STALE_TTL)
+        .execute()
+        .await
+        .map_err(Error::Kv)?;
+
+    Ok(document.body)
+}
+
+async fn fetch_jwks(
+    kv: &KvStore,
+    metadata: &CoreProviderMetadata,
+    force: bool,
+) -> Result<CoreJsonWebKeySet, Error> {
+    let jwks = fetch_cached(kv, metadata.jwks_uri().url().as_str(), force).await?;
+
+    serde_json::from_str(&jwks).map_err(Error::SerdeJson)
+}
+
+/// Discover the metadata and keys of a provider, like `CoreProviderMetadata::discover_async` but
+/// cached in KV.
+pub async fn discover(kv: &KvStore, issuer_url: &IssuerUrl) -> Result<CoreProviderMetadata, Error> {
+    let discovery_url = format!(
+        "{}/.well-known/openid-configuration",
+        issuer_url.as_str().trim_end_matches('/')
+    );
+
+    let metadata = fetch_cached(kv, &discovery_url, false).await?;
+    let metadata =
+        serde_json::from_str::<CoreProviderMetadata>(&metadata).map_err(Error::SerdeJson)?;
+
+    // The issuer of multi-tenant providers is a template, which is only required to be on the same
+    // origin as the configured issuer
+    let issuer_matches = if metadata.issuer().as_str().contains(TENANT_ID_PLACEHOLDER) {
+        metadata.issuer().url().origin() == issuer_url.url().origin()
+    } else {
+        metadata.issuer() == issuer_url
+    };
+
+    if !issuer_matches {
+        return Err(Error::DiscoveryError(DiscoveryError::Validation(format!(
+            "unexpected issuer URI `{}` (expected `{}`)",
+            metadata.issuer().as_str(),
+            issuer_url.as_str()
+        ))));
+    }
+
+    let jwks = fetch_jwks(kv, &metadata, false).await?;
+
+    Ok(metadata.set_jwks(jwks))
+}
+
+/// Fetch the keys of a provider again, for when an ID token is signed with an unknown key.
+pub async fn refresh_jwks(
+    kv: &KvStore,
+    metadata: CoreProviderMetadata,
+) -> Result<CoreProviderMetadata, Error> {
+    let jwks = fetch_jwks(kv, &metadata, true).await?;
+
+    Ok(metadata.set_jwks(jwks))
+}
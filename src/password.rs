@@ -0,0 +1,212 @@
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use chrono::{DateTime, Duration, Utc};
+use rand::rngs::OsRng;
+use serde::Deserialize;
+
+use crate::{d1, error::Error};
+
+/// Name of the connection, and the provider of the identities of password users.
+pub const CONNECTION: &str = "password";
+
+pub const MIN_PASSWORD_LEN: usize = 8;
+
+/// Failed attempts after which sign in is locked for `LOCKOUT_MINUTES`.
+const MAX_FAILED_ATTEMPTS: u32 = 5;
+const LOCKOUT_MINUTES: i64 = 15;
+
+#[derive(Deserialize)]
+pub struct PasswordCredentials {
+    pub user_id: String,
+    pub email: String,
+    password_hash: String,
+    locked_until: Option<DateTime<Utc>>,
+}
+
+impl PasswordCredentials {
+    pub fn is_locked(&self) -> bool {
+        self.locked_until
+            .map_or(false, |locked_until| locked_until > Utc::now())
+    }
+
+    pub fn verify(&self, password: &str) -> bool {
+        PasswordHash::new(&self.password_hash).map_or(false, |hash| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
+        })
+    }
+}
+
+/// Spend the time verifying a password takes, for email addresses without usable credentials, so
+/// that the response time doesn't reveal which addresses have an account.
+pub fn verify_dummy(password: &str) {
+    let salt = SaltString::generate(&mut OsRng);
+    let _ = Argon2::default().hash_password(password.as_bytes(), &salt);
+}
+
+/// Hash a password with Argon2id and a random salt, as a PHC string.
+pub fn hash_password(password: &str) -> Result<String, Error> {
+    if password.chars().count() < MIN_PASSWORD_LEN {
+        return Err(Error::WeakPassword);
+    }
+
+    let salt = SaltString::generate(&mut OsRng);
+
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|_| Error::WeakPassword)
+}
+
+/// Email addresses are compared case insensitively.
+pub fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+pub async fn get_credentials(
+    db: &d1::Database,
+    email: &str,
+) -> Result<Option<PasswordCredentials>, Error> {
+    d1::query!(
+        db,
+        r#"
+SELECT user_id, email, password_hash, locked_until
+FROM password_credentials
+WHERE email = ?
+        "#,
+        normalize_email(email),
+    )
+    .map_err(Error::D1)?
+    .first::<PasswordCredentials>(None)
+    .await
+    .map_err(Error::D1)
+}
+
+pub async fn insert_credentials(
+    db: &d1::Database,
+    user_id: &str,
+    email: &str,
+    password_hash: &str,
+) -> Result<(), Error> {
+    d1::query!(
+        db,
+        r#"
+INSERT INTO password_credentials (user_id, email, password_hash, created_at, updated_at)
+VALUES (?1, ?2, ?3, ?4, ?4)
+        "#,
+        user_id,
+        normalize_email(email),
+        password_hash,
+        Utc::now(),
+    )
+    .map_err(Error::D1)?
+    .run()
+    .await
+    .map_err(Error::D1)?;
+
+    Ok(())
+}
+
+/// Count a failed attempt, locking the credentials once there have been too many. The count is
+/// incremented in the database, so that parallel attempts can't overwrite each other's count.
+pub async fn record_failed_attempt(db: &d1::Database, user_id: &str) -> Result<(), Error> {
+    d1::query!(
+        db,
+        r#"
+UPDATE password_credentials
+SET
+    failed_attempts = CASE WHEN failed_attempts + 1 >= ?1 THEN 0 ELSE failed_attempts + 1 END,
+    locked_until = CASE WHEN failed_attempts + 1 >= ?1 THEN ?2 ELSE locked_until END
+WHERE user_id = ?3
+        "#,
+        MAX_FAILED_ATTEMPTS,
+        Utc::now() + Duration::minutes(LOCKOUT_MINUTES),
+        user_id,
+    )
+    .map_err(Error::D1)?
+    .run()
+    .await
+    .map_err(Error::D1)?;
+
+    Ok(())
+}
+
+pub async fn reset_failed_attempts(db: &d1::Database, user_id: &str) -> Result<(), Error> {
+    d1::query!(
+        db,
+        r#"
+UPDATE password_credentials
+SET failed_attempts = 0, locked_until = NULL
+WHERE user_id = ?
+        "#,
+        user_id,
+    )
+    .map_err(Error::D1)?
+    .run()
+    .await
+    .map_err(Error::D1)?;
+
+    Ok(())
+}
+
+pub async fn set_email_verified(db: &d1::Database, user_id: &str) -> Result<(), Error> {
+    d1::query!(
+        db,
+        r#"
+UPDATE users
+SET email_verified = 1, updated_at = ?1
+WHERE id = ?2
+        "#,
+        Utc::now(),
+        user_id,
+    )
+    .map_err(Error::D1)?
+    .run()
+    .await
+    .map_err(Error::D1)?;
+
+    Ok(())
+}
+
+/// Replace the password of a user, which also unlocks the credentials and verifies the email
+/// address, since the reset link was sent to it.
+pub async fn update_password(
+    db: &d1::Database,
+    user_id: &str,
+    password_hash: &str,
+) -> Result<(), Error> {
+    let now = Utc::now();
+
+    db.batch(vec![
+        d1::query!(
+            db,
+            r#"
+UPDATE password_credentials
+SET password_hash = ?1, failed_attempts = 0, locked_until = NULL, updated_at = ?2
+WHERE user_id = ?3
+            "#,
+            password_hash,
+            now,
+            user_id,
+        )
+        .map_err(Error::D1)?,
+        d1::query!(
+            db,
+            r#"
+UPDATE users
+SET last_password_reset = ?1, email_verified = 1, updated_at = ?1
+WHERE id = ?2
+            "#,
+            now,
+            user_id,
+        )
+        .map_err(Error::D1)?,
+    ])
+    .await
+    .map_err(Error::D1)?;
+
+    Ok(())
+}
@@ -1,26 +1,539 @@
+use std::collections::BTreeMap;
+
+use chrono::Utc;
 use oauth2::AccessToken;
-use reqwest::Client;
+use reqwest::{header, Client, Url};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use worker::{console_error, Env};
 
-use crate::{error::Error, users::User};
+use crate::{
+    crypto::{self, Encrypted},
+    d1, enterprise,
+    error::Error,
+    saml,
+    users::User,
+    AppState,
+};
 
+mod attributes;
+mod claims;
 mod discord;
 mod github;
 
+pub use attributes::AttributeMapping;
+pub use claims::ClaimMapping;
+
+/// How the access token is sent to the userinfo endpoints.
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthHeader {
+    /// `Authorization: Bearer {token}`
+    #[default]
+    Bearer,
+    /// `Authorization: token {token}`
+    Token,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct OAuth2Provider {
-    pub auth_url: &'static str,
-    pub token_url: &'static str,
-    pub scopes: &'static [&'static str],
+    pub auth_url: String,
+    pub token_url: String,
+    /// Profile endpoints for providers without a compiled in module, mapped through the claims.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub userinfo_urls: Vec<String>,
+    #[serde(default)]
+    pub auth_header: AuthHeader,
+}
+
+/// How the client authenticates itself to an OIDC provider.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientAuth {
+    /// The client secret is sent as is.
+    #[default]
+    Secret,
+    /// The client secret is a PKCS#8 encoded P-256 private key, which signs a JWT that is sent as
+    /// the client secret, as required by Sign in with Apple. The team ID and key ID are read from
+    /// the `{NAME}_TEAM_ID` and `{NAME}_KEY_ID` environment variables.
+    AppleJwt,
 }
 
+#[derive(Clone, Serialize, Deserialize)]
 pub struct OidcProvider {
-    pub issuer_url: &'static str,
-    pub scopes: &'static [&'static str],
+    pub issuer_url: String,
+    /// Passed as the `response_mode` of the authorization request, such as `form_post`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response_mode: Option<String>,
+    #[serde(default)]
+    pub client_auth: ClientAuth,
+    /// Tenants that may sign in through a multi-tenant provider. All tenants are allowed when
+    /// empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_tenants: Vec<String>,
 }
 
-#[allow(unused)]
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SamlProvider {
+    /// Entity ID of the identity provider, which must be the issuer of its responses.
+    pub idp_entity_id: String,
+    /// Single sign-on service of the identity provider, using the HTTP-POST binding.
+    pub sso_url: String,
+    /// PEM or base64 encoded DER certificate that the identity provider signs its responses with.
+    pub certificate: String,
+    #[serde(default)]
+    pub attributes: AttributeMapping,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
 pub enum Provider {
+    #[serde(rename = "oauth2")]
     OAuth2(OAuth2Provider),
+    #[serde(rename = "oidc")]
     Oidc(OidcProvider),
+    #[serde(rename = "saml")]
+    Saml(SamlProvider),
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Style {
+    pub display_name: String,
+    pub background_color: String,
+    pub background_color_hover: String,
+    /// SVG markup, shown in white as an image on the login page.
+    #[serde(default)]
+    pub icon: String,
+}
+
+/// Whether a value is a `#rgb`, `#rrggbb` or `rgb(r, g, b)` color, which is safe to put in a style
+/// attribute since it can't end the declaration.
+pub fn is_color(value: &str) -> bool {
+    if let Some(hex) = value.strip_prefix('#') {
+        return matches!(hex.len(), 3 | 6) && hex.bytes().all(|c| c.is_ascii_hexdigit());
+    }
+
+    let Some(channels) = value
+        .strip_prefix("rgb(")
+        .and_then(|value| value.strip_suffix(')'))
+    else {
+        return false;
+    };
+
+    let channels = channels.split(',').collect::<Vec<_>>();
+    channels.len() == 3
+        && channels
+            .iter()
+            .all(|channel| channel.trim().parse::<u8>().is_ok())
+}
+
+/// Definition of a provider. Providers in `src/providers` are compiled in and can be overridden
+/// or disabled at runtime by a definition with the same name in D1.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ProviderConfig {
+    pub name: String,
+    #[serde(flatten)]
+    pub provider: Provider,
+    pub scopes: Vec<String>,
+    pub style: Style,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub claims: Option<ClaimMapping>,
+    /// Used instead of the `{NAME}_CLIENT_ID` environment variable when set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// Used instead of the `{NAME}_CLIENT_SECRET` environment variable when set. Stored next to
+    /// the definition and never serialized.
+    #[serde(skip)]
+    pub client_secret: Option<Encrypted>,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+impl ProviderConfig {
+    /// Prefix of the environment variables of the provider, such as `GITHUB` for
+    /// `GITHUB_CLIENT_ID`.
+    pub fn env_prefix(&self) -> String {
+        self.name.to_uppercase().replace('-', "_")
+    }
+
+    pub fn env_var(&self, env: &Env, suffix: &str) -> Option<String> {
+        env.var(&format!("{}_{suffix}", self.env_prefix()))
+            .ok()
+            .map(|var| var.to_string())
+    }
+
+    /// Check the definition, including that the client credentials are set either in the
+    /// definition or in the environment, since providers created at runtime can't rely on
+    /// variables that weren't set at deploy time.
+    pub fn validate(&self, env: &Env) -> Result<(), Error> {
+        let invalid = |reason: &str| Err(Error::InvalidProvider(reason.to_string()));
+
+        if self.name.is_empty()
+            || !self
+                .name
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        {
+            return invalid("name may only contain lowercase letters, digits and dashes");
+        }
+
+        if !is_color(&self.style.background_color) || !is_color(&self.style.background_color_hover)
+        {
+            return invalid("background colors must be #rgb, #rrggbb or rgb(r, g, b)");
+        }
+
+        let urls = match &self.provider {
+            Provider::OAuth2(p) => [&p.auth_url, &p.token_url]
+                .into_iter()
+                .chain(&p.userinfo_urls)
+                .collect(),
+            Provider::Oidc(p) => vec![&p.issuer_url],
+            Provider::Saml(p) => vec![&p.sso_url],
+        };
+
+        if urls.into_iter().any(|url| Url::parse(url).is_err()) {
+            return invalid("invalid url");
+        }
+
+        if let Provider::Saml(p) = &self.provider {
+            if saml::verifying_key(&p.certificate).is_err() {
+                return invalid("invalid certificate");
+            }
+        }
+
+        if let Provider::OAuth2(p) = &self.provider {
+            let has_profile = !p.userinfo_urls.is_empty()
+                && self
+                    .claims
+                    .as_ref()
+                    .map_or(false, |claims| claims.id.is_some());
+
+            if !BUILTIN_MODULES.contains(&self.name.as_str()) && !has_profile {
+                return invalid(
+                    "oauth2 providers without a module require userinfo_urls and an id claim",
+                );
+            }
+        }
+
+        // Disabled providers never sign anyone in, and SAML has no client credentials
+        if !self.enabled || matches!(self.provider, Provider::Saml(_)) {
+            return Ok(());
+        }
+
+        if self.client_id.is_none() && self.env_var(env, "CLIENT_ID").is_none() {
+            return invalid("client_id is required");
+        }
+
+        if self.client_secret.is_none() && self.env_var(env, "CLIENT_SECRET").is_none() {
+            return invalid("client_secret is required");
+        }
+
+        if let Provider::Oidc(OidcProvider {
+            client_auth: ClientAuth::AppleJwt,
+            ..
+        }) = &self.provider
+        {
+            if self.env_var(env, "TEAM_ID").is_none() || self.env_var(env, "KEY_ID").is_none() {
+                return invalid("apple_jwt client auth requires the TEAM_ID and KEY_ID variables");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub fn client_secret_key(provider: &str) -> String {
+    format!("provider:{provider}:client_secret")
+}
+
+pub fn builtin_providers() -> Vec<ProviderConfig> {
+    serde_json::from_str(include_str!(concat!(env!("OUT_DIR"), "/providers.json")))
+        .expect("failed to parse builtin providers")
+}
+
+#[derive(Deserialize)]
+struct ProviderRow {
+    definition: String,
+    client_secret: Option<String>,
+}
+
+impl TryFrom<ProviderRow> for ProviderConfig {
+    type Error = Error;
+
+    fn try_from(row: ProviderRow) -> Result<Self, Self::Error> {
+        let mut provider =
+            serde_json::from_str::<ProviderConfig>(&row.definition).map_err(Error::SerdeJson)?;
+
+        provider.client_secret = row
+            .client_secret
+            .map(|secret| serde_json::from_str(&secret))
+            .transpose()
+            .map_err(Error::SerdeJson)?;
+
+        Ok(provider)
+    }
+}
+
+/// Get an enabled provider by name, which can also be an enterprise connection.
+pub async fn get_provider(db: &d1::Database, name: &str) -> Result<ProviderConfig, Error> {
+    let row = d1::query!(
+        db,
+        r#"
+SELECT definition, client_secret
+FROM providers
+WHERE name = ?
+        "#,
+        name,
+    )
+    .map_err(Error::D1)?
+    .first::<ProviderRow>(None)
+    .await
+    .map_err(Error::D1)?;
+
+    let provider = match row {
+        Some(row) => ProviderConfig::try_from(row)?,
+        None => match enterprise::get_connection(db, name).await? {
+            Some(connection) => connection.provider(),
+            None => builtin_providers()
+                .into_iter()
+                .find(|provider| provider.name == name)
+                .ok_or(Error::InvalidConnection)?,
+        },
+    };
+
+    if !provider.enabled {
+        return Err(Error::InvalidConnection);
+    }
+
+    Ok(provider)
+}
+
+/// List all providers sorted by name, including those that are disabled.
+pub async fn list_providers(db: &d1::Database) -> Result<Vec<ProviderConfig>, Error> {
+    let rows = d1::query!(
+        db,
+        r#"
+SELECT definition, client_secret
+FROM providers
+        "#
+    )
+    .all()
+    .await
+    .map_err(Error::D1)?
+    .results::<ProviderRow>()
+    .map_err(Error::D1)?;
+
+    let mut providers = builtin_providers()
+        .into_iter()
+        .map(|provider| (provider.name.clone(), provider))
+        .collect::<BTreeMap<_, _>>();
+
+    for row in rows {
+        let provider = ProviderConfig::try_from(row)?;
+        providers.insert(provider.name.clone(), provider);
+    }
+
+    Ok(providers.into_values().collect())
+}
+
+pub async fn upsert_provider(db: &d1::Database, provider: &ProviderConfig) -> Result<(), Error> {
+    let definition = serde_json::to_string(provider).map_err(Error::SerdeJson)?;
+    let client_secret = provider
+        .client_secret
+        .as_ref()
+        .map(serde_json::to_string)
+        .transpose()
+        .map_err(Error::SerdeJson)?;
+
+    d1::query!(
+        db,
+        r#"
+INSERT INTO providers (name, definition, client_secret, created_at, updated_at)
+VALUES (?1, ?2, ?3, ?4, ?4)
+ON CONFLICT (name) DO UPDATE SET
+    definition = excluded.definition,
+    client_secret = COALESCE(excluded.client_secret, providers.client_secret),
+    updated_at = excluded.updated_at
+        "#,
+        provider.name,
+        definition,
+        client_secret,
+        Utc::now(),
+    )
+    .map_err(Error::D1)?
+    .run()
+    .await
+    .map_err(Error::D1)?;
+
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct ClientSecretRow {
+    name: String,
+    client_secret: String,
+}
+
+/// Re-encrypt client secrets that were encrypted with an older key, so that the old key can be
+/// removed after a rotation.
+pub async fn reencrypt_client_secrets(state: &AppState) -> Result<(), Error> {
+    let version = crypto::current_version(&state.env)?;
+
+    let rows = d1::query!(
+        &state.db,
+        r#"
+SELECT name, client_secret
+FROM providers
+WHERE json_extract(client_secret, '$.version') != ?
+        "#,
+        version,
+    )
+    .map_err(Error::D1)?
+    .all()
+    .await
+    .map_err(Error::D1)?
+    .results::<ClientSecretRow>()
+    .map_err(Error::D1)?;
+
+    for row in rows {
+        // A single secret that can't be re-encrypted shouldn't hold back the others
+        if let Err(err) = reencrypt_client_secret(state, &row).await {
+            console_error!(
+                "failed to re-encrypt the client secret of {}: {err:?}",
+                row.name
+            );
+        }
+    }
+
+    Ok(())
+}
+
+async fn reencrypt_client_secret(state: &AppState, row: &ClientSecretRow) -> Result<(), Error> {
+    let encrypted =
+        serde_json::from_str::<Encrypted>(&row.client_secret).map_err(Error::SerdeJson)?;
+    let encrypted =
+        crypto::reencrypt(&state.env, &client_secret_key(&row.name), &encrypted).await?;
+    let client_secret = serde_json::to_string(&encrypted).map_err(Error::SerdeJson)?;
+
+    // The secret is left alone when it was replaced after it was read
+    d1::query!(
+        &state.db,
+        r#"
+UPDATE providers
+SET client_secret = ?1
+WHERE name = ?2 AND client_secret = ?3
+        "#,
+        client_secret,
+        row.name,
+        row.client_secret,
+    )
+    .map_err(Error::D1)?
+    .run()
+    .await
+    .map_err(Error::D1)?;
+
+    Ok(())
+}
+
+/// Delete the runtime definition of a provider. Compiled in providers fall back to their builtin
+/// definition.
+pub async fn delete_provider(db: &d1::Database, name: &str) -> Result<bool, Error> {
+    let deleted = d1::query!(
+        db,
+        r#"
+DELETE FROM providers
+WHERE name = ?
+RETURNING name
+        "#,
+        name,
+    )
+    .map_err(Error::D1)?
+    .first::<String>(Some("name"))
+    .await
+    .map_err(Error::D1)?;
+
+    Ok(deleted.is_some())
+}
+
+/// Fetch the profile of an OAuth2 user. Providers with a compiled in module use its `fetch_user`,
+/// other providers are mapped from their userinfo endpoints.
+///
+/// The returned user ID is the ID at the provider.
+pub async fn fetch_user(
+    provider: &ProviderConfig,
+    client: Client,
+    access_token: &AccessToken,
+) -> Result<User, Error> {
+    if let Some(user) = fetch_builtin_user(&provider.name, client.clone(), access_token).await {
+        return user;
+    }
+
+    let (Provider::OAuth2(oauth2), Some(claims)) = (&provider.provider, &provider.claims) else {
+        return Err(Error::InvalidConnection);
+    };
+
+    if oauth2.userinfo_urls.is_empty() {
+        return Err(Error::InvalidConnection);
+    }
+
+    let authorization = match oauth2.auth_header {
+        AuthHeader::Bearer => format!("Bearer {}", access_token.secret()),
+        AuthHeader::Token => format!("token {}", access_token.secret()),
+    };
+
+    let mut profile = Vec::with_capacity(oauth2.userinfo_urls.len());
+    for url in &oauth2.userinfo_urls {
+        let response = client
+            .get(url)
+            .header(header::AUTHORIZATION, &authorization)
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<Value>()
+            .await?;
+
+        profile.push(response);
+    }
+
+    claims.user(&profile)
 }
 
 include!(concat!(env!("OUT_DIR"), "/providers.rs"));
+
+#[cfg(test)]
+mod tests {
+    use super::is_color;
+
+    #[test]
+    fn accepts_colors() {
+        for color in [
+            "#fff",
+            "#5865f2",
+            "#5865F2",
+            "rgb(88, 101, 242)",
+            "rgb(0,0,0)",
+        ] {
+            assert!(is_color(color), "{color}");
+        }
+    }
+
+    #[test]
+    fn rejects_other_values() {
+        for color in [
+            "",
+            "red",
+            "#5865f",
+            "#5865fg",
+            "rgb(88, 101)",
+            "rgb(256, 0, 0)",
+            "#fff; background-image: url(https://example.com)",
+            "rgb(0, 0, 0); color: red",
+        ] {
+            assert!(!is_color(color), "{color}");
+        }
+    }
+}
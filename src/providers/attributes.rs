@@ -0,0 +1,46 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::users::User;
+
+/// Maps the attributes of a SAML assertion to a user. Every field is the name of an attribute,
+/// such as `email` or `http://schemas.xmlsoap.org/ws/2005/05/identity/claims/emailaddress`.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct AttributeMapping {
+    /// The user ID at the identity provider. Defaults to the `NameID` of the subject.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub given_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub family_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub picture: Option<String>,
+}
+
+fn lookup(attributes: &HashMap<String, Vec<String>>, name: &Option<String>) -> Option<String> {
+    attributes.get(name.as_deref()?)?.first().cloned()
+}
+
+impl AttributeMapping {
+    pub fn user(&self, name_id: &str, attributes: &HashMap<String, Vec<String>>) -> User {
+        let id = lookup(attributes, &self.id).unwrap_or_else(|| name_id.to_string());
+
+        User {
+            email: lookup(attributes, &self.email),
+            username: lookup(attributes, &self.username),
+            name: lookup(attributes, &self.name),
+            given_name: lookup(attributes, &self.given_name),
+            family_name: lookup(attributes, &self.family_name),
+            picture: lookup(attributes, &self.picture),
+            ..User::default_with_id(id)
+        }
+    }
+}
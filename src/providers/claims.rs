@@ -0,0 +1,83 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{error::Error, users::User};
+
+/// Maps a provider profile to a user. Every field is a JSON pointer into the profile, such as
+/// `/data/0/email`.
+///
+/// A profile can consist of several responses when a provider spreads the user over multiple
+/// endpoints. Pointers are then tried against each response in order, and the first match wins.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct ClaimMapping {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email_verified: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub picture: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+fn lookup<'a>(profile: &'a [Value], pointer: &Option<String>) -> Option<&'a Value> {
+    let pointer = pointer.as_deref()?;
+
+    profile
+        .iter()
+        .find_map(|response| response.pointer(pointer).filter(|value| !value.is_null()))
+}
+
+fn lookup_string(profile: &[Value], pointer: &Option<String>) -> Option<String> {
+    match lookup(profile, pointer)? {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        _ => None,
+    }
+}
+
+fn lookup_bool(profile: &[Value], pointer: &Option<String>) -> Option<bool> {
+    match lookup(profile, pointer)? {
+        Value::Bool(b) => Some(*b),
+        Value::String(s) => s.parse().ok(),
+        _ => None,
+    }
+}
+
+impl ClaimMapping {
+    /// Overwrite the fields of the user that have a mapping and are present in the profile.
+    pub fn apply(&self, profile: &[Value], user: &mut User) {
+        if let Some(id) = lookup_string(profile, &self.id) {
+            user.id = id;
+        }
+        if let Some(email) = lookup_string(profile, &self.email) {
+            user.email = Some(email);
+        }
+        if let Some(email_verified) = lookup_bool(profile, &self.email_verified) {
+            user.email_verified = Some(email_verified);
+        }
+        if let Some(username) = lookup_string(profile, &self.username) {
+            user.username = Some(username);
+        }
+        if let Some(picture) = lookup_string(profile, &self.picture) {
+            user.picture = Some(picture);
+        }
+        if let Some(name) = lookup_string(profile, &self.name) {
+            user.name = Some(name);
+        }
+    }
+
+    /// Create a user from a profile, which requires the ID to be mapped.
+    pub fn user(&self, profile: &[Value]) -> Result<User, Error> {
+        let id = lookup_string(profile, &self.id).ok_or(Error::InvalidUserProfile)?;
+
+        let mut user = User::default_with_id(id);
+        self.apply(profile, &mut user);
+
+        Ok(user)
+    }
+}
@@ -0,0 +1,107 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{error::Error, AppState};
+
+/// Attempts counted in a fixed window, which starts at the first attempt.
+#[derive(Serialize, Deserialize)]
+struct RateLimit {
+    count: u32,
+    reset_at: DateTime<Utc>,
+}
+
+impl RateLimit {
+    /// The limit after counting an attempt at `now`, starting a new window when the current one
+    /// has reset.
+    fn count(
+        current: Option<Self>,
+        now: DateTime<Utc>,
+        max: u32,
+        window: Duration,
+    ) -> Result<Self, Error> {
+        let limit = current
+            .filter(|limit| limit.reset_at > now)
+            .unwrap_or_else(|| RateLimit {
+                count: 0,
+                reset_at: now + window,
+            });
+
+        if limit.count >= max {
+            return Err(Error::RateLimited);
+        }
+
+        Ok(RateLimit {
+            count: limit.count + 1,
+            reset_at: limit.reset_at,
+        })
+    }
+}
+
+/// Count an attempt under `key`, failing when `max` attempts were counted within `window`.
+pub async fn check_rate_limit(
+    state: &AppState,
+    key: &str,
+    max: u32,
+    window: Duration,
+) -> Result<(), Error> {
+    let current = state
+        .kv
+        .get(key)
+        .json::<RateLimit>()
+        .await
+        .map_err(Error::Kv)?;
+
+    let limit = RateLimit::count(current, Utc::now(), max, window)?;
+
+    // The window doesn't move with every attempt, so the key expires when it resets. KV requires
+    // expirations to be at least a minute away.
+    let expiration = limit.reset_at.max(Utc::now() + Duration::minutes(1));
+
+    state
+        .kv
+        .put(key, limit)
+        .unwrap()
+        .expiration(expiration.timestamp() as u64)
+        .execute()
+        .await
+        .map_err(Error::Kv)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_attempts_in_a_window() {
+        let now = Utc::now();
+        let window = Duration::minutes(60);
+
+        let mut limit = None;
+        for count in 1..=3 {
+            let next = RateLimit::count(limit, now, 3, window).unwrap();
+            assert_eq!(next.count, count);
+            assert_eq!(next.reset_at, now + window);
+            limit = Some(next);
+        }
+
+        assert!(matches!(
+            RateLimit::count(limit, now + Duration::minutes(59), 3, window),
+            Err(Error::RateLimited)
+        ));
+    }
+
+    #[test]
+    fn starts_a_new_window_after_reset() {
+        let now = Utc::now();
+        let window = Duration::minutes(60);
+        let limit = RateLimit {
+            count: 3,
+            reset_at: now,
+        };
+
+        let next = RateLimit::count(Some(limit), now, 3, window).unwrap();
+
+        assert_eq!(next.count, 1);
+        assert_eq!(next.reset_at, now + window);
+    }
+}
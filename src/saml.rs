@@ -0,0 +1,318 @@
+//! SAML 2.0 service provider, using the HTTP-POST binding for both requests and responses.
+//!
+//! See <https://docs.oasis-open.org/security/saml/v2.0/>.
+
+use std::collections::HashMap;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
+use roxmltree::{Document, Node, NodeType};
+use rsa::{pkcs1v15::VerifyingKey, pkcs8::DecodePublicKey, RsaPublicKey};
+use sha2::Sha256;
+
+use crate::{error::Error, gen_string, login::escape_html, providers::SamlProvider};
+
+mod c14n;
+mod der;
+mod signature;
+#[cfg(test)]
+mod tests;
+
+const NS_PROTOCOL: &str = "urn:oasis:names:tc:SAML:2.0:protocol";
+const NS_ASSERTION: &str = "urn:oasis:names:tc:SAML:2.0:assertion";
+const NS_DSIG: &str = "http://www.w3.org/2000/09/xmldsig#";
+const STATUS_SUCCESS: &str = "urn:oasis:names:tc:SAML:2.0:status:Success";
+const BEARER: &str = "urn:oasis:names:tc:SAML:2.0:cm:bearer";
+const BINDING_POST: &str = "urn:oasis:names:tc:SAML:2.0:bindings:HTTP-POST";
+
+/// Allowed difference between our clock and the clock of the identity provider.
+const CLOCK_SKEW: i64 = 2;
+
+fn invalid(reason: &str) -> Error {
+    Error::InvalidSamlResponse(reason.to_string())
+}
+
+/// Entity ID of this service provider, which is also the URL of its metadata.
+pub fn entity_id() -> String {
+    format!("{}/oauth/saml/metadata", env!("DOMAIN"))
+}
+
+/// Assertion consumer service, where identity providers post their responses.
+pub fn acs_url() -> String {
+    format!("{}/oauth/saml/acs", env!("DOMAIN"))
+}
+
+/// A new request ID, which must start with a letter or underscore.
+pub fn request_id() -> String {
+    format!("_{}", gen_string(32))
+}
+
+/// Metadata of this service provider, for configuring it at identity providers.
+pub fn metadata() -> String {
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<md:EntityDescriptor xmlns:md="urn:oasis:names:tc:SAML:2.0:metadata" entityID="{entity_id}">
+  <md:SPSSODescriptor AuthnRequestsSigned="false" WantAssertionsSigned="true" protocolSupportEnumeration="{NS_PROTOCOL}">
+    <md:NameIDFormat>urn:oasis:names:tc:SAML:1.1:nameid-format:unspecified</md:NameIDFormat>
+    <md:AssertionConsumerService Binding="{BINDING_POST}" Location="{acs_url}" index="0" isDefault="true"/>
+  </md:SPSSODescriptor>
+</md:EntityDescriptor>
+"#,
+        entity_id = escape_html(&entity_id()),
+        acs_url = escape_html(&acs_url()),
+    )
+}
+
+/// A page that posts an authentication request to the identity provider.
+pub fn authn_request_form(provider: &SamlProvider, request_id: &str, relay_state: &str) -> String {
+    let request = format!(
+        r#"<samlp:AuthnRequest xmlns:samlp="{NS_PROTOCOL}" xmlns:saml="{NS_ASSERTION}" ID="{request_id}" Version="2.0" IssueInstant="{issue_instant}" Destination="{destination}" AssertionConsumerServiceURL="{acs_url}" ProtocolBinding="{BINDING_POST}"><saml:Issuer>{entity_id}</saml:Issuer></samlp:AuthnRequest>"#,
+        request_id = escape_html(request_id),
+        issue_instant = Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+        destination = escape_html(&provider.sso_url),
+        acs_url = escape_html(&acs_url()),
+        entity_id = escape_html(&entity_id()),
+    );
+
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<body onload="document.forms[0].submit()">
+  <form method="post" action="{sso_url}">
+    <input type="hidden" name="SAMLRequest" value="{request}">
+    <input type="hidden" name="RelayState" value="{relay_state}">
+    <noscript><button type="submit">Continue</button></noscript>
+  </form>
+</body>
+</html>
+"#,
+        sso_url = escape_html(&provider.sso_url),
+        request = STANDARD.encode(request),
+        relay_state = escape_html(relay_state),
+    )
+}
+
+/// The key of a PEM or base64 encoded DER certificate.
+pub fn verifying_key(certificate: &str) -> Result<VerifyingKey<Sha256>, Error> {
+    let invalid = || Error::InvalidProvider("invalid certificate".to_string());
+
+    let certificate = certificate
+        .lines()
+        .filter(|line| !line.starts_with("-----"))
+        .flat_map(str::split_whitespace)
+        .collect::<String>();
+    let certificate = STANDARD.decode(certificate).map_err(|_| invalid())?;
+
+    let spki = der::subject_public_key_info(&certificate).ok_or_else(invalid)?;
+    let key = RsaPublicKey::from_public_key_der(spki).map_err(|_| invalid())?;
+
+    Ok(VerifyingKey::new_with_prefix(key))
+}
+
+/// The subject of a verified assertion.
+pub struct Assertion {
+    pub name_id: String,
+    pub attributes: HashMap<String, Vec<String>>,
+}
+
+fn child<'a, 'input>(node: Node<'a, 'input>, ns: &str, name: &str) -> Option<Node<'a, 'input>> {
+    node.children().find(|n| n.has_tag_name((ns, name)))
+}
+
+/// The text of an element. Comments are left out of the signed digest, so text that is split by a
+/// comment is joined to read all of the signed text, and elements or processing instructions in
+/// between are rejected.
+fn text(node: Node) -> Result<String, Error> {
+    let mut text = String::new();
+
+    for child in node.children() {
+        match child.node_type() {
+            NodeType::Text => text.push_str(child.text().unwrap_or_default()),
+            NodeType::Comment => {}
+            _ => return Err(invalid("unexpected markup in text")),
+        }
+    }
+
+    Ok(text.trim().to_string())
+}
+
+fn parse_instant(s: &str) -> Result<DateTime<Utc>, Error> {
+    DateTime::parse_from_rfc3339(s)
+        .map(|t| t.with_timezone(&Utc))
+        .map_err(|_| invalid("invalid timestamp"))
+}
+
+/// Parse and verify a base64 encoded response to the authentication request with `request_id`.
+pub fn parse_response(
+    provider: &SamlProvider,
+    response: &str,
+    request_id: &str,
+) -> Result<Assertion, Error> {
+    let key = verifying_key(&provider.certificate)?;
+
+    let response = STANDARD
+        .decode(response.split_whitespace().collect::<String>())
+        .map_err(|_| invalid("invalid base64"))?;
+    let response = String::from_utf8(response).map_err(|_| invalid("invalid utf-8"))?;
+    let document = Document::parse(&response).map_err(|_| invalid("invalid xml"))?;
+
+    let root = document.root_element();
+    if !root.has_tag_name((NS_PROTOCOL, "Response")) {
+        return Err(invalid("not a response"));
+    }
+
+    if root.attribute("InResponseTo") != Some(request_id) {
+        return Err(invalid("response to another request"));
+    }
+
+    if root
+        .attribute("Destination")
+        .map_or(false, |destination| destination != acs_url())
+    {
+        return Err(invalid("wrong destination"));
+    }
+
+    if let Some(issuer) = child(root, NS_ASSERTION, "Issuer").map(text).transpose()? {
+        if issuer != provider.idp_entity_id {
+            return Err(invalid("wrong issuer"));
+        }
+    }
+
+    let status = child(root, NS_PROTOCOL, "Status")
+        .and_then(|status| child(status, NS_PROTOCOL, "StatusCode"))
+        .and_then(|code| code.attribute("Value"));
+    if status != Some(STATUS_SUCCESS) {
+        return Err(invalid("authentication failed"));
+    }
+
+    if document
+        .descendants()
+        .any(|n| n.has_tag_name((NS_ASSERTION, "EncryptedAssertion")))
+    {
+        return Err(invalid("encrypted assertions are not supported"));
+    }
+
+    // Only a single assertion directly in the response is accepted, so that the assertion that is
+    // read is always the one that is covered by the signature
+    let mut assertions = document
+        .descendants()
+        .filter(|n| n.has_tag_name((NS_ASSERTION, "Assertion")));
+    let (Some(assertion), None) = (assertions.next(), assertions.next()) else {
+        return Err(invalid("response must contain one assertion"));
+    };
+    if assertion.parent() != Some(root) {
+        return Err(invalid("response must contain one assertion"));
+    }
+
+    let assertion_signed = signature::verify(assertion, &key)?;
+    if !assertion_signed && !signature::verify(root, &key)? {
+        return Err(invalid("response is not signed"));
+    }
+
+    let issuer = child(assertion, NS_ASSERTION, "Issuer")
+        .map(text)
+        .transpose()?;
+    if issuer.as_deref() != Some(provider.idp_entity_id.as_str()) {
+        return Err(invalid("wrong issuer"));
+    }
+
+    let now = Utc::now();
+    let skew = Duration::minutes(CLOCK_SKEW);
+
+    let subject =
+        child(assertion, NS_ASSERTION, "Subject").ok_or_else(|| invalid("missing subject"))?;
+    let name_id = child(subject, NS_ASSERTION, "NameID")
+        .map(text)
+        .transpose()?
+        .filter(|name_id| !name_id.is_empty())
+        .ok_or_else(|| invalid("missing name id"))?;
+
+    let mut confirmed = false;
+    for confirmation in subject
+        .children()
+        .filter(|n| n.has_tag_name((NS_ASSERTION, "SubjectConfirmation")))
+        .filter(|n| n.attribute("Method") == Some(BEARER))
+    {
+        let Some(data) = child(confirmation, NS_ASSERTION, "SubjectConfirmationData") else {
+            continue;
+        };
+
+        let not_on_or_after = match data.attribute("NotOnOrAfter") {
+            Some(t) => parse_instant(t)?,
+            None => continue,
+        };
+
+        if data.attribute("Recipient") == Some(acs_url().as_str())
+            && data.attribute("InResponseTo") == Some(request_id)
+            && now < not_on_or_after + skew
+        {
+            confirmed = true;
+            break;
+        }
+    }
+    if !confirmed {
+        return Err(invalid("subject not confirmed"));
+    }
+
+    let conditions = child(assertion, NS_ASSERTION, "Conditions")
+        .ok_or_else(|| invalid("missing conditions"))?;
+    if let Some(not_before) = conditions.attribute("NotBefore") {
+        if now + skew < parse_instant(not_before)? {
+            return Err(invalid("assertion not yet valid"));
+        }
+    }
+    if let Some(not_on_or_after) = conditions.attribute("NotOnOrAfter") {
+        if now >= parse_instant(not_on_or_after)? + skew {
+            return Err(invalid("assertion expired"));
+        }
+    }
+
+    // Every audience restriction must include this service provider
+    let entity_id = entity_id();
+    let audience_restrictions = conditions
+        .children()
+        .filter(|n| n.has_tag_name((NS_ASSERTION, "AudienceRestriction")))
+        .map(|restriction| {
+            restriction
+                .children()
+                .filter(|n| n.has_tag_name((NS_ASSERTION, "Audience")))
+                .map(text)
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if audience_restrictions.is_empty()
+        || !audience_restrictions
+            .iter()
+            .all(|audiences| audiences.contains(&entity_id))
+    {
+        return Err(invalid("wrong audience"));
+    }
+
+    let mut attributes = HashMap::<String, Vec<String>>::new();
+    for attribute in assertion
+        .children()
+        .filter(|n| n.has_tag_name((NS_ASSERTION, "AttributeStatement")))
+        .flat_map(|statement| statement.children())
+        .filter(|n| n.has_tag_name((NS_ASSERTION, "Attribute")))
+    {
+        let Some(name) = attribute.attribute("Name") else {
+            continue;
+        };
+
+        let values = attribute
+            .children()
+            .filter(|n| n.has_tag_name((NS_ASSERTION, "AttributeValue")))
+            .map(text)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        attributes
+            .entry(name.to_string())
+            .or_default()
+            .extend(values.into_iter().filter(|value| !value.is_empty()));
+    }
+
+    Ok(Assertion {
+        name_id,
+        attributes,
+    })
+}
@@ -0,0 +1,258 @@
+//! Exclusive XML canonicalization, without comments, as used by XML signatures.
+//!
+//! See <https://www.w3.org/TR/xml-exc-c14n/>.
+
+use std::collections::BTreeMap;
+
+use roxmltree::{Node, NodeType};
+
+/// The qualified name that starts at a position in the input, such as `saml:Assertion`.
+fn qname(input: &str, position: usize) -> &str {
+    let rest = &input[position..];
+    let end = rest
+        .find(|c: char| c.is_whitespace() || matches!(c, '=' | '>' | '/'))
+        .unwrap_or(rest.len());
+
+    &rest[..end]
+}
+
+fn prefix(qname: &str) -> &str {
+    qname.split_once(':').map_or("", |(prefix, _)| prefix)
+}
+
+fn escape_text(s: &str, out: &mut String) {
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '\r' => out.push_str("&#xD;"),
+            c => out.push(c),
+        }
+    }
+}
+
+fn escape_attribute(s: &str, out: &mut String) {
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '"' => out.push_str("&quot;"),
+            '\t' => out.push_str("&#x9;"),
+            '\n' => out.push_str("&#xA;"),
+            '\r' => out.push_str("&#xD;"),
+            c => out.push(c),
+        }
+    }
+}
+
+struct Canonicalizer<'a, 'input> {
+    input: &'input str,
+    exclude: Option<Node<'a, 'input>>,
+    /// Prefixes that are treated as inclusive namespaces, where the default namespace is `""`.
+    inclusive: &'a [String],
+    out: String,
+}
+
+impl<'a, 'input> Canonicalizer<'a, 'input> {
+    /// Write an element, where `rendered` contains the namespace declarations that are in effect
+    /// in the output.
+    fn element(&mut self, node: Node<'a, 'input>, rendered: &BTreeMap<String, String>) {
+        let name = qname(self.input, node.range().start + 1);
+
+        let attributes = node
+            .attributes()
+            .map(|attribute| (qname(self.input, attribute.position()), attribute))
+            .collect::<Vec<_>>();
+
+        // Namespaces that are visibly utilized by the element and its attributes
+        let mut utilized = vec![prefix(name)];
+        utilized.extend(
+            attributes
+                .iter()
+                .map(|(name, _)| prefix(name))
+                .filter(|prefix| !prefix.is_empty()),
+        );
+        utilized.extend(self.inclusive.iter().map(String::as_str));
+
+        let mut declarations = BTreeMap::new();
+        for prefix in utilized {
+            if prefix == "xml" {
+                continue;
+            }
+
+            let uri = node
+                .lookup_namespace_uri((!prefix.is_empty()).then_some(prefix))
+                .unwrap_or("");
+
+            // Inclusive prefixes are only rendered when they are in scope
+            if uri.is_empty() && !prefix.is_empty() {
+                continue;
+            }
+
+            let current = rendered.get(prefix).map_or("", String::as_str);
+            if current != uri {
+                declarations.insert(prefix.to_string(), uri.to_string());
+            }
+        }
+
+        self.out.push('<');
+        self.out.push_str(name);
+
+        for (prefix, uri) in &declarations {
+            if prefix.is_empty() {
+                self.out.push_str(" xmlns=\"");
+            } else {
+                self.out.push_str(" xmlns:");
+                self.out.push_str(prefix);
+                self.out.push_str("=\"");
+            }
+            escape_attribute(uri, &mut self.out);
+            self.out.push('"');
+        }
+
+        let mut attributes = attributes;
+        attributes
+            .sort_by_key(|(_, attribute)| (attribute.namespace().unwrap_or(""), attribute.name()));
+
+        for (name, attribute) in attributes {
+            self.out.push(' ');
+            self.out.push_str(name);
+            self.out.push_str("=\"");
+            escape_attribute(attribute.value(), &mut self.out);
+            self.out.push('"');
+        }
+
+        self.out.push('>');
+
+        let rendered = if declarations.is_empty() {
+            rendered.clone()
+        } else {
+            let mut rendered = rendered.clone();
+            rendered.extend(declarations);
+            rendered
+        };
+
+        for child in node.children() {
+            match child.node_type() {
+                NodeType::Element if Some(child) != self.exclude => self.element(child, &rendered),
+                NodeType::Text => escape_text(child.text().unwrap_or_default(), &mut self.out),
+                NodeType::PI => {
+                    if let Some(pi) = child.pi() {
+                        self.out.push_str("<?");
+                        self.out.push_str(pi.target);
+                        if let Some(value) = pi.value {
+                            self.out.push(' ');
+                            self.out.push_str(value);
+                        }
+                        self.out.push_str("?>");
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        self.out.push_str("</");
+        self.out.push_str(name);
+        self.out.push('>');
+    }
+}
+
+/// Canonicalize an element and its descendants, leaving out the `exclude` element, which is the
+/// signature for the enveloped signature transform.
+pub fn canonicalize<'a, 'input>(
+    node: Node<'a, 'input>,
+    exclude: Option<Node<'a, 'input>>,
+    inclusive: &'a [String],
+) -> String {
+    let mut canonicalizer = Canonicalizer {
+        input: node.document().input_text(),
+        exclude,
+        inclusive,
+        out: String::new(),
+    };
+
+    canonicalizer.element(node, &BTreeMap::new());
+
+    canonicalizer.out
+}
+
+#[cfg(test)]
+mod tests {
+    use roxmltree::Document;
+
+    use super::canonicalize;
+
+    fn c14n(xml: &str) -> String {
+        let document = Document::parse(xml).unwrap();
+        canonicalize(document.root_element(), None, &[])
+    }
+
+    #[test]
+    fn sorts_attributes_and_expands_empty_elements() {
+        assert_eq!(
+            c14n(r#"<r   b = "1"  xmlns:p="urn:p" p:a='3' a="2" />"#),
+            r#"<r xmlns:p="urn:p" a="2" b="1" p:a="3"></r>"#,
+        );
+    }
+
+    #[test]
+    fn renders_only_utilized_namespaces() {
+        assert_eq!(
+            c14n(r#"<a:r xmlns:a="urn:a" xmlns:b="urn:b"><a:c/><c xmlns="urn:d"/></a:r>"#),
+            r#"<a:r xmlns:a="urn:a"><a:c></a:c><c xmlns="urn:d"></c></a:r>"#,
+        );
+    }
+
+    #[test]
+    fn declares_inherited_namespaces_on_subtrees() {
+        let document =
+            Document::parse(r#"<r xmlns="urn:d" xmlns:s="urn:s"><s:x><s:y>t</s:y></s:x></r>"#)
+                .unwrap();
+        let x = document.root_element().first_element_child().unwrap();
+
+        assert_eq!(
+            canonicalize(x, None, &[]),
+            r#"<s:x xmlns:s="urn:s"><s:y>t</s:y></s:x>"#,
+        );
+    }
+
+    #[test]
+    fn renders_inclusive_namespaces() {
+        let document = Document::parse(r#"<r xmlns="urn:d" xmlns:p="urn:p"><p:c/></r>"#).unwrap();
+        let c = document.root_element().first_element_child().unwrap();
+
+        assert_eq!(
+            canonicalize(c, None, &[String::new(), "p".into(), "q".into()]),
+            r#"<p:c xmlns="urn:d" xmlns:p="urn:p"></p:c>"#,
+        );
+    }
+
+    #[test]
+    fn removes_comments_and_keeps_processing_instructions() {
+        assert_eq!(
+            c14n("<r>a<!-- comment -->b<?pi data?></r>"),
+            "<r>ab<?pi data?></r>",
+        );
+    }
+
+    #[test]
+    fn escapes_text_and_attributes() {
+        assert_eq!(
+            c14n(r#"<r a="&#x9;&quot;&lt;&gt;'" xml:lang="en">&amp; &lt; &gt; "q" &#xD;</r>"#),
+            r#"<r a="&#x9;&quot;&lt;>'" xml:lang="en">&amp; &lt; &gt; "q" &#xD;</r>"#,
+        );
+    }
+
+    #[test]
+    fn leaves_out_excluded_element() {
+        let document = Document::parse(
+            r#"<r><ds:Signature xmlns:ds="urn:ds"><x/></ds:Signature><v>1</v></r>"#,
+        )
+        .unwrap();
+        let root = document.root_element();
+        let signature = root.first_element_child();
+
+        assert_eq!(canonicalize(root, signature, &[]), "<r><v>1</v></r>");
+    }
+}
@@ -0,0 +1,70 @@
+//! Just enough DER to find the public key in an X.509 certificate.
+
+const SEQUENCE: u8 = 0x30;
+const VERSION: u8 = 0xa0;
+
+/// A DER element.
+struct Element<'a> {
+    tag: u8,
+    /// The whole element, including its tag and length.
+    bytes: &'a [u8],
+    contents: &'a [u8],
+    /// The input after the element.
+    rest: &'a [u8],
+}
+
+/// Read a DER element from the start of the input.
+fn read(input: &[u8]) -> Option<Element<'_>> {
+    let (&tag, rest) = input.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+
+    let (len, rest) = if first < 0x80 {
+        (first as usize, rest)
+    } else {
+        let count = (first & 0x7f) as usize;
+        if count == 0 || count > 4 || rest.len() < count {
+            return None;
+        }
+
+        let len = rest[..count]
+            .iter()
+            .fold(0usize, |len, &b| (len << 8) | b as usize);
+
+        (len, &rest[count..])
+    };
+
+    if rest.len() < len {
+        return None;
+    }
+
+    let header = input.len() - rest.len();
+
+    Some(Element {
+        tag,
+        bytes: &input[..header + len],
+        contents: &rest[..len],
+        rest: &rest[len..],
+    })
+}
+
+/// Read a sequence from the start of the input.
+fn read_sequence(input: &[u8]) -> Option<Element<'_>> {
+    read(input).filter(|element| element.tag == SEQUENCE)
+}
+
+/// The DER encoded `SubjectPublicKeyInfo` of a DER encoded certificate.
+pub fn subject_public_key_info(certificate: &[u8]) -> Option<&[u8]> {
+    let certificate = read_sequence(certificate)?.contents;
+    let mut tbs_certificate = read_sequence(certificate)?.contents;
+
+    if tbs_certificate.first() == Some(&VERSION) {
+        tbs_certificate = read(tbs_certificate)?.rest;
+    }
+
+    // serialNumber, signature, issuer, validity and subject
+    for _ in 0..5 {
+        tbs_certificate = read(tbs_certificate)?.rest;
+    }
+
+    read_sequence(tbs_certificate).map(|element| element.bytes)
+}
@@ -0,0 +1,145 @@
+//! Enveloped XML signatures, restricted to what SAML identity providers use in practice.
+//!
+//! See <https://www.w3.org/TR/xmldsig-core1/> and section 5.4 of the SAML core specification.
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use roxmltree::Node;
+use rsa::{
+    pkcs1v15::{Signature, VerifyingKey},
+    signature::Verifier,
+};
+use sha2::{Digest, Sha256};
+
+use crate::error::Error;
+
+use super::{c14n::canonicalize, invalid, NS_DSIG};
+
+const EXC_C14N: &str = "http://www.w3.org/2001/10/xml-exc-c14n#";
+const ENVELOPED_SIGNATURE: &str = "http://www.w3.org/2000/09/xmldsig#enveloped-signature";
+const RSA_SHA256: &str = "http://www.w3.org/2001/04/xmldsig-more#rsa-sha256";
+const SHA256: &str = "http://www.w3.org/2001/04/xmlenc#sha256";
+
+fn child<'a, 'input>(node: Node<'a, 'input>, name: &str) -> Result<Node<'a, 'input>, Error> {
+    node.children()
+        .find(|n| n.has_tag_name((NS_DSIG, name)))
+        .ok_or_else(|| invalid(&format!("missing {name}")))
+}
+
+fn algorithm<'a>(node: Node<'a, '_>, name: &str) -> Result<&'a str, Error> {
+    child(node, name)?
+        .attribute("Algorithm")
+        .ok_or_else(|| invalid(&format!("missing {name} algorithm")))
+}
+
+fn decode(node: Node) -> Result<Vec<u8>, Error> {
+    let value = node
+        .text()
+        .unwrap_or_default()
+        .split_whitespace()
+        .collect::<String>();
+
+    STANDARD
+        .decode(value)
+        .map_err(|_| invalid("invalid base64 in signature"))
+}
+
+/// The `InclusiveNamespaces` prefix list of an exclusive canonicalization transform.
+fn inclusive_prefixes(node: Node) -> Vec<String> {
+    node.children()
+        .find(|n| n.has_tag_name((EXC_C14N, "InclusiveNamespaces")))
+        .and_then(|n| n.attribute("PrefixList"))
+        .map(|list| {
+            list.split_whitespace()
+                .map(|prefix| match prefix {
+                    "#default" => String::new(),
+                    prefix => prefix.to_string(),
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Verify the enveloped signature of an element, returning `false` if the element isn't signed.
+pub fn verify(node: Node, key: &VerifyingKey<Sha256>) -> Result<bool, Error> {
+    let Some(signature) = node
+        .children()
+        .find(|n| n.has_tag_name((NS_DSIG, "Signature")))
+    else {
+        return Ok(false);
+    };
+
+    let signed_info = child(signature, "SignedInfo")?;
+
+    let c14n_method = child(signed_info, "CanonicalizationMethod")?;
+    if c14n_method.attribute("Algorithm") != Some(EXC_C14N) {
+        return Err(invalid("unsupported canonicalization method"));
+    }
+
+    if algorithm(signed_info, "SignatureMethod")? != RSA_SHA256 {
+        return Err(invalid("unsupported signature method"));
+    }
+
+    // The signature must cover exactly the signed element, which must be the only element with
+    // that ID to prevent signature wrapping
+    let mut references = signed_info
+        .children()
+        .filter(|n| n.has_tag_name((NS_DSIG, "Reference")));
+    let (Some(reference), None) = (references.next(), references.next()) else {
+        return Err(invalid("signature must contain one reference"));
+    };
+
+    let id = node
+        .attribute("ID")
+        .filter(|id| !id.is_empty())
+        .ok_or_else(|| invalid("signed element has no ID"))?;
+    if reference.attribute("URI") != Some(format!("#{id}").as_str()) {
+        return Err(invalid(
+            "signature reference doesn't match the signed element",
+        ));
+    }
+    if node
+        .document()
+        .descendants()
+        .filter(|n| n.attribute("ID") == Some(id))
+        .count()
+        != 1
+    {
+        return Err(invalid("duplicate ID"));
+    }
+
+    let mut enveloped = false;
+    let mut prefixes = Vec::new();
+    for transform in child(reference, "Transforms")?
+        .children()
+        .filter(|n| n.has_tag_name((NS_DSIG, "Transform")))
+    {
+        match transform.attribute("Algorithm") {
+            Some(ENVELOPED_SIGNATURE) => enveloped = true,
+            Some(EXC_C14N) => prefixes = inclusive_prefixes(transform),
+            _ => return Err(invalid("unsupported transform")),
+        }
+    }
+    if !enveloped {
+        return Err(invalid("signature must be enveloped"));
+    }
+
+    if algorithm(reference, "DigestMethod")? != SHA256 {
+        return Err(invalid("unsupported digest method"));
+    }
+
+    let digest = Sha256::digest(canonicalize(node, Some(signature), &prefixes));
+    if digest.as_slice() != decode(child(reference, "DigestValue")?)? {
+        return Err(invalid("digest mismatch"));
+    }
+
+    let prefixes = inclusive_prefixes(c14n_method);
+    let signed_info = canonicalize(signed_info, None, &prefixes);
+    let signature_value = decode(child(signature, "SignatureValue")?)?;
+    let signature_value = Signature::try_from(signature_value.as_slice())
+        .map_err(|_| invalid("invalid signature"))?;
+
+    key.verify(signed_info.as_bytes(), &signature_value)
+        .map_err(|_| invalid("invalid signature"))?;
+
+    Ok(true)
+}
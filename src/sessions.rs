@@ -0,0 +1,338 @@
+//! First-party sessions, which let users that signed in for one application sign in to others
+//! without going through the provider again.
+
+use axum::http::{header, HeaderMap};
+use axum_extra::extract::cookie::{Cookie, CookieJar, SameSite};
+use chrono::{DateTime, Duration, Utc};
+use oauth2::ClientId;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{d1, error::Error, gen_string};
+
+/// Name of the cookie that holds the session token.
+pub const COOKIE: &str = "session";
+
+/// Sessions expire this long after signing in, regardless of how often they are used.
+const TTL_DAYS: i64 = 14;
+
+const TOKEN_LEN: usize = 48;
+
+/// Authentication context class of sessions that used a second factor.
+pub const ACR_MFA: &str = "http://schemas.openid.net/pape/policies/2007/06/multi-factor";
+/// Authentication context class of sessions that only used one factor.
+pub const ACR_SINGLE_FACTOR: &str = "urn:auth-worker:single-factor";
+
+/// The browser a user signed in with, so they can tell their sessions apart.
+#[derive(Default, Serialize, Deserialize)]
+pub struct Device {
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+impl Device {
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let get = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+
+        Self {
+            user_agent: get(header::USER_AGENT.as_str()),
+            // Set by Cloudflare to the address of the browser
+            ip: get("CF-Connecting-IP"),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Session {
+    pub id: String,
+    pub user_id: String,
+    /// The connection the user signed in with.
+    pub connection: String,
+    /// JSON array of the authentication methods used to sign in.
+    amr: String,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+impl Session {
+    pub fn amr(&self) -> Vec<String> {
+        serde_json::from_str(&self.amr).unwrap_or_default()
+    }
+
+    /// When the user signed in.
+    pub fn auth_time(&self) -> DateTime<Utc> {
+        self.created_at
+    }
+
+    pub fn acr(&self) -> &'static str {
+        if self.amr().iter().any(|method| method == "mfa") {
+            ACR_MFA
+        } else {
+            ACR_SINGLE_FACTOR
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct SessionInfo {
+    pub id: String,
+    pub connection: String,
+    pub amr: Vec<String>,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+impl From<Session> for SessionInfo {
+    fn from(session: Session) -> Self {
+        Self {
+            amr: session.amr(),
+            id: session.id,
+            connection: session.connection,
+            user_agent: session.user_agent,
+            ip: session.ip,
+            created_at: session.created_at,
+            expires_at: session.expires_at,
+            last_used_at: session.last_used_at,
+        }
+    }
+}
+
+/// The cookie only holds a random token, so that a leaked database or session ID can't be used to
+/// sign in.
+fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token))
+}
+
+fn cookie(token: String, max_age: Duration) -> Cookie<'static> {
+    Cookie::build(COOKIE, token)
+        .path("/")
+        .http_only(true)
+        .secure(true)
+        // Sent along when applications redirect to the authorization endpoint
+        .same_site(SameSite::Lax)
+        .max_age(time::Duration::seconds(max_age.num_seconds()))
+        .finish()
+}
+
+/// The cookie to pass to [`CookieJar::remove`] to sign the browser out.
+pub fn removal_cookie() -> Cookie<'static> {
+    Cookie::build(COOKIE, "").path("/").finish()
+}
+
+/// Start a session for a user that just signed in, returning the cookie to set.
+pub async fn create_session(
+    db: &d1::Database,
+    user_id: &str,
+    connection: &str,
+    amr: &[&str],
+    device: Device,
+) -> Result<(Session, Cookie<'static>), Error> {
+    let token = gen_string(TOKEN_LEN);
+    let now = Utc::now();
+
+    let session = Session {
+        id: gen_string(32),
+        user_id: user_id.to_string(),
+        connection: connection.to_string(),
+        amr: serde_json::to_string(amr).map_err(Error::SerdeJson)?,
+        user_agent: device.user_agent,
+        ip: device.ip,
+        created_at: now,
+        expires_at: now + Duration::days(TTL_DAYS),
+        last_used_at: None,
+    };
+
+    d1::query!(
+        db,
+        r#"
+INSERT INTO sessions (id, token_hash, user_id, connection, amr, user_agent, ip, created_at, expires_at)
+VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
+        "#,
+        &session.id,
+        hash_token(&token),
+        &session.user_id,
+        &session.connection,
+        &session.amr,
+        &session.user_agent,
+        &session.ip,
+        session.created_at,
+        session.expires_at,
+    )
+    .map_err(Error::D1)?
+    .run()
+    .await
+    .map_err(Error::D1)?;
+
+    Ok((session, cookie(token, Duration::days(TTL_DAYS))))
+}
+
+/// Find the unexpired session of the cookie sent by the browser.
+pub async fn find_session(db: &d1::Database, jar: &CookieJar) -> Result<Option<Session>, Error> {
+    let Some(cookie) = jar.get(COOKIE) else {
+        return Ok(None);
+    };
+
+    let session = d1::query!(
+        db,
+        r#"
+SELECT id, user_id, connection, amr, user_agent, ip, created_at, expires_at, last_used_at
+FROM sessions
+WHERE token_hash = ?
+        "#,
+        hash_token(cookie.value()),
+    )
+    .map_err(Error::D1)?
+    .first::<Session>(None)
+    .await
+    .map_err(Error::D1)?;
+
+    Ok(session.filter(|session| session.expires_at > Utc::now()))
+}
+
+pub async fn get_session(db: &d1::Database, id: &str) -> Result<Option<Session>, Error> {
+    let session = d1::query!(
+        db,
+        r#"
+SELECT id, user_id, connection, amr, user_agent, ip, created_at, expires_at, last_used_at
+FROM sessions
+WHERE id = ?
+        "#,
+        id,
+    )
+    .map_err(Error::D1)?
+    .first::<Session>(None)
+    .await
+    .map_err(Error::D1)?;
+
+    Ok(session.filter(|session| session.expires_at > Utc::now()))
+}
+
+/// The unexpired sessions of a user, most recent first.
+pub async fn list_sessions(db: &d1::Database, user_id: &str) -> Result<Vec<Session>, Error> {
+    let sessions = d1::query!(
+        db,
+        r#"
+SELECT id, user_id, connection, amr, user_agent, ip, created_at, expires_at, last_used_at
+FROM sessions
+WHERE user_id = ?
+ORDER BY created_at DESC
+        "#,
+        user_id,
+    )
+    .map_err(Error::D1)?
+    .all()
+    .await
+    .map_err(Error::D1)?
+    .results::<Session>()
+    .map_err(Error::D1)?;
+
+    let now = Utc::now();
+
+    Ok(sessions
+        .into_iter()
+        .filter(|session| session.expires_at > now)
+        .collect())
+}
+
+/// Add the authentication methods of a second factor to a session, which the user used to step up
+/// the session for an application that requires it.
+pub async fn step_up_session(
+    db: &d1::Database,
+    mut session: Session,
+    amr: &[&str],
+) -> Result<Session, Error> {
+    let mut methods = session.amr();
+    for method in amr {
+        if !methods.iter().any(|m| m == method) {
+            methods.push(method.to_string());
+        }
+    }
+    session.amr = serde_json::to_string(&methods).map_err(Error::SerdeJson)?;
+
+    d1::query!(
+        db,
+        "UPDATE sessions SET amr = ?1 WHERE id = ?2",
+        &session.amr,
+        &session.id,
+    )
+    .map_err(Error::D1)?
+    .run()
+    .await
+    .map_err(Error::D1)?;
+
+    Ok(session)
+}
+
+/// Remember that a session signed the user in to an application, to notify the application when
+/// the session ends.
+pub async fn add_session_application(
+    db: &d1::Database,
+    id: &str,
+    client_id: &ClientId,
+) -> Result<(), Error> {
+    d1::query!(
+        db,
+        r#"
+INSERT INTO session_applications (session_id, client_id, created_at)
+VALUES (?, ?, ?)
+ON CONFLICT (session_id, client_id) DO NOTHING
+        "#,
+        id,
+        client_id,
+        Utc::now(),
+    )
+    .map_err(Error::D1)?
+    .run()
+    .await
+    .map_err(Error::D1)?;
+
+    Ok(())
+}
+
+/// Record that a session signed the user in to an application.
+pub async fn touch_session(db: &d1::Database, id: &str) -> Result<(), Error> {
+    d1::query!(
+        db,
+        "UPDATE sessions SET last_used_at = ?1 WHERE id = ?2",
+        Utc::now(),
+        id,
+    )
+    .map_err(Error::D1)?
+    .run()
+    .await
+    .map_err(Error::D1)?;
+
+    Ok(())
+}
+
+pub async fn delete_session(db: &d1::Database, id: &str) -> Result<(), Error> {
+    d1::query!(db, "DELETE FROM sessions WHERE id = ?", id)
+        .map_err(Error::D1)?
+        .run()
+        .await
+        .map_err(Error::D1)?;
+
+    Ok(())
+}
+
+/// End every session of a user, such as after changing their password.
+pub async fn delete_user_sessions(db: &d1::Database, user_id: &str) -> Result<(), Error> {
+    d1::query!(db, "DELETE FROM sessions WHERE user_id = ?", user_id)
+        .map_err(Error::D1)?
+        .run()
+        .await
+        .map_err(Error::D1)?;
+
+    Ok(())
+}
@@ -0,0 +1,240 @@
+#[cfg(test)]
+use std::{cell::RefCell, rc::Rc};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{Duration, Utc};
+use rand::{thread_rng, Rng};
+use reqwest::header;
+use worker::{console_log, Env};
+
+use crate::{d1, error::Error, http_client, rate_limit, AppState};
+
+/// Codes that can be sent to a number within `RATE_LIMIT_MINUTES`.
+const MAX_MESSAGES: u32 = 5;
+const RATE_LIMIT_MINUTES: i64 = 60;
+
+#[derive(Clone, Debug)]
+pub struct Sms {
+    pub to: String,
+    pub body: String,
+}
+
+/// Sends text messages, such as one-time codes.
+pub enum SmsSender {
+    /// Sends messages through Twilio from the `SMS_FROM` number.
+    Twilio {
+        account_sid: String,
+        auth_token: String,
+        from: String,
+    },
+    /// Only logs messages, for local development. Requires `SMS_LOG` to be `true`, so that codes
+    /// don't end up in the logs in production.
+    Log,
+    /// Keeps messages in memory, for tests.
+    #[cfg(test)]
+    Memory(Rc<RefCell<Vec<Sms>>>),
+}
+
+impl SmsSender {
+    pub fn new(env: &Env) -> Result<Self, Error> {
+        Self::from_config(
+            env.var("TWILIO_ACCOUNT_SID")
+                .ok()
+                .map(|var| var.to_string()),
+            env.secret("TWILIO_AUTH_TOKEN")
+                .ok()
+                .map(|secret| secret.to_string()),
+            env.var("SMS_FROM").ok().map(|var| var.to_string()),
+            env.var("SMS_LOG")
+                .map_or(false, |var| var.to_string() == "true"),
+        )
+    }
+
+    /// Twilio when it is configured, otherwise messages are only logged when that is enabled. Twilio
+    /// being partly configured is an error rather than a reason to log codes.
+    fn from_config(
+        account_sid: Option<String>,
+        auth_token: Option<String>,
+        from: Option<String>,
+        log: bool,
+    ) -> Result<Self, Error> {
+        match (account_sid, auth_token, from) {
+            (Some(account_sid), Some(auth_token), Some(from)) => Ok(Self::Twilio {
+                account_sid,
+                auth_token,
+                from,
+            }),
+            (None, None, None) if log => Ok(Self::Log),
+            _ => Err(Error::SmsSenderNotConfigured),
+        }
+    }
+
+    pub async fn send(&self, sms: Sms) -> Result<(), Error> {
+        match self {
+            Self::Twilio {
+                account_sid,
+                auth_token,
+                from,
+            } => {
+                http_client()
+                    .post(format!(
+                        "https://api.twilio.com/2010-04-01/Accounts/{account_sid}/Messages.json"
+                    ))
+                    .header(
+                        header::AUTHORIZATION,
+                        format!(
+                            "Basic {}",
+                            STANDARD.encode(format!("{account_sid}:{auth_token}"))
+                        ),
+                    )
+                    .form(&[("To", &sms.to), ("From", from), ("Body", &sms.body)])
+                    .send()
+                    .await?
+                    .error_for_status()?;
+            }
+            Self::Log => console_log!("sms to {}: {}", sms.to, sms.body),
+            #[cfg(test)]
+            Self::Memory(outbox) => outbox.borrow_mut().push(sms),
+        }
+
+        Ok(())
+    }
+}
+
+/// Normalize a phone number to E.164, such as `+14155552671`. Only the separators people commonly
+/// type are removed, the country code is required.
+pub fn normalize_phone_number(phone_number: &str) -> Result<String, Error> {
+    let normalized = phone_number
+        .chars()
+        .filter(|c| !matches!(c, ' ' | '-' | '(' | ')' | '.'))
+        .collect::<String>();
+
+    let digits = normalized
+        .strip_prefix('+')
+        .ok_or(Error::InvalidPhoneNumber)?;
+    if !(8..=15).contains(&digits.len())
+        || !digits.bytes().all(|c| c.is_ascii_digit())
+        || digits.starts_with('0')
+    {
+        return Err(Error::InvalidPhoneNumber);
+    }
+
+    Ok(normalized)
+}
+
+pub fn gen_code() -> String {
+    format!("{:06}", thread_rng().gen_range(0..1_000_000))
+}
+
+/// Count a message to a number, failing when too many were sent recently. This limits the cost of
+/// messages and the attempts to guess codes.
+pub async fn check_rate_limit(state: &AppState, phone_number: &str) -> Result<(), Error> {
+    rate_limit::check_rate_limit(
+        state,
+        &format!("sms-rate-limit:{phone_number}"),
+        MAX_MESSAGES,
+        Duration::minutes(RATE_LIMIT_MINUTES),
+    )
+    .await
+}
+
+/// Store a phone number of a user that proved to receive messages at it.
+pub async fn set_phone_verified(
+    db: &d1::Database,
+    user_id: &str,
+    phone_number: &str,
+) -> Result<(), Error> {
+    d1::query!(
+        db,
+        r#"
+UPDATE users
+SET phone_number = ?1, phone_verified = 1, updated_at = ?2
+WHERE id = ?3
+        "#,
+        phone_number,
+        Utc::now(),
+        user_id,
+    )
+    .map_err(Error::D1)?
+    .run()
+    .await
+    .map_err(Error::D1)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(
+        account_sid: Option<&str>,
+        auth_token: Option<&str>,
+        from: Option<&str>,
+        log: bool,
+    ) -> Result<SmsSender, Error> {
+        SmsSender::from_config(
+            account_sid.map(Into::into),
+            auth_token.map(Into::into),
+            from.map(Into::into),
+            log,
+        )
+    }
+
+    #[test]
+    fn sends_through_twilio() {
+        let sender = config(Some("AC123"), Some("token"), Some("+14155552671"), true).unwrap();
+
+        assert!(matches!(sender, SmsSender::Twilio { from, .. } if from == "+14155552671"));
+    }
+
+    #[test]
+    fn rejects_partial_twilio_config() {
+        for (account_sid, auth_token, from) in [
+            (None, Some("token"), Some("+14155552671")),
+            (Some("AC123"), None, Some("+14155552671")),
+            (Some("AC123"), Some("token"), None),
+        ] {
+            assert!(matches!(
+                config(account_sid, auth_token, from, true),
+                Err(Error::SmsSenderNotConfigured)
+            ));
+        }
+    }
+
+    #[test]
+    fn logs_only_when_enabled() {
+        assert!(matches!(config(None, None, None, true), Ok(SmsSender::Log)));
+        assert!(matches!(
+            config(None, None, None, false),
+            Err(Error::SmsSenderNotConfigured)
+        ));
+    }
+
+    #[test]
+    fn normalizes_phone_numbers() {
+        assert_eq!(
+            normalize_phone_number("+1 (415) 555-2671").unwrap(),
+            "+14155552671"
+        );
+        assert_eq!(
+            normalize_phone_number("+44.20.7946.0958").unwrap(),
+            "+442079460958"
+        );
+
+        for phone_number in ["4155552671", "+0155552671", "+1415", "+1415555267a"] {
+            assert!(matches!(
+                normalize_phone_number(phone_number),
+                Err(Error::InvalidPhoneNumber)
+            ));
+        }
+    }
+
+    #[test]
+    fn generates_six_digit_codes() {
+        let code = gen_code();
+
+        assert_eq!(code.len(), 6);
+        assert!(code.bytes().all(|c| c.is_ascii_digit()));
+    }
+}
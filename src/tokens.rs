@@ -1,24 +1,70 @@
-use chrono::{Duration, Utc};
-use oauth2::{AccessToken, AuthorizationCode, ClientId, RefreshToken};
+use std::collections::HashSet;
+
+use chrono::{DateTime, Duration, Utc};
+use oauth2::{
+    basic::BasicErrorResponseType, AccessToken, AuthorizationCode, ClientId, RefreshToken, Scope,
+};
 use openidconnect::{
-    core::{CoreIdToken, CoreIdTokenClaims, CoreJwsSigningAlgorithm},
-    Audience, EmptyAdditionalClaims, EndUserEmail, EndUserFamilyName, EndUserGivenName,
-    EndUserName, EndUserNickname, EndUserPhoneNumber, EndUserPictureUrl, EndUserUsername,
-    IssuerUrl, StandardClaims, SubjectIdentifier,
+    core::{
+        CoreGenderClaim, CoreIdTokenVerifier, CoreJsonWebKeyType,
+        CoreJweContentEncryptionAlgorithm, CoreJwsSigningAlgorithm, CoreTokenType,
+    },
+    Audience, AuthenticationContextClass, AuthenticationMethodReference, EmptyExtraTokenFields,
+    EndUserEmail, EndUserFamilyName, EndUserGivenName, EndUserName, EndUserNickname,
+    EndUserPhoneNumber, EndUserPictureUrl, EndUserUsername, IssuerUrl, Nonce, StandardClaims,
+    StandardTokenResponse, SubjectIdentifier,
 };
+use serde::{Deserialize, Serialize};
 
-use crate::{error::Error, gen_string, keys::get_rsa_key, users::User, AppState};
+use crate::{
+    auth::{revocation::touch_tokens, states::TokenMetadata},
+    error::Error,
+    gen_string,
+    keys::{get_jwks, get_rsa_key},
+    sessions::Session,
+    users::{Identity, User},
+    AppState,
+};
 
-pub async fn id_token(
-    state: &AppState,
-    client_id: &ClientId,
-    code: &AuthorizationCode,
-    user: User,
-    access_token: &AccessToken,
-) -> Result<CoreIdToken, Error> {
-    let signing_key = get_rsa_key(state).await?.ok_or(Error::MissingKeys)?;
+/// Scope that adds the `identities` claim to the ID token.
+pub const IDENTITIES_SCOPE: &str = "identities";
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AdditionalClaims {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub identities: Option<Vec<Identity>>,
+    /// ID of the session, which is also sent in back-channel logout tokens.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sid: Option<String>,
+}
+
+impl openidconnect::AdditionalClaims for AdditionalClaims {}
 
-    let claims = StandardClaims::new(SubjectIdentifier::new(user.id))
+pub type IdToken = openidconnect::IdToken<
+    AdditionalClaims,
+    CoreGenderClaim,
+    CoreJweContentEncryptionAlgorithm,
+    CoreJwsSigningAlgorithm,
+    CoreJsonWebKeyType,
+>;
+
+pub type IdTokenClaims = openidconnect::IdTokenClaims<AdditionalClaims, CoreGenderClaim>;
+
+pub type IdTokenFields = openidconnect::IdTokenFields<
+    AdditionalClaims,
+    EmptyExtraTokenFields,
+    CoreGenderClaim,
+    CoreJweContentEncryptionAlgorithm,
+    CoreJwsSigningAlgorithm,
+    CoreJsonWebKeyType,
+>;
+
+pub type TokenResponse = StandardTokenResponse<IdTokenFields, CoreTokenType>;
+
+pub type UserInfoClaims = openidconnect::UserInfoClaims<AdditionalClaims, CoreGenderClaim>;
+
+fn standard_claims(user: User) -> StandardClaims<CoreGenderClaim> {
+    StandardClaims::new(SubjectIdentifier::new(user.id))
         .set_email(user.email.map(EndUserEmail::new))
         .set_email_verified(user.email_verified)
         .set_family_name(user.family_name.map(EndUserFamilyName::new).map(Into::into))
@@ -32,17 +78,48 @@ pub async fn id_token(
                 .map(EndUserPhoneNumber::new)
                 .map(Into::into),
         )
-        .set_phone_number_verified(user.phone_verified);
+        .set_phone_number_verified(user.phone_verified)
+}
 
-    let id_token = CoreIdToken::new(
-        CoreIdTokenClaims::new(
+pub async fn id_token(
+    state: &AppState,
+    client_id: &ClientId,
+    code: &AuthorizationCode,
+    scopes: &HashSet<Scope>,
+    mut user: User,
+    session: &Session,
+    access_token: &AccessToken,
+) -> Result<IdToken, Error> {
+    let signing_key = get_rsa_key(state).await?.ok_or(Error::MissingKeys)?;
+
+    let identities = std::mem::take(&mut user.identities);
+    let additional_claims = AdditionalClaims {
+        identities: scopes
+            .contains(&Scope::new(IDENTITIES_SCOPE.to_string()))
+            .then_some(identities),
+        sid: Some(session.id.clone()),
+    };
+
+    let amr = session.amr();
+
+    let id_token = IdToken::new(
+        IdTokenClaims::new(
             IssuerUrl::new(env!("DOMAIN").to_string()).expect("invalid issuer URL"),
             vec![Audience::new(client_id.to_string())],
             Utc::now() + Duration::seconds(36000),
             Utc::now(),
-            claims,
-            EmptyAdditionalClaims {},
-        ),
+            standard_claims(user),
+            additional_claims,
+        )
+        .set_auth_time(Some(session.auth_time()))
+        .set_auth_context_ref(Some(AuthenticationContextClass::new(
+            session.acr().to_string(),
+        )))
+        .set_auth_method_refs((!amr.is_empty()).then(|| {
+            amr.into_iter()
+                .map(AuthenticationMethodReference::new)
+                .collect()
+        })),
         &signing_key,
         CoreJwsSigningAlgorithm::RsaSsaPkcs1V15Sha256,
         Some(access_token),
@@ -53,6 +130,68 @@ pub async fn id_token(
     Ok(id_token)
 }
 
+/// Verify an ID token that an application sends back as hint of the user, such as when signing
+/// them out. The token was issued by us, but may be expired by now.
+pub async fn verify_id_token_hint(state: &AppState, hint: &str) -> Result<IdTokenClaims, Error> {
+    let invalid_hint = || {
+        Error::OAuth2(
+            BasicErrorResponseType::InvalidRequest,
+            "invalid id_token_hint".into(),
+        )
+    };
+
+    let id_token = hint.parse::<IdToken>().map_err(|_| invalid_hint())?;
+
+    // The audience is checked by the caller, since any application can send the hint
+    let verifier = CoreIdTokenVerifier::new_public_client(
+        ClientId::new(String::new()),
+        IssuerUrl::new(env!("DOMAIN").to_string()).expect("invalid issuer URL"),
+        get_jwks(state).await?,
+    )
+    .require_audience_match(false)
+    .set_time_fn(|| DateTime::<Utc>::MIN_UTC);
+
+    id_token
+        .into_claims(&verifier, |_: Option<&Nonce>| Ok(()))
+        .map_err(|_| invalid_hint())
+}
+
+pub fn user_info_claims(mut user: User) -> UserInfoClaims {
+    let additional_claims = AdditionalClaims {
+        identities: Some(std::mem::take(&mut user.identities)),
+        sid: None,
+    };
+
+    UserInfoClaims::new(standard_claims(user), additional_claims)
+}
+
+/// KV key of the metadata of an access token.
+pub fn access_token_key(token: &str) -> String {
+    format!("token:access:{token}")
+}
+
+/// KV key of the metadata of a refresh token.
+pub fn refresh_token_key(token: &str) -> String {
+    format!("token:refresh:{token}")
+}
+
+pub async fn get_access_token(state: &AppState, token: &str) -> Result<TokenMetadata, Error> {
+    let token_meta = state
+        .kv
+        .get(&access_token_key(token))
+        .json::<TokenMetadata>()
+        .await
+        .map_err(Error::Kv)?
+        .ok_or(Error::InvalidAccessToken)?;
+
+    // Tokens issued before they were indexed in D1 don't have an ID
+    if let Some(token_id) = &token_meta.token_id {
+        touch_tokens(&state.db, token_id).await?;
+    }
+
+    Ok(token_meta)
+}
+
 pub struct AccessRefreshTokenSet {
     pub access_token: AccessToken,
     pub expires_in: Duration,
@@ -0,0 +1,37 @@
+use axum::{
+    extract::State,
+    headers::{authorization::Bearer, Authorization},
+    response::IntoResponse,
+    Json, TypedHeader,
+};
+use futures::channel::oneshot;
+
+use crate::{error::Error, tokens, users::get_user, AppState};
+
+async fn userinfo_impl(
+    state: AppState,
+    authorization: Authorization<Bearer>,
+) -> Result<impl IntoResponse, Error> {
+    let token_meta = tokens::get_access_token(&state, authorization.token()).await?;
+
+    let user = get_user(&state.db, &token_meta.user_id)
+        .await
+        .map_err(Error::D1)?
+        .ok_or(Error::UserNotFound)?;
+
+    Ok(Json(tokens::user_info_claims(user)))
+}
+
+pub async fn userinfo(
+    State(state): State<AppState>,
+    TypedHeader(req): TypedHeader<Authorization<Bearer>>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let res = userinfo_impl(state, req).await;
+        tx.send(res).map_err(|_| ()).unwrap();
+    });
+
+    rx.await.unwrap()
+}
@@ -1,17 +1,39 @@
+use chrono::{DateTime, Utc};
 use openidconnect::core::CoreUserInfoClaims;
 use serde::{Deserialize, Serialize};
 
+use crate::d1::deserialize_option_bool;
+
+mod get;
+mod identities;
 mod upsert;
 
+pub use get::get_user;
+pub use identities::{get_identities, upsert_identity};
 pub use upsert::upsert_user;
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Identity {
+    pub provider: String,
+    pub provider_user_id: String,
+    /// The tenant the user signed in through, for multi-tenant providers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant_id: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct User {
     pub id: String,
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub email: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "deserialize_option_bool"
+    )]
     pub email_verified: Option<bool>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -33,10 +55,14 @@ pub struct User {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub updated_at: Option<String>,
 
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "deserialize_option_bool"
+    )]
     pub blocked: Option<bool>,
-    #[serde(skip_serializing_if = "Vec::is_empty")]
-    pub identities: Vec<()>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub identities: Vec<Identity>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub last_ip: Option<String>,
@@ -51,7 +77,11 @@ pub struct User {
     pub multifactor: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub phone_number: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "deserialize_option_bool"
+    )]
     pub phone_verified: Option<bool>,
 }
 
@@ -88,9 +118,11 @@ impl User {
         }
     }
 
-    pub fn from_claims(provider: &str, claims: CoreUserInfoClaims) -> Self {
+    /// Create a user from the claims of an OIDC provider. The user ID is the subject at the
+    /// provider.
+    pub fn from_claims(claims: &CoreUserInfoClaims) -> Self {
         Self {
-            id: format!("{provider}|{}", claims.subject().to_string()),
+            id: claims.subject().to_string(),
 
             email: claims.email().map(|x| x.to_string()),
             email_verified: claims.email_verified(),
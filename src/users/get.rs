@@ -0,0 +1,44 @@
+use crate::{d1, users::User};
+
+use super::get_identities;
+
+pub async fn get_user(db: &d1::Database, id: &str) -> worker::Result<Option<User>> {
+    let user = d1::query!(
+        db,
+        r#"
+SELECT
+    id,
+    email,
+    email_verified,
+    family_name,
+    given_name,
+    username,
+    name,
+    nickname,
+    picture,
+    created_at,
+    updated_at,
+    blocked,
+    last_ip,
+    last_login,
+    last_password_reset,
+    logins_count,
+    multifactor,
+    phone_number,
+    phone_verified
+FROM users
+WHERE id = ?
+        "#,
+        id,
+    )?
+    .first::<User>(None)
+    .await?;
+
+    let Some(mut user) = user else {
+        return Ok(None);
+    };
+
+    user.identities = get_identities(db, &user.id).await?;
+
+    Ok(Some(user))
+}
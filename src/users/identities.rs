@@ -0,0 +1,48 @@
+use chrono::Utc;
+
+use crate::{
+    d1,
+    users::{Identity, User},
+};
+
+pub async fn upsert_identity(
+    db: &d1::Database,
+    user: &User,
+    provider: &str,
+    provider_user_id: &str,
+    tenant_id: Option<&str>,
+) -> worker::Result<d1::QueryResult> {
+    d1::query!(
+        db,
+        r#"
+INSERT INTO identities (provider, provider_user_id, user_id, tenant_id, created_at, updated_at)
+VALUES (?1, ?2, ?3, ?4, ?5, ?5)
+ON CONFLICT (provider, provider_user_id) DO UPDATE SET
+    tenant_id = excluded.tenant_id,
+    updated_at = excluded.updated_at
+        "#,
+        provider,
+        provider_user_id,
+        user.id,
+        tenant_id,
+        Utc::now(),
+    )?
+    .run()
+    .await
+}
+
+pub async fn get_identities(db: &d1::Database, user_id: &str) -> worker::Result<Vec<Identity>> {
+    d1::query!(
+        db,
+        r#"
+SELECT provider, provider_user_id, tenant_id, created_at, updated_at
+FROM identities
+WHERE user_id = ?
+ORDER BY created_at
+        "#,
+        user_id,
+    )?
+    .all()
+    .await?
+    .results::<Identity>()
+}
@@ -0,0 +1,653 @@
+//! WebAuthn relying party for passkeys, supporting ES256 and RS256 credentials.
+//!
+//! See <https://www.w3.org/TR/webauthn-2/>.
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Utc};
+use rand::{thread_rng, Rng};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use wasm_bindgen_futures::JsFuture;
+
+use crate::{d1, error::Error};
+
+mod cbor;
+
+mod sys {
+    use wasm_bindgen::prelude::wasm_bindgen;
+    use worker::js_sys::Promise;
+
+    #[wasm_bindgen(module = "/js/webauthn.js")]
+    extern "C" {
+        pub fn verify(jwk: &str, data: &[u8], signature: &[u8]) -> Promise;
+    }
+}
+
+const ES256: i128 = -7;
+const RS256: i128 = -257;
+
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_USER_VERIFIED: u8 = 0x04;
+const FLAG_ATTESTED_CREDENTIAL_DATA: u8 = 0x40;
+
+/// How long a ceremony may take, which is also how long its challenge is kept.
+pub const TIMEOUT_SECONDS: u64 = 300;
+
+fn invalid(reason: &str) -> Error {
+    Error::InvalidPasskey(reason.to_string())
+}
+
+fn decode(s: &str) -> Result<Vec<u8>, Error> {
+    URL_SAFE_NO_PAD
+        .decode(s.trim_end_matches('='))
+        .map_err(|_| invalid("invalid base64url"))
+}
+
+/// The relying party ID, which is the host of the Worker.
+pub fn rp_id() -> String {
+    Url::parse(env!("DOMAIN"))
+        .unwrap()
+        .host_str()
+        .unwrap()
+        .to_string()
+}
+
+fn origin() -> String {
+    Url::parse(env!("DOMAIN"))
+        .unwrap()
+        .origin()
+        .ascii_serialization()
+}
+
+pub fn challenge() -> String {
+    URL_SAFE_NO_PAD.encode(thread_rng().gen::<[u8; 32]>())
+}
+
+/// The user handle that is stored on authenticators, which is derived from the user ID so that it
+/// doesn't reveal it and stays within the 64 byte limit.
+fn user_handle(user_id: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(user_id))
+}
+
+#[derive(Deserialize)]
+pub struct Passkey {
+    pub id: String,
+    pub user_id: String,
+    pub name: Option<String>,
+    /// JWK of the public key.
+    public_key: String,
+    sign_count: u32,
+    /// JSON array of the transports the authenticator supports, such as `internal` or `usb`.
+    transports: String,
+    created_at: DateTime<Utc>,
+    last_used_at: Option<DateTime<Utc>>,
+}
+
+impl Passkey {
+    fn transports(&self) -> Vec<String> {
+        serde_json::from_str(&self.transports).unwrap_or_default()
+    }
+
+    fn descriptor(&self) -> Value {
+        json!({
+            "type": "public-key",
+            "id": self.id,
+            "transports": self.transports(),
+        })
+    }
+}
+
+#[derive(Serialize)]
+pub struct PasskeyInfo {
+    pub id: String,
+    pub name: Option<String>,
+    pub transports: Vec<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+impl From<Passkey> for PasskeyInfo {
+    fn from(passkey: Passkey) -> Self {
+        Self {
+            transports: passkey.transports(),
+            id: passkey.id,
+            name: passkey.name,
+            created_at: passkey.created_at,
+            last_used_at: passkey.last_used_at,
+        }
+    }
+}
+
+/// A new credential from `navigator.credentials.create()`, encoded like
+/// `PublicKeyCredential.toJSON()`.
+#[derive(Deserialize)]
+pub struct RegistrationCredential {
+    pub id: String,
+    pub response: AttestationResponse,
+}
+
+#[derive(Deserialize)]
+pub struct AttestationResponse {
+    #[serde(rename = "clientDataJSON")]
+    client_data_json: String,
+    #[serde(rename = "attestationObject")]
+    attestation_object: String,
+    #[serde(default)]
+    transports: Vec<String>,
+}
+
+/// An assertion from `navigator.credentials.get()`, encoded like `PublicKeyCredential.toJSON()`.
+#[derive(Deserialize)]
+pub struct AuthenticationCredential {
+    pub id: String,
+    pub response: AssertionResponse,
+}
+
+#[derive(Deserialize)]
+pub struct AssertionResponse {
+    #[serde(rename = "clientDataJSON")]
+    client_data_json: String,
+    #[serde(rename = "authenticatorData")]
+    authenticator_data: String,
+    signature: String,
+}
+
+/// A verified credential that is not stored yet.
+pub struct NewPasskey {
+    id: String,
+    public_key: String,
+    sign_count: u32,
+    transports: Vec<String>,
+}
+
+/// Options for `navigator.credentials.create()`, which registers a discoverable credential.
+pub fn creation_options(
+    challenge: &str,
+    user_id: &str,
+    name: &str,
+    display_name: &str,
+    exclude: &[Passkey],
+) -> Value {
+    json!({
+        "challenge": challenge,
+        "rp": { "id": rp_id(), "name": rp_id() },
+        "user": {
+            "id": user_handle(user_id),
+            "name": name,
+            "displayName": display_name,
+        },
+        "pubKeyCredParams": [
+            { "type": "public-key", "alg": ES256 as i64 },
+            { "type": "public-key", "alg": RS256 as i64 },
+        ],
+        "timeout": TIMEOUT_SECONDS * 1000,
+        "excludeCredentials": exclude.iter().map(Passkey::descriptor).collect::<Vec<_>>(),
+        "authenticatorSelection": {
+            "residentKey": "required",
+            "requireResidentKey": true,
+            "userVerification": "required",
+        },
+        "attestation": "none",
+    })
+}
+
+/// Options for `navigator.credentials.get()`. Without allowed credentials, the user picks one of
+/// their discoverable credentials.
+pub fn request_options(challenge: &str, allow: &[Passkey], user_verification: bool) -> Value {
+    json!({
+        "challenge": challenge,
+        "rpId": rp_id(),
+        "timeout": TIMEOUT_SECONDS * 1000,
+        "allowCredentials": allow.iter().map(Passkey::descriptor).collect::<Vec<_>>(),
+        "userVerification": if user_verification { "required" } else { "discouraged" },
+    })
+}
+
+#[derive(Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    ty: String,
+    challenge: String,
+    origin: String,
+}
+
+fn verify_client_data(client_data_json: &[u8], ty: &str, challenge: &str) -> Result<(), Error> {
+    let client_data = serde_json::from_slice::<ClientData>(client_data_json)
+        .map_err(|_| invalid("invalid client data"))?;
+
+    if client_data.ty != ty {
+        return Err(invalid("wrong ceremony"));
+    }
+
+    if client_data.challenge.trim_end_matches('=') != challenge {
+        return Err(invalid("wrong challenge"));
+    }
+
+    if client_data.origin != origin() {
+        return Err(invalid("wrong origin"));
+    }
+
+    Ok(())
+}
+
+struct AuthenticatorData {
+    sign_count: u32,
+    /// The credential ID and public key, only present when registering.
+    credential: Option<(Vec<u8>, cbor::Value)>,
+}
+
+fn parse_authenticator_data(
+    data: &[u8],
+    user_verification: bool,
+) -> Result<AuthenticatorData, Error> {
+    if data.len() < 37 {
+        return Err(invalid("invalid authenticator data"));
+    }
+
+    let (rp_id_hash, flags, sign_count) = (&data[..32], data[32], &data[33..37]);
+
+    if rp_id_hash != Sha256::digest(rp_id()).as_slice() {
+        return Err(invalid("wrong relying party"));
+    }
+
+    if flags & FLAG_USER_PRESENT == 0 {
+        return Err(invalid("user not present"));
+    }
+
+    if user_verification && flags & FLAG_USER_VERIFIED == 0 {
+        return Err(invalid("user not verified"));
+    }
+
+    let sign_count = u32::from_be_bytes(sign_count.try_into().unwrap());
+
+    let credential = if flags & FLAG_ATTESTED_CREDENTIAL_DATA != 0 {
+        // AAGUID, followed by the length of the credential ID
+        let rest = &data[37..];
+        if rest.len() < 18 {
+            return Err(invalid("invalid attested credential data"));
+        }
+
+        let len = u16::from_be_bytes([rest[16], rest[17]]) as usize;
+        let rest = &rest[18..];
+        if rest.len() < len {
+            return Err(invalid("invalid attested credential data"));
+        }
+
+        let (id, rest) = rest.split_at(len);
+        let (key, _) = cbor::decode(rest).ok_or_else(|| invalid("invalid public key"))?;
+
+        Some((id.to_vec(), key))
+    } else {
+        None
+    };
+
+    Ok(AuthenticatorData {
+        sign_count,
+        credential,
+    })
+}
+
+/// Convert a COSE public key to a JWK, which WebCrypto can import.
+fn cose_to_jwk(key: &cbor::Value) -> Result<String, Error> {
+    let param = |label: i128| {
+        key.get(label)
+            .and_then(cbor::Value::as_bytes)
+            .map(|bytes| URL_SAFE_NO_PAD.encode(bytes))
+            .ok_or_else(|| invalid("invalid public key"))
+    };
+
+    let jwk = match key.get(3_i128).and_then(cbor::Value::as_integer) {
+        // EC2 key on P-256
+        Some(ES256)
+            if key.get(1_i128) == Some(&cbor::Value::Integer(2))
+                && key.get(-1_i128) == Some(&cbor::Value::Integer(1)) =>
+        {
+            json!({
+                "kty": "EC",
+                "crv": "P-256",
+                "x": param(-2)?,
+                "y": param(-3)?,
+            })
+        }
+        Some(RS256) if key.get(1_i128) == Some(&cbor::Value::Integer(3)) => json!({
+            "kty": "RSA",
+            "alg": "RS256",
+            "n": param(-1)?,
+            "e": param(-2)?,
+        }),
+        _ => return Err(invalid("unsupported algorithm")),
+    };
+
+    Ok(jwk.to_string())
+}
+
+/// Verify a new credential. Attestation statements are not checked, since no attestation is
+/// requested.
+pub fn verify_registration(
+    credential: RegistrationCredential,
+    challenge: &str,
+) -> Result<NewPasskey, Error> {
+    let client_data_json = decode(&credential.response.client_data_json)?;
+    verify_client_data(&client_data_json, "webauthn.create", challenge)?;
+
+    let attestation_object = decode(&credential.response.attestation_object)?;
+    let (attestation_object, _) =
+        cbor::decode(&attestation_object).ok_or_else(|| invalid("invalid attestation object"))?;
+    let authenticator_data = attestation_object
+        .get("authData")
+        .and_then(cbor::Value::as_bytes)
+        .ok_or_else(|| invalid("invalid attestation object"))?;
+
+    let authenticator_data = parse_authenticator_data(authenticator_data, true)?;
+    let (id, public_key) = authenticator_data
+        .credential
+        .ok_or_else(|| invalid("missing credential"))?;
+
+    let id = URL_SAFE_NO_PAD.encode(id);
+    if id != credential.id.trim_end_matches('=') {
+        return Err(invalid("credential ID mismatch"));
+    }
+
+    Ok(NewPasskey {
+        id,
+        public_key: cose_to_jwk(&public_key)?,
+        sign_count: authenticator_data.sign_count,
+        transports: credential.response.transports,
+    })
+}
+
+/// Verify an assertion of a stored credential, returning its new signature counter.
+pub async fn verify_authentication(
+    credential: &AuthenticationCredential,
+    challenge: &str,
+    passkey: &Passkey,
+    user_verification: bool,
+) -> Result<u32, Error> {
+    let client_data_json = decode(&credential.response.client_data_json)?;
+    verify_client_data(&client_data_json, "webauthn.get", challenge)?;
+
+    let authenticator_data = decode(&credential.response.authenticator_data)?;
+    let parsed = parse_authenticator_data(&authenticator_data, user_verification)?;
+
+    let mut signed = authenticator_data;
+    signed.extend_from_slice(&Sha256::digest(&client_data_json));
+
+    let signature = decode(&credential.response.signature)?;
+
+    let valid = JsFuture::from(sys::verify(&passkey.public_key, &signed, &signature))
+        .await
+        .map_err(Error::Crypto)?
+        .as_bool()
+        .unwrap_or(false);
+    if !valid {
+        return Err(invalid("invalid signature"));
+    }
+
+    // Authenticators that count signatures must always increase the counter, otherwise the
+    // credential may have been cloned
+    if (parsed.sign_count != 0 || passkey.sign_count != 0)
+        && parsed.sign_count <= passkey.sign_count
+    {
+        return Err(invalid("signature counter did not increase"));
+    }
+
+    Ok(parsed.sign_count)
+}
+
+pub async fn get_passkey(db: &d1::Database, id: &str) -> Result<Option<Passkey>, Error> {
+    d1::query!(
+        db,
+        r#"
+SELECT id, user_id, name, public_key, sign_count, transports, created_at, last_used_at
+FROM webauthn_credentials
+WHERE id = ?
+        "#,
+        id.trim_end_matches('='),
+    )
+    .map_err(Error::D1)?
+    .first::<Passkey>(None)
+    .await
+    .map_err(Error::D1)
+}
+
+pub async fn list_passkeys(db: &d1::Database, user_id: &str) -> Result<Vec<Passkey>, Error> {
+    d1::query!(
+        db,
+        r#"
+SELECT id, user_id, name, public_key, sign_count, transports, created_at, last_used_at
+FROM webauthn_credentials
+WHERE user_id = ?
+ORDER BY created_at
+        "#,
+        user_id,
+    )
+    .map_err(Error::D1)?
+    .all()
+    .await
+    .map_err(Error::D1)?
+    .results::<Passkey>()
+    .map_err(Error::D1)
+}
+
+pub async fn insert_passkey(
+    db: &d1::Database,
+    user_id: &str,
+    name: Option<&str>,
+    passkey: &NewPasskey,
+) -> Result<(), Error> {
+    if get_passkey(db, &passkey.id).await?.is_some() {
+        return Err(invalid("passkey is already registered"));
+    }
+
+    d1::query!(
+        db,
+        r#"
+INSERT INTO webauthn_credentials (id, user_id, name, public_key, sign_count, transports, created_at)
+VALUES (?, ?, ?, ?, ?, ?, ?)
+        "#,
+        passkey.id,
+        user_id,
+        name,
+        passkey.public_key,
+        passkey.sign_count,
+        serde_json::to_string(&passkey.transports).map_err(Error::SerdeJson)?,
+        Utc::now(),
+    )
+    .map_err(Error::D1)?
+    .run()
+    .await
+    .map_err(Error::D1)?;
+
+    Ok(())
+}
+
+pub async fn update_sign_count(db: &d1::Database, id: &str, sign_count: u32) -> Result<(), Error> {
+    d1::query!(
+        db,
+        r#"
+UPDATE webauthn_credentials
+SET sign_count = ?1, last_used_at = ?2
+WHERE id = ?3
+        "#,
+        sign_count,
+        Utc::now(),
+        id,
+    )
+    .map_err(Error::D1)?
+    .run()
+    .await
+    .map_err(Error::D1)?;
+
+    Ok(())
+}
+
+/// Delete a passkey of a user, returning whether it existed.
+pub async fn delete_passkey(db: &d1::Database, user_id: &str, id: &str) -> Result<bool, Error> {
+    let deleted = d1::query!(
+        db,
+        r#"
+DELETE FROM webauthn_credentials
+WHERE user_id = ? AND id = ?
+RETURNING id
+        "#,
+        user_id,
+        id,
+    )
+    .map_err(Error::D1)?
+    .first::<String>(Some("id"))
+    .await
+    .map_err(Error::D1)?;
+
+    Ok(deleted.is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A registration and assertion of an ES256 credential for `localhost` from a software
+    // authenticator, without attestation
+    const ATTESTATION_OBJECT: &str = "o2NmbXRkbm9uZWdhdHRTdG10oGhhdXRoRGF0YVikSZYN5YgOjGh0NBcPZHZgW4_krrmihjLHmVzzuoMdl2NFAAAAAAAAAAAAAAAAAAAAAAAAAAAAIExG8AMpsVQQxsvdRRjEFIzFw1Xr03nd-Mqf3sVg0EL3pQECAyYgASFYIPYFBYJeSLniHX2zgga-Da3yeNtl8DkYl3xDOiB2HIiiIlggUbAoNyKOGA4jlD1vYqCEJx87st8iBNpGaJ_IM9WOgBo";
+    const AUTHENTICATOR_DATA: &str = "SZYN5YgOjGh0NBcPZHZgW4_krrmihjLHmVzzuoMdl2MFAAAAAQ";
+    const CREDENTIAL_ID: &str = "TEbwAymxVBDGy91FGMQUjMXDVevTed34yp_exWDQQvc";
+
+    /// Authenticator data for the configured relying party instead of `localhost`. Replacing the
+    /// hash only invalidates the signature, which isn't checked by parsing.
+    fn for_rp_id(mut data: Vec<u8>) -> Vec<u8> {
+        data[..32].copy_from_slice(&Sha256::digest(rp_id()));
+        data
+    }
+
+    fn registration_data() -> Vec<u8> {
+        let (attestation_object, _) = cbor::decode(&decode(ATTESTATION_OBJECT).unwrap()).unwrap();
+        let data = attestation_object
+            .get("authData")
+            .and_then(cbor::Value::as_bytes)
+            .unwrap();
+
+        for_rp_id(data.to_vec())
+    }
+
+    fn assertion_data() -> Vec<u8> {
+        for_rp_id(decode(AUTHENTICATOR_DATA).unwrap())
+    }
+
+    fn reason<T>(result: Result<T, Error>) -> String {
+        match result {
+            Err(Error::InvalidPasskey(reason)) => reason,
+            _ => panic!("expected an invalid passkey"),
+        }
+    }
+
+    #[test]
+    fn parses_registration() {
+        let data = parse_authenticator_data(&registration_data(), true).unwrap();
+        assert_eq!(data.sign_count, 0);
+
+        let (id, public_key) = data.credential.unwrap();
+        assert_eq!(id, decode(CREDENTIAL_ID).unwrap());
+
+        let jwk = serde_json::from_str::<Value>(&cose_to_jwk(&public_key).unwrap()).unwrap();
+        assert_eq!(
+            jwk,
+            json!({
+                "kty": "EC",
+                "crv": "P-256",
+                "x": "9gUFgl5IueIdfbOCBr4NrfJ422XwORiXfEM6IHYciKI",
+                "y": "UbAoNyKOGA4jlD1vYqCEJx87st8iBNpGaJ_IM9WOgBo",
+            })
+        );
+    }
+
+    #[test]
+    fn parses_assertion() {
+        let data = parse_authenticator_data(&assertion_data(), true).unwrap();
+
+        assert_eq!(data.sign_count, 1);
+        assert!(data.credential.is_none());
+    }
+
+    #[test]
+    fn rejects_other_relying_party() {
+        let mut data = assertion_data();
+        data[0] ^= 1;
+
+        assert_eq!(
+            reason(parse_authenticator_data(&data, false)),
+            "wrong relying party"
+        );
+    }
+
+    #[test]
+    fn checks_user_flags() {
+        let mut data = assertion_data();
+        data[32] &= !FLAG_USER_VERIFIED;
+
+        assert!(parse_authenticator_data(&data, false).is_ok());
+        assert_eq!(
+            reason(parse_authenticator_data(&data, true)),
+            "user not verified"
+        );
+
+        data[32] &= !FLAG_USER_PRESENT;
+        assert_eq!(
+            reason(parse_authenticator_data(&data, false)),
+            "user not present"
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_data() {
+        let data = registration_data();
+
+        assert_eq!(
+            reason(parse_authenticator_data(&data[..36], false)),
+            "invalid authenticator data"
+        );
+        assert_eq!(
+            reason(parse_authenticator_data(&data[..60], false)),
+            "invalid attested credential data"
+        );
+        assert_eq!(
+            reason(parse_authenticator_data(&data[..data.len() - 1], false)),
+            "invalid public key"
+        );
+    }
+
+    #[test]
+    fn converts_rsa_keys() {
+        let key = cbor::Value::Map(vec![
+            (cbor::Value::Integer(1), cbor::Value::Integer(3)),
+            (cbor::Value::Integer(3), cbor::Value::Integer(RS256)),
+            (
+                cbor::Value::Integer(-1),
+                cbor::Value::Bytes(vec![0xc5, 0x2a]),
+            ),
+            (cbor::Value::Integer(-2), cbor::Value::Bytes(vec![1, 0, 1])),
+        ]);
+
+        let jwk = serde_json::from_str::<Value>(&cose_to_jwk(&key).unwrap()).unwrap();
+        assert_eq!(
+            jwk,
+            json!({ "kty": "RSA", "alg": "RS256", "n": "xSo", "e": "AQAB" })
+        );
+    }
+
+    #[test]
+    fn rejects_unsupported_keys() {
+        // EdDSA, and ES256 on another curve
+        for (kty, alg, crv) in [(1, -8, 6), (2, ES256, 2)] {
+            let key = cbor::Value::Map(vec![
+                (cbor::Value::Integer(1), cbor::Value::Integer(kty)),
+                (cbor::Value::Integer(3), cbor::Value::Integer(alg)),
+                (cbor::Value::Integer(-1), cbor::Value::Integer(crv)),
+                (cbor::Value::Integer(-2), cbor::Value::Bytes(vec![0; 32])),
+                (cbor::Value::Integer(-3), cbor::Value::Bytes(vec![0; 32])),
+            ]);
+
+            assert_eq!(reason(cose_to_jwk(&key)), "unsupported algorithm");
+        }
+    }
+}
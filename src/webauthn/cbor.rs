@@ -0,0 +1,242 @@
+//! Just enough CBOR to read attestation objects and COSE keys.
+//!
+//! See <https://www.rfc-editor.org/rfc/rfc8949>.
+
+#[derive(Debug, PartialEq)]
+pub enum Value {
+    Integer(i128),
+    Bytes(Vec<u8>),
+    Text(String),
+    Array(Vec<Value>),
+    Map(Vec<(Value, Value)>),
+    Bool(bool),
+    Null,
+}
+
+impl Value {
+    /// Look up a value in a map by a text or integer key.
+    pub fn get(&self, key: impl Into<Value>) -> Option<&Value> {
+        let key = key.into();
+
+        match self {
+            Value::Map(entries) => entries.iter().find(|(k, _)| *k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Value::Bytes(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    pub fn as_integer(&self) -> Option<i128> {
+        match self {
+            Value::Integer(n) => Some(*n),
+            _ => None,
+        }
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::Text(s.to_string())
+    }
+}
+
+impl From<i128> for Value {
+    fn from(n: i128) -> Self {
+        Value::Integer(n)
+    }
+}
+
+/// Nesting limit, so that malicious input can't overflow the stack.
+const MAX_DEPTH: usize = 16;
+
+fn take(input: &[u8], len: usize) -> Option<(&[u8], &[u8])> {
+    (input.len() >= len).then(|| input.split_at(len))
+}
+
+/// Read the argument of a data item, which is its value or length.
+fn argument(info: u8, input: &[u8]) -> Option<(u64, &[u8])> {
+    let len = match info {
+        0..=23 => return Some((info as u64, input)),
+        24 => 1,
+        25 => 2,
+        26 => 4,
+        27 => 8,
+        // Indefinite lengths are not used by authenticators
+        _ => return None,
+    };
+
+    let (bytes, rest) = take(input, len)?;
+    let n = bytes.iter().fold(0u64, |n, &b| (n << 8) | b as u64);
+
+    Some((n, rest))
+}
+
+fn decode_item(input: &[u8], depth: usize) -> Option<(Value, &[u8])> {
+    if depth > MAX_DEPTH {
+        return None;
+    }
+
+    let (&initial, rest) = input.split_first()?;
+    let (n, rest) = argument(initial & 0x1f, rest)?;
+
+    match initial >> 5 {
+        0 => Some((Value::Integer(n as i128), rest)),
+        1 => Some((Value::Integer(-1 - n as i128), rest)),
+        2 => {
+            let (bytes, rest) = take(rest, usize::try_from(n).ok()?)?;
+            Some((Value::Bytes(bytes.to_vec()), rest))
+        }
+        3 => {
+            let (bytes, rest) = take(rest, usize::try_from(n).ok()?)?;
+            let text = String::from_utf8(bytes.to_vec()).ok()?;
+            Some((Value::Text(text), rest))
+        }
+        4 => {
+            let mut rest = rest;
+            let mut items = Vec::new();
+            for _ in 0..n {
+                let (item, r) = decode_item(rest, depth + 1)?;
+                items.push(item);
+                rest = r;
+            }
+            Some((Value::Array(items), rest))
+        }
+        5 => {
+            let mut rest = rest;
+            let mut entries = Vec::new();
+            for _ in 0..n {
+                let (key, r) = decode_item(rest, depth + 1)?;
+                let (value, r) = decode_item(r, depth + 1)?;
+                entries.push((key, value));
+                rest = r;
+            }
+            Some((Value::Map(entries), rest))
+        }
+        // Tags are skipped
+        6 => decode_item(rest, depth + 1),
+        7 => match initial & 0x1f {
+            20 => Some((Value::Bool(false), rest)),
+            21 => Some((Value::Bool(true), rest)),
+            22 | 23 => Some((Value::Null, rest)),
+            _ => None,
+        },
+        _ => unreachable!(),
+    }
+}
+
+/// Decode the first data item, returning it and the remaining input.
+pub fn decode(input: &[u8]) -> Option<(Value, &[u8])> {
+    decode_item(input, 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_all(input: &[u8]) -> Option<Value> {
+        decode(input)
+            .filter(|(_, rest)| rest.is_empty())
+            .map(|(value, _)| value)
+    }
+
+    // Examples from appendix A of RFC 8949
+    #[test]
+    fn decodes_integers() {
+        for (input, n) in [
+            (&[0x00][..], 0),
+            (&[0x17], 23),
+            (&[0x18, 0x18], 24),
+            (&[0x19, 0x03, 0xe8], 1000),
+            (
+                &[0x1b, 0x00, 0x00, 0x00, 0xe8, 0xd4, 0xa5, 0x10, 0x00],
+                1_000_000_000_000,
+            ),
+            (&[0x20], -1),
+            (&[0x38, 0x63], -100),
+            (
+                &[0x3b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+                -18_446_744_073_709_551_616,
+            ),
+        ] {
+            assert_eq!(decode_all(input), Some(Value::Integer(n)));
+        }
+    }
+
+    #[test]
+    fn decodes_strings() {
+        assert_eq!(
+            decode_all(&[0x44, 0x01, 0x02, 0x03, 0x04]),
+            Some(Value::Bytes(vec![1, 2, 3, 4]))
+        );
+        assert_eq!(
+            decode_all(&[0x64, 0x49, 0x45, 0x54, 0x46]),
+            Some(Value::Text("IETF".into()))
+        );
+        assert_eq!(decode_all(&[0x62, 0xc3, 0x28]), None);
+    }
+
+    #[test]
+    fn decodes_simple_values() {
+        assert_eq!(decode_all(&[0xf4]), Some(Value::Bool(false)));
+        assert_eq!(decode_all(&[0xf5]), Some(Value::Bool(true)));
+        assert_eq!(decode_all(&[0xf6]), Some(Value::Null));
+    }
+
+    #[test]
+    fn decodes_arrays_and_maps() {
+        assert_eq!(
+            decode_all(&[0x83, 0x01, 0x82, 0x02, 0x03, 0x82, 0x04, 0x05]),
+            Some(Value::Array(vec![
+                Value::Integer(1),
+                Value::Array(vec![Value::Integer(2), Value::Integer(3)]),
+                Value::Array(vec![Value::Integer(4), Value::Integer(5)]),
+            ]))
+        );
+
+        let map = decode_all(&[0xa2, 0x61, 0x61, 0x01, 0x20, 0x82, 0x02, 0x03]).unwrap();
+        assert_eq!(map.get("a"), Some(&Value::Integer(1)));
+        assert_eq!(
+            map.get(-1_i128),
+            Some(&Value::Array(vec![Value::Integer(2), Value::Integer(3)]))
+        );
+        assert_eq!(map.get("b"), None);
+    }
+
+    #[test]
+    fn skips_tags() {
+        assert_eq!(
+            decode_all(&[0xc1, 0x1a, 0x51, 0x4b, 0x67, 0xb0]),
+            Some(Value::Integer(1_363_896_240))
+        );
+    }
+
+    #[test]
+    fn returns_the_rest() {
+        assert_eq!(
+            decode(&[0x01, 0x02]),
+            Some((Value::Integer(1), &[0x02][..]))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_input() {
+        // Truncated, indefinite length and unsupported simple values
+        assert_eq!(decode(&[0x19, 0x03]), None);
+        assert_eq!(decode(&[0x44, 0x01, 0x02]), None);
+        assert_eq!(decode(&[0x5f, 0x41, 0x01, 0xff]), None);
+        assert_eq!(decode(&[0xf8, 0x20]), None);
+    }
+
+    #[test]
+    fn limits_nesting() {
+        let nested = |depth| [vec![0x81; depth], vec![0x00]].concat();
+
+        assert!(decode(&nested(MAX_DEPTH)).is_some());
+        assert_eq!(decode(&nested(MAX_DEPTH + 1)), None);
+    }
+}
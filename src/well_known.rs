@@ -2,39 +2,103 @@ use axum::{response::IntoResponse, routing::get, Json, Router};
 use oauth2::{AuthUrl, Scope, TokenUrl};
 use openidconnect::{
     core::{
-        CoreClaimName, CoreGrantType, CoreJwsSigningAlgorithm, CoreProviderMetadata,
-        CoreResponseType, CoreSubjectIdentifierType,
+        CoreAuthDisplay, CoreClaimName, CoreClaimType, CoreClientAuthMethod, CoreGrantType,
+        CoreJsonWebKey, CoreJsonWebKeyType, CoreJsonWebKeyUse, CoreJweContentEncryptionAlgorithm,
+        CoreJweKeyManagementAlgorithm, CoreJwsSigningAlgorithm, CoreResponseMode, CoreResponseType,
+        CoreSubjectIdentifierType,
     },
-    EmptyAdditionalProviderMetadata, IssuerUrl, JsonWebKeySetUrl, ResponseTypes,
+    AdditionalProviderMetadata, AuthenticationContextClass, EndSessionUrl, IssuerUrl,
+    JsonWebKeySetUrl, LogoutProviderMetadata, ResponseTypes, UserInfoUrl,
 };
+use serde::{Deserialize, Serialize};
 use worker::body::Body;
 
-use crate::AppState;
+use crate::{
+    sessions::{ACR_MFA, ACR_SINGLE_FACTOR},
+    tokens::IDENTITIES_SCOPE,
+    AppState,
+};
+
+/// Metadata of [OpenID Connect Back-Channel Logout](https://openid.net/specs/openid-connect-backchannel-1_0.html#BCSupport).
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct BackchannelLogoutMetadata {
+    backchannel_logout_supported: bool,
+    backchannel_logout_session_supported: bool,
+}
+
+impl AdditionalProviderMetadata for BackchannelLogoutMetadata {}
+
+type ProviderMetadata = openidconnect::ProviderMetadata<
+    LogoutProviderMetadata<BackchannelLogoutMetadata>,
+    CoreAuthDisplay,
+    CoreClientAuthMethod,
+    CoreClaimName,
+    CoreClaimType,
+    CoreGrantType,
+    CoreJweContentEncryptionAlgorithm,
+    CoreJweKeyManagementAlgorithm,
+    CoreJwsSigningAlgorithm,
+    CoreJsonWebKeyType,
+    CoreJsonWebKeyUse,
+    CoreJsonWebKey,
+    CoreResponseMode,
+    CoreResponseType,
+    CoreSubjectIdentifierType,
+>;
 
 async fn openid_configuration() -> impl IntoResponse {
     let domain = env!("DOMAIN");
 
-    let metadata = CoreProviderMetadata::new(
+    let metadata = ProviderMetadata::new(
         IssuerUrl::new(domain.to_string()).unwrap(),
         AuthUrl::new(format!("{domain}/oauth/authorize")).unwrap(),
         JsonWebKeySetUrl::new(format!("{domain}/jwks")).unwrap(),
         vec![ResponseTypes::new(vec![CoreResponseType::Code])],
         vec![CoreSubjectIdentifierType::Public],
         vec![CoreJwsSigningAlgorithm::RsaSsaPkcs1V15Sha256],
-        EmptyAdditionalProviderMetadata {},
+        LogoutProviderMetadata {
+            end_session_endpoint: Some(
+                EndSessionUrl::new(format!("{domain}/oauth/logout")).unwrap(),
+            ),
+            additional_metadata: BackchannelLogoutMetadata {
+                backchannel_logout_supported: true,
+                // ID tokens and logout tokens contain the `sid` claim
+                backchannel_logout_session_supported: true,
+            },
+        },
     )
+    .set_acr_values_supported(Some(
+        [ACR_MFA, ACR_SINGLE_FACTOR]
+            .into_iter()
+            .map(|acr| AuthenticationContextClass::new(acr.into()))
+            .collect(),
+    ))
     .set_grant_types_supported(Some(vec![CoreGrantType::AuthorizationCode]))
     .set_token_endpoint(Some(
         TokenUrl::new(format!("{domain}/oauth/token")).unwrap(),
     ))
-    // .set_userinfo_endpoint(Some(
-    //     UserInfoUrl::new(format!("{domain}/userinfo")).unwrap(),
-    // ))
+    .set_userinfo_endpoint(Some(
+        UserInfoUrl::new(format!("{domain}/userinfo")).unwrap(),
+    ))
     .set_scopes_supported(Some(
-        ["openid", "profile", "email", "read:user_idp_tokens"]
-            .into_iter()
-            .map(|s| Scope::new(s.into()))
-            .collect(),
+        [
+            "openid",
+            "profile",
+            "email",
+            IDENTITIES_SCOPE,
+            "read:users",
+            "write:users",
+            "read:user_idp_tokens",
+            "read:providers",
+            "write:providers",
+            "read:connections",
+            "write:connections",
+            "read:account",
+            "write:account",
+        ]
+        .into_iter()
+        .map(|s| Scope::new(s.into()))
+        .collect(),
     ))
     .set_claims_supported(Some(
         [
@@ -44,6 +108,10 @@ async fn openid_configuration() -> impl IntoResponse {
             "iat",
             "at_hash",
             "c_hash",
+            "auth_time",
+            "acr",
+            "amr",
+            "sid",
             //
             "sub",
             "email",
@@ -56,6 +124,7 @@ async fn openid_configuration() -> impl IntoResponse {
             "picture",
             "phone_number",
             "phone_number_verified",
+            "identities",
         ]
         .into_iter()
         .map(|c| CoreClaimName::new(c.into()))